@@ -3,11 +3,25 @@ extern crate cpal;
 use cpal::traits::{DeviceTrait, EventLoopTrait, HostTrait};
 use std::thread;
 use std::sync::{Mutex, mpsc::{SyncSender, sync_channel}};
+use crate::events::{Event, LogLevel, Logger};
+
+/**
+ * Audio output so far, see `Board::audio_stats`
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AudioStats {
+    // Samples passed to BSP_AUDIO_OUT_Play_Sample
+    pub samples: u128,
+
+    // Whether samples are sent to a host output or observer
+    pub connected: bool,
+}
 
 #[derive(Debug)]
 pub struct AudioHandler {
     sender: Option<SyncSender<i16>>,
     samples: u128,
+    pub(crate) logger: Logger,
 }
 
 impl AudioHandler {
@@ -15,6 +29,7 @@ impl AudioHandler {
         return AudioHandler {
             sender: None,
             samples: 0,
+            logger: Logger::new(),
         };
     }
 
@@ -33,7 +48,7 @@ impl AudioHandler {
 
     pub fn spawn_audio(&mut self) {
         if let Some(_) = self.sender {
-            println!("Attempt to spawn audio failed: handler already registered");
+            report(&self.logger, LogLevel::Warn, String::from("Attempt to spawn audio failed: handler already registered"));
             return;
         }
 
@@ -41,17 +56,18 @@ impl AudioHandler {
         let (tx_data, rx_data) = sync_channel::<i16>(32); // board uses ~6
         let (tx_confirm, rx_confirm) = sync_channel::<bool>(1);
 
+        let logger = self.logger.clone();
         thread::spawn(move || {
             let host = cpal::default_host();
             let event_loop = host.event_loop();
-            let (stream_id, num_channels) = match get_audio_config(target_freq, &host, &event_loop) {
+            let (stream_id, num_channels) = match get_audio_config(target_freq, &host, &event_loop, &logger) {
                 Ok(result) => {
-                    println!("Spawned audio at freq {}", target_freq);
+                    report(&logger, LogLevel::Info, format!("Spawned audio at freq {}", target_freq));
                     tx_confirm.send(true).unwrap();
                     result
                 },
                 Err(e) => {
-                    println!("Failed to spawn audio: {}", e);
+                    report(&logger, LogLevel::Error, format!("Failed to spawn audio: {}", e));
                     tx_confirm.send(false).unwrap();
                     return;
                 }
@@ -64,7 +80,7 @@ impl AudioHandler {
                 let data = match result {
                     Ok(data) => data,
                     Err(err) => {
-                        eprintln!("an error occurred on stream {:?}: {}", id, err);
+                        report(&logger, LogLevel::Error, format!("an error occurred on stream {:?}: {}", id, err));
                         return;
                     }
                 };
@@ -103,16 +119,16 @@ impl AudioHandler {
         });
 
         if rx_confirm.recv().unwrap() {
-            println!("Audio output connected");
+            report(&self.logger, LogLevel::Info, String::from("Audio output connected"));
             self.sender = Some(tx_data);
         } else {
-            println!("Could not connect to suitable audio output");
+            report(&self.logger, LogLevel::Error, String::from("Could not connect to suitable audio output"));
         }
     }
 
     pub fn spawn_buffered_audio(&mut self, buffer_ms: u32) {
         if let Some(_) = self.sender {
-            println!("Attempt to spawn buffered audio failed: handler already registered");
+            report(&self.logger, LogLevel::Warn, String::from("Attempt to spawn buffered audio failed: handler already registered"));
             return;
         }
 
@@ -121,17 +137,18 @@ impl AudioHandler {
         let (tx_data, rx_data) = sync_channel::<i16>(32); // board uses ~6
         let (tx_confirm, rx_confirm) = sync_channel::<bool>(1);
 
+        let logger = self.logger.clone();
         thread::spawn(move || {
             let host = cpal::default_host();
             let event_loop = host.event_loop();
-            let (stream_id, num_channels) = match get_audio_config(target_freq, &host, &event_loop) {
+            let (stream_id, num_channels) = match get_audio_config(target_freq, &host, &event_loop, &logger) {
                 Ok(result) => {
-                    println!("Spawned audio at freq {}", target_freq);
+                    report(&logger, LogLevel::Info, format!("Spawned audio at freq {}", target_freq));
                     tx_confirm.send(true).unwrap();
                     result
                 },
                 Err(e) => {
-                    println!("Failed to spawn audio: {}", e);
+                    report(&logger, LogLevel::Error, format!("Failed to spawn audio: {}", e));
                     tx_confirm.send(false).unwrap();
                     return;
                 }
@@ -146,7 +163,7 @@ impl AudioHandler {
                 let data = match result {
                     Ok(data) => data,
                     Err(err) => {
-                        eprintln!("an error occurred on stream {:?}: {}", id, err);
+                        report(&logger, LogLevel::Error, format!("an error occurred on stream {:?}: {}", id, err));
                         return;
                     }
                 };
@@ -154,11 +171,11 @@ impl AudioHandler {
                 let rx_data = rx_data.lock().unwrap();
 
                 if audio_buffer.len() < 10000 {
-                    println!("Filling audio buffer...");
+                    report(&logger, LogLevel::Debug, String::from("Filling audio buffer..."));
                     while audio_buffer.len() < target_buffer_fill {
                         audio_buffer.push_back(rx_data.recv().unwrap());
                     }
-                    println!("Refilled audio buffer");
+                    report(&logger, LogLevel::Debug, String::from("Refilled audio buffer"));
                 }
 
                 match data {
@@ -194,25 +211,36 @@ impl AudioHandler {
         });
 
         if rx_confirm.recv().unwrap() {
-            println!("Buffered audio output ({}s) connected", buffer_ms / 1000);
+            report(&self.logger, LogLevel::Info, format!("Buffered audio output ({}s) connected", buffer_ms / 1000));
             self.sender = Some(tx_data);
         } else {
-            println!("Could not connect to suitable audio output");
+            report(&self.logger, LogLevel::Error, String::from("Could not connect to suitable audio output"));
         }
     }
 
     pub fn set_observer(&mut self, observer: SyncSender<i16>) {
         self.sender = Some(observer);
     }
+
+    pub fn stats(&self) -> AudioStats {
+        return AudioStats {
+            samples: self.samples,
+            connected: self.sender.is_some(),
+        };
+    }
+}
+
+fn report(logger: &Logger, level: LogLevel, message: String) {
+    logger.emit(Event::Audio { level, message });
 }
 
-fn get_audio_config(freq: u32, host: &cpal::Host, event_loop: &cpal::EventLoop) -> Result<(cpal::StreamId, usize), String> {
+fn get_audio_config(freq: u32, host: &cpal::Host, event_loop: &cpal::EventLoop, logger: &Logger) -> Result<(cpal::StreamId, usize), String> {
     let device = host.default_output_device().expect("failed to find a default output device");
 
     let formats = device.supported_output_formats().unwrap();
     let required_freq = cpal::SampleRate(freq);
     for supported in formats {
-        println!("Candidate audio format: channels: {:?}, min: {:?}, max: {:?}, data: {:?}", supported.channels, supported.min_sample_rate, supported.max_sample_rate, supported.data_type);
+        report(logger, LogLevel::Debug, format!("Candidate audio format: channels: {:?}, min: {:?}, max: {:?}, data: {:?}", supported.channels, supported.min_sample_rate, supported.max_sample_rate, supported.data_type));
         if supported.min_sample_rate > required_freq || supported.max_sample_rate < required_freq {
             continue;
         }
@@ -1,4 +1,4 @@
-use crate::{ByteInstruction};
+use crate::{ByteInstruction, EmulatorError};
 use crate::utils::bits::is_wide_thumb;
 use std::fmt;
 
@@ -28,12 +28,12 @@ impl InstructionCache {
         };
     }
 
-    pub fn get_cached(&self, address: u32) -> Result<(u32, u32), String> {
+    pub fn get_cached(&self, address: u32) -> Result<(u32, u32), EmulatorError> {
         if address as usize <= self.cache.len() - 2 {
             let base = address as usize;
             return Ok((self.cache[base], self.cache[base + 1]));
         }
-        return Err(String::from("Out of bounds access"));
+        return Err(EmulatorError::FetchFault { address });
     }
 
     pub fn write_cache_narrow(&mut self, address: u32, value: ByteInstruction) {
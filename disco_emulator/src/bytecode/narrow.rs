@@ -242,7 +242,7 @@ fn id_misc(hword: u16, c: Context) -> u32 {
     assert!(matches(hword, 12, 0b1111, 0b1011));
     return match (hword >> 5) & 0x7F {
         0b011_0011 => {
-            let base = tag::get_narrow(Opcode::Cps, c, hword & 0b11 | (hword & (1 << 3)) >> 1); // B5.2.1 T1
+            let base = tag::get_narrow(Opcode::Cps, c, hword & 0b11 | (hword & (1 << 4)) >> 2); // B5.2.1 T1
             let base = if bitset(hword, 3) || bitset(hword, 2) || (!bitset(hword, 1) && !bitset(hword, 0)) {
                 tag::as_unpred(base)
             } else {
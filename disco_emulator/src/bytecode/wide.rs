@@ -522,7 +522,7 @@ fn id_branch_and_misc(word: u32, c: Context) -> ByteInstruction {
             return base;
         }
 
-        if (op & 0b1111110) == 0b0111110 {
+        if (op & 0b1111110) == 0b0111000 {
             let mut base = tag::get_wide(Opcode::Msr, c, rn, sysm | mask << 8); // A7.7.82 T1, B5.2.3 T1
             if bitset(word, 20) || bitset(word, 13) || bitset(word, 9) || bitset(word, 8) {
                 base = tag::as_unpred_w(base);
@@ -615,7 +615,7 @@ fn id_branch_and_misc(word: u32, c: Context) -> ByteInstruction {
             return base;
         }
 
-        if (op & 0b1111110) == 0b01111110 {
+        if (op & 0b1111110) == 0b0111110 {
             let mut base = tag::get_wide(Opcode::Mrs, c, rd, sysm); // A7.7.82 T1, B5.2.2 T1
             if bitset(word, 20) || bitset(word, 13) || rn != 0b1111 {
                 base = tag::as_unpred_w(base);
@@ -2,6 +2,9 @@ use std::fmt;
 
 use crate::utils::bits::bitset;
 use crate::bytecode::{ItPos};
+use crate::EmulatorError;
+use crate::events::{Event, Logger};
+use crate::snapshot::{SnapshotWriter, SnapshotReader};
 
 // NOTE: condition checking is defined in A7.3.1 p178
 #[derive(Copy, Clone, Debug)]
@@ -181,6 +184,7 @@ impl EPSR {
 
 #[derive(Debug)]
 struct Control {
+    // B1.4.4
     spsel: bool,
     n_priv: bool,
     fpca: bool,
@@ -196,6 +200,24 @@ impl Control {
     }
 }
 
+#[derive(Debug)]
+struct Mask {
+    // B1.4.3
+    primask: bool,
+    faultmask: bool,
+    basepri: u8,
+}
+
+impl Mask {
+    fn new() -> Mask {
+        return Mask {
+            primask: false,
+            faultmask: false,
+            basepri: 0,
+        };
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ExecMode {
     // B1.4.7 p521
@@ -223,7 +245,14 @@ pub struct CPU {
     epsr: EPSR,
     pub itstate: ItState,
     control: Control,
+    mask: Mask,
+
+    // S0-S31 and FPSCR of the FP extension (A2.5.2, A2.5.3). No floating point
+    // instructions are executed yet, so only a debugger changes them.
+    fp_registers: [u32; 32],
+    fpscr: u32,
     pub current_mode: ExecMode,
+    pub(crate) logger: Logger,
 }
 
 impl CPU {
@@ -239,7 +268,11 @@ impl CPU {
             epsr: EPSR::new(),
             itstate: ItState::new(),
             control: Control::new(),
+            mask: Mask::new(),
+            fp_registers: [0; 32],
+            fpscr: 0,
             current_mode: ExecMode::ModeThread,
+            logger: Logger::new(),
         };
     }
 
@@ -253,7 +286,7 @@ impl CPU {
     }
 
     fn raise_unpredictable(&self) {
-        println!("Unpredictable SP access");
+        self.logger.emit(Event::Unpredictable { address: self.registers[15].wrapping_sub(4), what: "SP access" });
     }
 
     pub fn read_sp(&self) -> u32 {
@@ -368,13 +401,7 @@ impl CPU {
     }
 
     pub fn read_xpsr(&self) -> u32 {
-        let n = u32::from(self.apsr.n) << 31;
-        let z = u32::from(self.apsr.z) << 30;
-        let c = u32::from(self.apsr.c) << 29;
-        let v = u32::from(self.apsr.v) << 28;
-        let q = u32::from(self.apsr.q) << 27;
-        let ge = u32::from(self.apsr.ge & 0xF) << 16;
-        let apsr = n | z | c | v | q | ge;
+        let apsr = self.read_apsr();
 
         let it_ici = self.itstate.state & 0xFF;
         let t: u32 = u32::from(self.epsr.t) << 24;
@@ -388,6 +415,205 @@ impl CPU {
         return apsr | epsr | ipsr;
     }
 
+    /**
+     * Writes the registers, including the banked stack pointers and the
+     * special registers, to a board snapshot
+     */
+    pub fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_words(&self.registers);
+        snapshot.write_words(&[self.instr_pc, self.sp_main, self.sp_process, self.read_xpsr(), self.epsr.it_ici]);
+        snapshot.write_bool(self.sp_unpredictable);
+        snapshot.write_bool(self.control.spsel);
+        snapshot.write_bool(self.control.n_priv);
+        snapshot.write_bool(self.control.fpca);
+        snapshot.write_bool(self.mask.primask);
+        snapshot.write_bool(self.mask.faultmask);
+        snapshot.write_u8(self.mask.basepri);
+        snapshot.write_bool(self.current_mode == ExecMode::ModeHandler);
+        snapshot.write_words(&self.fp_registers);
+        snapshot.write_u32(self.fpscr);
+    }
+
+    pub fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        snapshot.read_words_into(&mut self.registers)?;
+        let mut words = [0; 5];
+        snapshot.read_words_into(&mut words)?;
+        let [instr_pc, sp_main, sp_process, xpsr, it_ici] = words;
+        self.instr_pc = instr_pc;
+        self.sp_main = sp_main;
+        self.sp_process = sp_process;
+        self.write_xpsr(xpsr);
+        self.epsr.it_ici = it_ici;
+        self.sp_unpredictable = snapshot.read_bool()?;
+        self.control.spsel = snapshot.read_bool()?;
+        self.control.n_priv = snapshot.read_bool()?;
+        self.control.fpca = snapshot.read_bool()?;
+        self.mask.primask = snapshot.read_bool()?;
+        self.mask.faultmask = snapshot.read_bool()?;
+        self.mask.basepri = snapshot.read_u8()?;
+        self.current_mode = if snapshot.read_bool()? { ExecMode::ModeHandler } else { ExecMode::ModeThread };
+        snapshot.read_words_into(&mut self.fp_registers)?;
+        self.fpscr = snapshot.read_u32()?;
+        return Ok(());
+    }
+
+    pub fn read_apsr(&self) -> u32 {
+        let n = u32::from(self.apsr.n) << 31;
+        let z = u32::from(self.apsr.z) << 30;
+        let c = u32::from(self.apsr.c) << 29;
+        let v = u32::from(self.apsr.v) << 28;
+        let q = u32::from(self.apsr.q) << 27;
+        let ge = u32::from(self.apsr.ge & 0xF) << 16;
+        return n | z | c | v | q | ge;
+    }
+
+    pub fn write_apsr(&mut self, value: u32, nzcvq: bool, ge: bool) {
+        // B5.2.3, the MSR mask selects which fields are written
+        if nzcvq {
+            self.apsr.n = bitset(value, 31);
+            self.apsr.z = bitset(value, 30);
+            self.apsr.c = bitset(value, 29);
+            self.apsr.v = bitset(value, 28);
+            self.apsr.q = bitset(value, 27);
+        }
+        if ge {
+            self.apsr.ge = ((value >> 16) & 0xF) as u8;
+        }
+    }
+
+    pub fn write_xpsr(&mut self, value: u32) {
+        // B1.4.2, used when restoring the stacked xPSR on exception return
+        self.write_apsr(value, true, true);
+
+        let ici1 = (value >> 25) & 0b11;
+        let ici2 = (value >> 10) & 0b11;
+        let ici3 = (value >> 12) & 0b1111;
+        self.itstate.state = ici1 | ici2 << 2 | ici3 << 4;
+        self.epsr.t = bitset(value, 24);
+
+        self.ipsr.exception = value & 0x1FF;
+    }
+
+    pub fn read_ipsr(&self) -> u32 {
+        return self.ipsr.exception;
+    }
+
+    pub fn write_ipsr(&mut self, exception: u32) {
+        self.ipsr.exception = exception & 0x1FF;
+    }
+
+    fn process_sp_active(&self) -> bool {
+        // B1.4.7 p521
+        return self.current_mode == ExecMode::ModeThread && self.control.spsel;
+    }
+
+    pub fn read_msp(&self) -> u32 {
+        return if self.process_sp_active() { self.sp_main } else { self.read_sp() };
+    }
+
+    pub fn write_msp(&mut self, value: u32) {
+        if self.process_sp_active() {
+            self.sp_main = value & !0b11;
+        } else {
+            self.write_sp(value);
+        }
+    }
+
+    pub fn read_psp(&self) -> u32 {
+        return if self.process_sp_active() { self.read_sp() } else { self.sp_process };
+    }
+
+    pub fn write_psp(&mut self, value: u32) {
+        if self.process_sp_active() {
+            self.write_sp(value);
+        } else {
+            self.sp_process = value & !0b11;
+        }
+    }
+
+    /**
+     * Changes the mode and stack selection, banking the current SP
+     * so that r13 always holds the active stack pointer.
+     */
+    pub fn select_stack(&mut self, mode: ExecMode, spsel: bool) {
+        let msp = self.read_msp();
+        let psp = self.read_psp();
+        self.current_mode = mode;
+        self.control.spsel = spsel;
+        self.write_msp(msp);
+        self.write_psp(psp);
+    }
+
+    pub fn read_spsel(&self) -> bool {
+        return self.control.spsel;
+    }
+
+    pub fn is_privileged(&self) -> bool {
+        // B1.3.1 CurrentModeIsPrivileged()
+        return self.current_mode == ExecMode::ModeHandler || !self.control.n_priv;
+    }
+
+    pub fn read_control(&self) -> u32 {
+        let n_priv = u32::from(self.control.n_priv);
+        let spsel = u32::from(self.control.spsel) << 1;
+        let fpca = u32::from(self.control.fpca) << 2;
+        return n_priv | spsel | fpca;
+    }
+
+    pub fn write_control(&mut self, value: u32) {
+        // B5.2.3: SPSEL is only writable from Thread mode
+        self.control.n_priv = bitset(value, 0);
+        self.control.fpca = bitset(value, 2);
+        if self.current_mode == ExecMode::ModeThread {
+            self.select_stack(ExecMode::ModeThread, bitset(value, 1));
+        }
+    }
+
+    pub fn read_primask(&self) -> bool {
+        return self.mask.primask;
+    }
+
+    pub fn write_primask(&mut self, enabled: bool) {
+        self.mask.primask = enabled;
+    }
+
+    pub fn read_faultmask(&self) -> bool {
+        return self.mask.faultmask;
+    }
+
+    pub fn write_faultmask(&mut self, enabled: bool) {
+        self.mask.faultmask = enabled;
+    }
+
+    pub fn read_basepri(&self) -> u8 {
+        return self.mask.basepri;
+    }
+
+    pub fn write_basepri(&mut self, value: u8) {
+        self.mask.basepri = value;
+    }
+
+    /**
+     * Single precision register S`reg`. D`n` is S`2n+1`:S`2n`.
+     */
+    pub fn read_fp_reg(&self, reg: u32) -> u32 {
+        assert!(reg <= 31);
+        return self.fp_registers[reg as usize];
+    }
+
+    pub fn write_fp_reg(&mut self, reg: u32, value: u32) {
+        assert!(reg <= 31);
+        self.fp_registers[reg as usize] = value;
+    }
+
+    pub fn read_fpscr(&self) -> u32 {
+        return self.fpscr;
+    }
+
+    pub fn write_fpscr(&mut self, value: u32) {
+        self.fpscr = value;
+    }
+
     pub fn get_flags(&self) -> Flags {
         return Flags {
             n: self.apsr.n,
@@ -0,0 +1,85 @@
+use crate::MemError;
+use crate::bytecode::opcode::Opcode;
+
+use std::error::Error;
+use std::fmt;
+
+/**
+ * Why the board could not carry on as normal.
+ *
+ * Faults the program could cause on real hardware (fetch faults, undefined
+ * instructions, memory faults and invalid exception returns) have already
+ * been turned into the matching exception by the time `Board::step` returns
+ * them, so stepping again runs the fault handler. The others mean the
+ * emulation cannot continue faithfully, see `is_fatal`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmulatorError {
+    // An instruction was fetched from `address`, which is not executable memory
+    FetchFault { address: u32 },
+
+    // An instruction was executed with EPSR.T clear (B1.4.2)
+    InvalidState { address: u32 },
+
+    // The instruction at `address` is UNDEFINED. Wide encodings have the first halfword in the top half.
+    UndefinedInstruction { address: u32, encoding: u32, wide: bool },
+
+    // The instruction at `address` is valid, but the emulator does not support it
+    UnimplementedInstruction { address: u32, opcode: Opcode },
+
+    // A load or store to `address` failed
+    MemoryFault { address: u32, kind: MemError },
+
+    // An exception return used an invalid EXC_RETURN value, or returned to the wrong mode (B1.5.8)
+    InvalidExceptionReturn { exc_return: u32 },
+
+    // Exception `number` could not be taken, so the processor would enter lockup (B1.5.15)
+    Lockup { number: u32 },
+
+    // The ELF file could not be read or loaded into flash
+    ElfLoad(String),
+
+    // A snapshot could not be read, written or restored
+    Snapshot(String),
+
+    // A peripheral could not be registered for the address range
+    PeripheralRange { base: u32, size: u32 },
+    PeripheralOverlap { base: u32, existing: u32 },
+}
+
+impl EmulatorError {
+    /**
+     * True if the board cannot be stepped any further in a meaningful way
+     */
+    pub fn is_fatal(&self) -> bool {
+        return match self {
+            EmulatorError::FetchFault { .. } |
+            EmulatorError::InvalidState { .. } |
+            EmulatorError::UndefinedInstruction { .. } |
+            EmulatorError::MemoryFault { .. } |
+            EmulatorError::InvalidExceptionReturn { .. } => false,
+            _ => true,
+        };
+    }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            EmulatorError::FetchFault { address } => write!(f, "Cannot execute from 0x{:08X}, it is not in flash", address),
+            EmulatorError::InvalidState { address } => write!(f, "EPSR.T is clear at 0x{:08X}", address),
+            EmulatorError::UndefinedInstruction { address, encoding, wide: true } => write!(f, "Undefined instruction 0x{:08X} at 0x{:08X}", encoding, address),
+            EmulatorError::UndefinedInstruction { address, encoding, wide: false } => write!(f, "Undefined instruction 0x{:04X} at 0x{:08X}", encoding, address),
+            EmulatorError::UnimplementedInstruction { address, opcode } => write!(f, "Unimplemented instruction {:?} at 0x{:08X}", opcode, address),
+            EmulatorError::MemoryFault { address, kind } => write!(f, "Memory fault ({}) accessing 0x{:08X}", kind, address),
+            EmulatorError::InvalidExceptionReturn { exc_return } => write!(f, "Invalid exception return 0x{:08X}", exc_return),
+            EmulatorError::Lockup { number } => write!(f, "Lockup: exception {} could not be taken", number),
+            EmulatorError::ElfLoad(reason) => write!(f, "Failed to load ELF file: {}", reason),
+            EmulatorError::Snapshot(reason) => write!(f, "Snapshot failed: {}", reason),
+            EmulatorError::PeripheralRange { base, size } => write!(f, "Cannot map a peripheral at 0x{:08X} with size 0x{:X}", base, size),
+            EmulatorError::PeripheralOverlap { base, existing } => write!(f, "Peripheral at 0x{:08X} overlaps the one at 0x{:08X}", base, existing),
+        };
+    }
+}
+
+impl Error for EmulatorError {}
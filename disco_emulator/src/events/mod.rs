@@ -0,0 +1,165 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/**
+ * How much an event matters, from most to least severe
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    /**
+     * Parses `error`, `warn`, `info` or `debug`
+     */
+    pub fn parse(text: &str) -> Result<LogLevel, String> {
+        return match text.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("Unknown log level \"{}\", expected error, warn, info or debug", text)),
+        };
+    }
+}
+
+/**
+ * Something the emulator noticed that is worth telling the user about, but
+ * that does not stop the program. Faults are returned by `Board::step` instead.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    // An ELF file was loaded, and execution will start at `entry`
+    ProgramLoaded { entry: u32 },
+
+    // A write to flash was ignored
+    ReadOnlyWrite { address: u32 },
+
+    // A write to memory the emulator does not model was ignored
+    UnimplementedWrite { address: u32 },
+
+    // The instruction at `address` is UNPREDICTABLE, for the reason in `what`
+    Unpredictable { address: u32, what: &'static str },
+
+    // A semihosting operation number the emulator does not support
+    UnsupportedSemihosting { op: u32 },
+
+    // A call to a library function the emulator does not need was skipped
+    SkippedCall { name: String },
+
+    // An audio library function was called before audio was initialised
+    AudioNotInitialised { name: String },
+
+    // Progress or problems with the host audio output
+    Audio { level: LogLevel, message: String },
+}
+
+impl Event {
+    pub fn level(&self) -> LogLevel {
+        return match self {
+            Event::ProgramLoaded { .. } => LogLevel::Info,
+            Event::ReadOnlyWrite { .. } => LogLevel::Warn,
+            Event::UnimplementedWrite { .. } => LogLevel::Error,
+            Event::Unpredictable { .. } => LogLevel::Warn,
+            Event::UnsupportedSemihosting { .. } => LogLevel::Warn,
+            Event::SkippedCall { .. } => LogLevel::Debug,
+            Event::AudioNotInitialised { .. } => LogLevel::Warn,
+            Event::Audio { level, .. } => *level,
+        };
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            Event::ProgramLoaded { entry } => write!(f, "setting emulator pc to 0x{:08X}", entry),
+            Event::ReadOnlyWrite { address } => write!(f, "attempted to write to readonly memory: 0x{:08X}", address),
+            Event::UnimplementedWrite { address } => write!(f, "EMULATOR ERROR: unimplemented memory write: 0x{:08X}", address),
+            Event::Unpredictable { address, what } => write!(f, "UNPREDICTABLE {} at 0x{:08X}", what, address),
+            Event::UnsupportedSemihosting { op } => write!(f, "Unsupported semihosting operation 0x{:02X}", op),
+            Event::SkippedCall { name } => write!(f, "Skipping call to {}", name),
+            Event::AudioNotInitialised { name } => write!(f, "Call to {} without initialising audio", name),
+            Event::Audio { message, .. } => write!(f, "{}", message),
+        };
+    }
+}
+
+/**
+ * Receives the events of a board, see `Board::set_event_sink`. Events from
+ * audio output arrive on other threads, so sinks must be `Send`.
+ */
+pub trait EventSink: Send {
+    fn event(&mut self, event: &Event);
+}
+
+impl<F: FnMut(&Event) + Send> EventSink for F {
+    fn event(&mut self, event: &Event) {
+        self(event);
+    }
+}
+
+/**
+ * Prints events at `level` or more severe to stdout
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct ConsoleSink {
+    level: LogLevel,
+}
+
+impl ConsoleSink {
+    pub fn new(level: LogLevel) -> ConsoleSink {
+        return ConsoleSink {
+            level,
+        };
+    }
+}
+
+impl EventSink for ConsoleSink {
+    fn event(&mut self, event: &Event) {
+        if event.level() <= self.level {
+            println!("{}", event);
+        }
+    }
+}
+
+/**
+ * The sink shared by the board and the parts of it that report events.
+ * Events are discarded until a sink is set.
+ */
+#[derive(Clone)]
+pub(crate) struct Logger {
+    sink: Arc<Mutex<Box<dyn EventSink>>>,
+}
+
+impl fmt::Debug for Logger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "<logger>");
+    }
+}
+
+impl Logger {
+    pub fn new() -> Logger {
+        return Logger {
+            sink: Arc::new(Mutex::new(Box::new(|_: &Event| {}))),
+        };
+    }
+
+    /**
+     * Replaces the sink for this logger and every clone of it
+     */
+    pub fn set_sink(&self, sink: Box<dyn EventSink>) {
+        if let Ok(mut current) = self.sink.lock() {
+            *current = sink;
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        if let Ok(mut sink) = self.sink.lock() {
+            sink.event(&event);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::{Board, EmulatorError};
+use crate::semihosting::SemihostingReply;
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+// Steps between snapshots, so going back replays at most this many
+const CHECKPOINT_INTERVAL: u64 = 50_000;
+
+// Snapshots are over half a megabyte, so only the most recent are kept
+const MAX_CHECKPOINTS: usize = 64;
+
+/**
+ * Where a reverse step or continue stopped
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReverseStop {
+    // The instruction at a breakpoint is next to execute
+    Breakpoint,
+
+    // One instruction was undone
+    Stepped,
+
+    // There is no more history to go back through
+    Beginning,
+}
+
+/**
+ * Lets execution go backwards. Stepping through the history takes a snapshot
+ * of the board every so often, and going back restores the closest earlier
+ * one and steps forward again to the wanted point.
+ *
+ * Re-executing relies on the board being deterministic. Input timelines play
+ * the same way again, and semihosting calls that reach the host are recorded
+ * the first time and replayed from the record, so the host sees them once.
+ * Serial data is not: input is not replayed, and output is sent again.
+ */
+#[derive(Default)]
+pub struct History {
+    // (steps since the history was started, snapshot), oldest first
+    checkpoints: VecDeque<(u64, Vec<u8>)>,
+    position: u64,
+
+    // Steps before this have been taken before, and are replayed
+    recorded: u64,
+
+    // The semihosting calls that reached the host, by the step that made them
+    semihosting: BTreeMap<u64, SemihostingReply>,
+}
+
+impl History {
+    pub fn new() -> History {
+        return History {
+            checkpoints: VecDeque::new(),
+            position: 0,
+            recorded: 0,
+            semihosting: BTreeMap::new(),
+        };
+    }
+
+    /**
+     * Forgets all history, like after loading a new program or snapshot
+     */
+    pub fn clear(&mut self) {
+        self.checkpoints.clear();
+        self.position = 0;
+        self.recorded = 0;
+        self.semihosting.clear();
+    }
+
+    /**
+     * The number of steps taken since the history was started
+     */
+    pub fn position(&self) -> u64 {
+        return self.position;
+    }
+
+    /**
+     * Steps the board once, recording history to come back to
+     */
+    pub fn step(&mut self, board: &mut Board) -> Result<(), EmulatorError> {
+        if self.position.is_multiple_of(CHECKPOINT_INTERVAL) && self.checkpoints.back().is_none_or(|c| c.0 < self.position) {
+            self.push_checkpoint(board);
+        }
+        let result = self.step_board(board, self.position);
+        self.position += 1;
+        return result;
+    }
+
+    /**
+     * Records a change made to the board between steps, like a debugger writing
+     * memory, so it is kept when going back and replaying past this point
+     */
+    pub fn record_change(&mut self, board: &Board) {
+        while self.checkpoints.back().is_some_and(|c| c.0 >= self.position) {
+            self.checkpoints.pop_back();
+        }
+        self.push_checkpoint(board);
+
+        // What happens from here on may differ from last time
+        self.recorded = self.position;
+        self.semihosting.split_off(&self.position);
+    }
+
+    /**
+     * Undoes the last step
+     */
+    pub fn step_back(&mut self, board: &mut Board) -> Result<ReverseStop, EmulatorError> {
+        if self.position == 0 || !self.can_reach(self.position - 1) {
+            return Ok(ReverseStop::Beginning);
+        }
+        self.seek(board, self.position - 1)?;
+        return Ok(ReverseStop::Stepped);
+    }
+
+    /**
+     * Goes back to the last time the PC was at one of `breakpoints`, or
+     * as far back as the history goes
+     */
+    pub fn continue_back(&mut self, board: &mut Board, breakpoints: &HashSet<u32>) -> Result<ReverseStop, EmulatorError> {
+        let mut end = self.position;
+        while let Some(index) = self.checkpoints.iter().rposition(|c| c.0 < end) {
+            // Replay the span from the checkpoint, remembering the last breakpoint hit
+            let start = self.checkpoints[index].0;
+            board.restore(&self.checkpoints[index].1)?;
+            let mut hit = None;
+            for position in start..end {
+                if breakpoints.contains(&board.cpu.read_instruction_pc()) {
+                    hit = Some(position);
+                }
+                let _ = self.step_board(board, position);
+            }
+            if let Some(position) = hit {
+                self.seek(board, position)?;
+                return Ok(ReverseStop::Breakpoint);
+            }
+            end = start;
+        }
+
+        if let Some(&(oldest, _)) = self.checkpoints.front() {
+            self.seek(board, oldest)?;
+        }
+        return Ok(ReverseStop::Beginning);
+    }
+
+    fn can_reach(&self, target: u64) -> bool {
+        return self.checkpoints.front().is_some_and(|c| c.0 <= target);
+    }
+
+    /**
+     * Moves the board to `target` steps since the start, which must be in the history
+     */
+    fn seek(&mut self, board: &mut Board, target: u64) -> Result<(), EmulatorError> {
+        // Later checkpoints are taken again when stepping forward, and may differ
+        // if the debugger changes anything in between
+        while self.checkpoints.back().is_some_and(|c| c.0 > target) {
+            self.checkpoints.pop_back();
+        }
+        let start = match self.checkpoints.back() {
+            Some((start, snapshot)) => {
+                board.restore(snapshot)?;
+                *start
+            }
+            None => return Ok(()),
+        };
+        for position in start..target {
+            // Faults were reported the first time round
+            let _ = self.step_board(board, position);
+        }
+        self.position = target;
+        return Ok(());
+    }
+
+    fn push_checkpoint(&mut self, board: &Board) {
+        if self.checkpoints.len() == MAX_CHECKPOINTS {
+            self.checkpoints.pop_front();
+            if let Some(&(oldest, _)) = self.checkpoints.front() {
+                self.semihosting = self.semihosting.split_off(&oldest);
+            }
+        }
+        self.checkpoints.push_back((self.position, board.snapshot()));
+    }
+
+    /**
+     * Steps the board from `position`, replaying the step if it has been taken before
+     */
+    fn step_board(&mut self, board: &mut Board, position: u64) -> Result<(), EmulatorError> {
+        if position < self.recorded {
+            board.replay_semihosting(self.semihosting.get(&position).cloned());
+            let result = board.step();
+            board.replay_semihosting(None);
+            return result;
+        }
+
+        board.take_semihosting_reply();
+        let result = board.step();
+        if let Some(reply) = board.take_semihosting_reply() {
+            self.semihosting.insert(position, reply);
+        }
+        self.recorded = position + 1;
+        return result;
+    }
+}
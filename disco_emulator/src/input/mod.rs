@@ -0,0 +1,209 @@
+use std::fs;
+use std::path::Path;
+
+// Picoseconds in a second, simulated time is kept in picoseconds
+pub const PICOSECONDS: u64 = 1_000_000_000_000;
+
+// UM1879, the joystick buttons connect their pin to VDD when pressed
+const BUTTONS: [(&str, char, u32); 6] = [
+    ("centre", 'A', 0),
+    ("center", 'A', 0),
+    ("left", 'A', 1),
+    ("right", 'A', 2),
+    ("up", 'A', 3),
+    ("down", 'A', 5),
+];
+
+/**
+ * When an input event happens, either after a number of executed
+ * instructions or after an amount of simulated time
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InputTime {
+    Tick(u64),
+    Picoseconds(u64),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PinLevel {
+    High,
+    Low,
+
+    // Not driven, so the pin reads its pull-up or pull-down
+    Released,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InputEvent {
+    pub at: InputTime,
+    pub port: char,
+    pub pin: u32,
+    pub level: PinLevel,
+}
+
+/**
+ * A script of changes to GPIO inputs, applied by the board as execution
+ * reaches the time of each event.
+ *
+ * The text format has one event per line, as `<when> <pin> <level>`:
+ * - `<when>` is a number of executed instructions, or a simulated time with
+ *   one of the units `s`, `ms`, `us` or `ns` (like `2s` or `1.5ms`)
+ * - `<pin>` is a pin like `PA0`, or one of the joystick buttons `centre`,
+ *   `left`, `right`, `up` and `down`
+ * - `<level>` is `high`, `low` or `released`, or `press` (high) and `release`
+ *
+ * Blank lines and anything after a `#` are ignored.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputTimeline {
+    // Both kept in order, with the index of the next event to apply
+    by_tick: Vec<InputEvent>,
+    by_time: Vec<InputEvent>,
+    next_tick: usize,
+    next_time: usize,
+}
+
+impl InputTimeline {
+    pub fn new() -> InputTimeline {
+        return InputTimeline {
+            by_tick: Vec::new(),
+            by_time: Vec::new(),
+            next_tick: 0,
+            next_time: 0,
+        };
+    }
+
+    pub fn from_path(path: &Path) -> Result<InputTimeline, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Failed to read input timeline \"{:?}\": {}", path, e)),
+        };
+        return InputTimeline::parse(&text);
+    }
+
+    pub fn parse(text: &str) -> Result<InputTimeline, String> {
+        let mut timeline = InputTimeline::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            if fields.len() != 3 {
+                return Err(format!("Input timeline line {}: expected <when> <pin> <level>", i + 1));
+            }
+
+            let at = parse_time(fields[0]).ok_or_else(|| format!("Input timeline line {}: invalid time \"{}\"", i + 1, fields[0]))?;
+            let (port, pin) = parse_pin(fields[1]).ok_or_else(|| format!("Input timeline line {}: unknown pin \"{}\"", i + 1, fields[1]))?;
+            let level = match fields[2].to_ascii_lowercase().as_str() {
+                "high" | "press" => PinLevel::High,
+                "low" => PinLevel::Low,
+                "released" | "release" => PinLevel::Released,
+                _ => return Err(format!("Input timeline line {}: invalid level \"{}\"", i + 1, fields[2])),
+            };
+            timeline.push(InputEvent { at, port, pin, level });
+        }
+        return Ok(timeline);
+    }
+
+    /**
+     * Adds an event. Events at the same time are applied in the order they were added.
+     */
+    pub fn push(&mut self, event: InputEvent) {
+        let (events, key): (&mut Vec<InputEvent>, u64) = match event.at {
+            InputTime::Tick(t) => (&mut self.by_tick, t),
+            InputTime::Picoseconds(t) => (&mut self.by_time, t),
+        };
+        let index = events.iter().position(|e| time_key(e.at) > key).unwrap_or(events.len());
+        events.insert(index, event);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        return self.next_tick == self.by_tick.len() && self.next_time == self.by_time.len();
+    }
+
+    /**
+     * Starts playing the timeline again from its first event
+     */
+    pub fn rewind(&mut self) {
+        self.next_tick = 0;
+        self.next_time = 0;
+    }
+
+    /**
+     * Carries on from `tick` instructions and `time` picoseconds, like after
+     * restoring a snapshot, taking the events due by then as already applied
+     */
+    pub fn seek(&mut self, tick: u64, time: u64) {
+        self.next_tick = self.by_tick.partition_point(|e| time_key(e.at) <= tick);
+        self.next_time = self.by_time.partition_point(|e| time_key(e.at) <= time);
+    }
+
+    /**
+     * Removes and returns the events due once `tick` instructions have
+     * executed and `time` picoseconds have passed
+     */
+    pub fn take_due(&mut self, tick: u64, time: u64) -> Vec<InputEvent> {
+        let mut due = Vec::new();
+        while let Some(&e) = self.by_tick.get(self.next_tick) {
+            if time_key(e.at) > tick {
+                break;
+            }
+            due.push(e);
+            self.next_tick += 1;
+        }
+        while let Some(&e) = self.by_time.get(self.next_time) {
+            if time_key(e.at) > time {
+                break;
+            }
+            due.push(e);
+            self.next_time += 1;
+        }
+        return due;
+    }
+}
+
+fn time_key(at: InputTime) -> u64 {
+    return match at {
+        InputTime::Tick(t) => t,
+        InputTime::Picoseconds(t) => t,
+    };
+}
+
+fn parse_time(text: &str) -> Option<InputTime> {
+    if let Ok(tick) = text.parse::<u64>() {
+        return Some(InputTime::Tick(tick));
+    }
+
+    let units: [(&str, f64); 4] = [("ns", 1e3), ("us", 1e6), ("ms", 1e9), ("s", 1e12)];
+    for &(suffix, scale) in units.iter() {
+        if let Some(number) = text.strip_suffix(suffix) {
+            let value = number.parse::<f64>().ok()?;
+            if !value.is_finite() || value < 0.0 {
+                return None;
+            }
+            return Some(InputTime::Picoseconds((value * scale).round() as u64));
+        }
+    }
+    return None;
+}
+
+fn parse_pin(text: &str) -> Option<(char, u32)> {
+    let lower = text.to_ascii_lowercase();
+    for &(name, port, pin) in BUTTONS.iter() {
+        if lower == name {
+            return Some((port, pin));
+        }
+    }
+
+    let mut chars = lower.strip_prefix('p')?.chars();
+    let port = chars.next()?.to_ascii_uppercase();
+    let pin = chars.as_str().parse::<u32>().ok()?;
+    if !('A'..='H').contains(&port) || pin > 15 {
+        return None;
+    }
+    return Some((port, pin));
+}
@@ -4,26 +4,80 @@ extern crate goblin;
 
 mod audio;
 use audio::{AudioHandler};
+pub use audio::AudioStats;
 
 mod bytecode;
-use bytecode::{InstructionCache, InstructionContext, decode_thumb, tag, opcode::{Opcode}};
+use bytecode::{InstructionCache, InstructionContext, decode_thumb, tag};
 
 mod cpu;
 use cpu::{CPU, ExecMode, Condition};
 
+mod nvic;
+use nvic::{Nvic, IRQ_BASE, NUM_IRQS};
+
+mod systick;
+use systick::SysTick;
+
+mod peripherals;
+use peripherals::PeripheralMap;
+use peripherals::gpio::{GPIOA_BASE, GPIO_PORT_SIZE, GPIO_PORTS};
+use peripherals::rcc::{Rcc, RCC_BASE, RCC_SIZE, AHB1ENR, AHB2ENR, APB1ENR1, APB2ENR};
+use peripherals::syscfg::{Syscfg, SYSCFG_BASE, SYSCFG_SIZE};
+use peripherals::exti::{Exti, EXTI_BASE, EXTI_SIZE};
+use peripherals::registers::RegisterBank;
+use peripherals::usart::{USART2_BASE, USART_SIZE, USART2_IRQ};
+pub use peripherals::{Peripheral, PeripheralInfo, Interrupts};
+pub use peripherals::rcc::ClockGate;
+pub use peripherals::gpio::{Gpio, PinChange};
+pub use peripherals::usart::{Usart, SerialBackend};
+
+mod semihosting;
+use semihosting::{Semihosting, SemihostingReply, SEMIHOSTING_BKPT};
+pub use semihosting::{ADP_STOPPED_BRANCH_THROUGH_ZERO, ADP_STOPPED_UNDEFINED_INSTR, ADP_STOPPED_PREFETCH_ABORT, ADP_STOPPED_DATA_ABORT, ADP_STOPPED_ADDRESS_EXCEPTION};
+pub use semihosting::{ADP_STOPPED_BREAK_POINT, ADP_STOPPED_WATCH_POINT, ADP_STOPPED_STEP_COMPLETE, ADP_STOPPED_USER_INTERRUPTION, ADP_STOPPED_APPLICATION_EXIT, ADP_STOPPED_STACK_OVERFLOW, ADP_STOPPED_DIVISION_BY_ZERO};
+use semihosting::{SYS_OPEN, SYS_CLOSE, SYS_WRITEC, SYS_WRITE0, SYS_WRITE, SYS_READ, SYS_CLOCK, SYS_EXIT, SYS_EXIT_EXTENDED};
+
+mod input;
+use input::PICOSECONDS;
+pub use input::{InputTimeline, InputEvent, InputTime, PinLevel};
+
+mod error;
+pub use error::EmulatorError;
+
+mod events;
+use events::Logger;
+
+mod history;
+pub use history::{History, ReverseStop};
+
+mod watchpoint;
+use watchpoint::Watchpoints;
+pub use watchpoint::{Watchpoint, WatchKind};
+
+mod snapshot;
+use snapshot::{SNAPSHOT_MAGIC, SNAPSHOT_VERSION};
+pub use snapshot::{SnapshotWriter, SnapshotReader};
+pub use events::{Event, EventSink, ConsoleSink, LogLevel};
+pub use bytecode::opcode::Opcode;
+
 mod utils;
 use utils::bits::{self, bitset, add_with_carry, shift, shift_c, align, word_align, sign_extend, shifted_sign_extend};
 
 use goblin::elf::Elf;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::hint::unreachable_unchecked;
 use std::collections::HashMap;
-use std::{fmt, fs, string::String, option::Option};
+use std::sync::mpsc::Sender;
+use std::io::{Read, Write};
+use std::{cmp, fmt, fs, string::String, option::Option};
 
 pub type ByteInstruction = (u32, u32); // Intermediate bytecode format for more efficient decode and execution
 
-#[derive(Copy, Clone, Debug)]
-enum RegFormat {
+/**
+ * How a register is shown when the board is printed
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RegFormat {
     Bin, // binary
     Oct, // octal
     Dec, // unsigned decimal
@@ -31,7 +85,23 @@ enum RegFormat {
     Hex, // hexadecimal
 }
 
-#[derive(Debug)]
+impl RegFormat {
+    /**
+     * Parses `bin`, `oct`, `dec`, `sig` or `hex`
+     */
+    pub fn parse(text: &str) -> Result<RegFormat, String> {
+        return match text.to_ascii_lowercase().as_str() {
+            "bin" => Ok(RegFormat::Bin),
+            "oct" => Ok(RegFormat::Oct),
+            "dec" => Ok(RegFormat::Dec),
+            "sig" => Ok(RegFormat::Sig),
+            "hex" => Ok(RegFormat::Hex),
+            _ => Err(format!("Unknown register format \"{}\", expected bin, oct, dec, sig or hex", text)),
+        };
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 enum Exception {
     Reset,
     NonMaskableInterrupt,
@@ -45,6 +115,39 @@ enum Exception {
     SysTick,
 }
 
+impl Exception {
+    fn number(&self) -> u32 {
+        // B1.5.2 p525
+        return match self {
+            Exception::Reset => 1,
+            Exception::NonMaskableInterrupt => 2,
+            Exception::HardFault => 3,
+            Exception::MemManage => 4,
+            Exception::BusFault => 5,
+            Exception::UsageFault => 6,
+            Exception::SupervisorCall => 11,
+            Exception::DebugMonitor => 12,
+            Exception::PendSV => 14,
+            Exception::SysTick => 15,
+        };
+    }
+
+    /**
+     * Faults and debug events return to the instruction that caused them
+     * so it can be retried. Everything else returns to the next instruction.
+     */
+    fn returns_to_current_instruction(&self) -> bool {
+        return match self {
+            Exception::HardFault |
+            Exception::MemManage |
+            Exception::BusFault |
+            Exception::UsageFault |
+            Exception::DebugMonitor => true,
+            _ => false,
+        };
+    }
+}
+
 #[derive(Debug)]
 enum AccessType {
     Normal,
@@ -54,6 +157,8 @@ enum AccessType {
 pub enum Location {
     Flash(usize),
     Ram(usize),
+    System(usize),
+    Peripheral(usize, u32),
 }
 
 // Gets the audio buffer amount in seconds
@@ -74,6 +179,72 @@ pub fn get_buffer_from_argv() -> Option<u32> {
     return None;
 }
 
+// Gets the number of clock cycles to count for each instruction
+pub fn get_cycles_per_instruction_from_argv() -> Option<u32> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--cpi" {
+            let amount = args.next()?;
+            return Some(match amount.parse::<u32>() {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Failed to read cycles per instruction: {}", e);
+                    return None;
+                }
+            });
+        }
+    }
+    return None;
+}
+
+// Gets the least severe level of diagnostics to print
+pub fn get_log_level_from_argv() -> Option<LogLevel> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--log-level" {
+            let level = args.next()?;
+            return match LogLevel::parse(&level) {
+                Ok(l) => Some(l),
+                Err(e) => {
+                    println!("Failed to read log level: {}", e);
+                    None
+                }
+            };
+        }
+    }
+    return None;
+}
+
+// Gets where the USART2 virtual COM port is connected on the host
+pub fn get_serial_backend_from_argv() -> Option<SerialBackend> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--serial" {
+            let backend = args.next()?;
+            return match SerialBackend::parse(&backend) {
+                Ok(b) => Some(b),
+                Err(e) => {
+                    println!("Failed to read serial backend: {}", e);
+                    None
+                }
+            };
+        }
+    }
+    return None;
+}
+
+// Gets the path of the GPIO input timeline to play during execution
+pub fn get_input_timeline_path_from_argv() -> Option<PathBuf> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--input" {
+            let path = args.next()?;
+            return Some(PathBuf::from(&path));
+        }
+    }
+    return None;
+}
+
 #[derive(Debug)]
 pub struct ExclusiveMonitors {
     region: Option<()>,
@@ -106,6 +277,15 @@ impl ExclusiveMonitors {
         return Ok(passed);
     }
 
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_bool(self.region.is_some());
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        self.region = if snapshot.read_bool()? { Some(()) } else { None };
+        return Ok(());
+    }
+
     fn is_exclusive_local(&self, _address: u32, _size: u32) -> bool {
         return match self.region {
             Some(()) => true,
@@ -121,6 +301,10 @@ impl ExclusiveMonitors {
 pub struct MemoryBus {
     flash: Box<[u8]>,
     data: Box<[u8]>,
+    nvic: Nvic,
+    systick: SysTick,
+    peripherals: PeripheralMap,
+    watchpoints: Watchpoints,
 }
 
 impl fmt::Debug for MemoryBus {
@@ -161,7 +345,7 @@ fn write_value(mut value: u32, bank: &mut[u8], base: usize, size: usize) -> Resu
     return Ok(());
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum MemError {
     OutOfBounds,
     ReadOnly,
@@ -175,26 +359,90 @@ impl fmt::Display for MemError {
     }
 }
 
+// Flash is erased a 2 KiB page at a time (RM0351 3.3.1)
+pub const FLASH_PAGE_SIZE: u32 = 0x800;
+
+// SRAM1 is the first 96 KiB of RAM, and SRAM2 follows it (RM0351 2.4)
+const SRAM1_SIZE: u32 = 96 * 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MemoryKind {
+    Flash,
+    Ram,
+    Peripheral,
+}
+
+/**
+ * A range of addresses the memory bus responds to
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MemoryRegion {
+    pub name: &'static str,
+    pub start: u32,
+    pub length: u32,
+    pub kind: MemoryKind,
+}
+
 impl MemoryBus {
     fn new() -> MemoryBus {
-        return MemoryBus {
+        let mut memory = MemoryBus {
             flash: vec![0xFF; 1024 * 512].into_boxed_slice(),
             data: vec![0xFF; 1024 * 124].into_boxed_slice(),
+            nvic: Nvic::new(),
+            systick: SysTick::new(),
+            peripherals: PeripheralMap::new(),
+            watchpoints: Watchpoints::new(),
         };
+        memory.register_board_peripherals();
+        return memory;
     }
 
-    fn load_elf(&mut self, elf: Elf, bytes: &[u8]) -> Result<(), String> {
+    fn register_board_peripherals(&mut self) {
+        // RM0351 2.2.2 memory map, and 6.4.16 - 6.4.21 for the clock enable bits
+        self.register_peripheral(RCC_BASE, RCC_SIZE, Box::new(Rcc::new())).expect("RCC overlaps");
+
+        // Flash wait states (ACR) and the power controller (voltage range) are just stored
+        let flash_interface = RegisterBank::new("FLASH", 0x400, &[(0x00, 0x0000_0600), (0x14, 0xC000_0000), (0x20, 0xFFEF_F8AA)], &[0x10]);
+        let flash_gate = ClockGate::new(AHB1ENR, 8);
+        self.register_clocked_peripheral(0x4002_2000, 0x400, flash_gate, Box::new(flash_interface)).expect("FLASH overlaps");
+        let pwr = RegisterBank::new("PWR", 0x400, &[(0x00, 0x0000_0200), (0x08, 0x0000_8000)], &[0x10, 0x14]);
+        let pwr_gate = ClockGate::new(APB1ENR1, 28);
+        self.register_clocked_peripheral(0x4000_7000, 0x400, pwr_gate, Box::new(pwr)).expect("PWR overlaps");
+
+        for (i, &port) in GPIO_PORTS.iter().enumerate() {
+            let base = GPIOA_BASE + GPIO_PORT_SIZE * i as u32;
+            let gate = ClockGate::new(AHB2ENR, i as u32);
+            self.register_clocked_peripheral(base, GPIO_PORT_SIZE, gate, Box::new(Gpio::new(port))).expect("GPIO ports overlap");
+        }
+
+        // EXTI is always clocked, SYSCFG selects which port drives each of its GPIO lines
+        let syscfg_gate = ClockGate::new(APB2ENR, 0);
+        self.register_clocked_peripheral(SYSCFG_BASE, SYSCFG_SIZE, syscfg_gate, Box::new(Syscfg::new())).expect("SYSCFG overlaps");
+        self.register_peripheral(EXTI_BASE, EXTI_SIZE, Box::new(Exti::new())).expect("EXTI overlaps");
+
+        let usart2_gate = ClockGate::new(APB1ENR1, 17);
+        self.register_clocked_peripheral(USART2_BASE, USART_SIZE, usart2_gate, Box::new(Usart::new(USART2_IRQ))).expect("USART2 overlaps");
+    }
+
+    fn load_elf(&mut self, elf: Elf, bytes: &[u8]) -> Result<(), EmulatorError> {
         for header in elf.program_headers.iter() {
             if header.p_type != goblin::elf::program_header::PT_LOAD {
-                return Err(String::from("Unexpected program header type"));
+                return Err(EmulatorError::ElfLoad(String::from("Unexpected program header type")));
             }
 
-            let phys_adr = header.p_paddr as usize;
             let offset = header.p_offset as usize;
             let size = header.p_filesz as usize;
+            if size == 0 {
+                continue;
+            }
+
+            let phys_adr = match self.flash_offset(header.p_paddr as u32) {
+                Some(a) => a as usize,
+                None => return Err(EmulatorError::ElfLoad(format!("Cannot load content to 0x{:08X}, it is not in flash", header.p_paddr))),
+            };
 
             if phys_adr + size > self.flash.len() {
-                return Err(String::from("Flash too small to fit content"));
+                return Err(EmulatorError::ElfLoad(String::from("Flash too small to fit content")));
             }
 
             for i in 0..size {
@@ -204,9 +452,98 @@ impl MemoryBus {
         return Ok(());
     }
 
-    fn get_instr_word(&self, address: u32) -> Result<u32, String> {
-        if address <= (self.flash.len() as u32) {
-            let base = address as usize;
+    /**
+     * Flash is mapped at 0x0800_0000, and aliased at 0x0000_0000 when booting from it
+     */
+    fn flash_offset(&self, address: u32) -> Option<u32> {
+        return match address {
+            0x0000_0000..=0x000F_FFFF => Some(address),
+            0x0800_0000..=0x080F_FFFF => Some(address - 0x0800_0000),
+            _ => None,
+        };
+    }
+
+    /**
+     * The regions that are mapped, in address order. Peripheral regions
+     * cover the whole bus, although only registered peripherals respond.
+     * SRAM2 is only mapped after SRAM1, not at its 0x1000_0000 alias.
+     */
+    pub fn regions(&self) -> Vec<MemoryRegion> {
+        let flash = self.flash.len() as u32;
+        let ram = self.data.len() as u32;
+        return vec![
+            MemoryRegion { name: "Flash (boot alias)", start: 0x0000_0000, length: flash, kind: MemoryKind::Flash },
+            MemoryRegion { name: "Flash", start: 0x0800_0000, length: flash, kind: MemoryKind::Flash },
+            MemoryRegion { name: "SRAM1", start: 0x2000_0000, length: SRAM1_SIZE, kind: MemoryKind::Ram },
+            MemoryRegion { name: "SRAM2", start: 0x2000_0000 + SRAM1_SIZE, length: ram - SRAM1_SIZE, kind: MemoryKind::Ram },
+            MemoryRegion { name: "Peripherals", start: 0x4000_0000, length: 0x2000_0000, kind: MemoryKind::Peripheral },
+            MemoryRegion { name: "Private peripheral bus", start: 0xE000_0000, length: 0x10_0000, kind: MemoryKind::Peripheral },
+        ];
+    }
+
+    /**
+     * Returns RAM, the NVIC, SysTick and the peripherals to their power-on state.
+     * Flash keeps its contents, and watchpoints stay set.
+     */
+    fn reset(&mut self) {
+        self.data.fill(0xFF);
+        self.nvic.reset();
+        self.systick.reset();
+        self.peripherals.reset();
+        self.watchpoints.take_hit();
+    }
+
+    /**
+     * Whether the `length` bytes from `address` are all in RAM, or in flash too
+     * if they are only to be read. A program passes buffers like this to
+     * semihosting calls, with lengths that cannot be trusted.
+     */
+    fn is_buffer(&self, address: u32, length: u32, writable: bool) -> bool {
+        if length == 0 {
+            return true;
+        }
+        let last = match address.checked_add(length - 1) {
+            Some(l) => l,
+            None => return false,
+        };
+        return match (self.address_to_physical(address), self.address_to_physical(last)) {
+            (Ok(Location::Ram(start)), Ok(Location::Ram(end))) => end - start == (length - 1) as usize && end < self.data.len(),
+            (Ok(Location::Flash(start)), Ok(Location::Flash(end))) if !writable => end - start == (length - 1) as usize && end < self.flash.len(),
+            _ => false,
+        };
+    }
+
+    /**
+     * The flash offsets of `length` bytes from `address`, if they are all in flash
+     */
+    fn flash_range(&self, address: u32, length: usize) -> Option<std::ops::Range<usize>> {
+        let start = self.flash_offset(address)? as usize;
+        let end = start.checked_add(length)?;
+        if end > self.flash.len() {
+            return None;
+        }
+        return Some(start..end);
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_bytes(&self.flash);
+        snapshot.write_bytes(&self.data);
+        self.nvic.save(snapshot);
+        self.systick.save(snapshot);
+        self.peripherals.save(snapshot);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        snapshot.read_bytes_into(&mut self.flash)?;
+        snapshot.read_bytes_into(&mut self.data)?;
+        self.nvic.restore(snapshot)?;
+        self.systick.restore(snapshot)?;
+        return self.peripherals.restore(snapshot);
+    }
+
+    fn get_instr_word(&self, offset: u32) -> Result<u32, EmulatorError> {
+        if offset as usize + 4 <= self.flash.len() {
+            let base = offset as usize;
             let b1 = self.flash[base] as u32;
             let b2 = self.flash[base + 1] as u32;
             let b3 = self.flash[base + 2] as u32;
@@ -214,7 +551,7 @@ impl MemoryBus {
             return Ok((b2 << 24) + (b1 << 16) + (b4 << 8) + b3);
         }
 
-        return Err(format!("Out of bounds access for instruction address 0x{:08X}", address));
+        return Err(EmulatorError::FetchFault { address: offset });
     }
 
     fn read_mem_a(&self, address: u32, size: usize) -> Result<u32, MemError> {
@@ -222,24 +559,64 @@ impl MemoryBus {
         return self.read_mem_a_with_priv(address, size, &AccessType::Normal);
     }
 
-    fn read_system_memory(&self, _address: u32, size: usize) -> Result<u32, MemError> {
-        // TODO: emulate system memory properly.
-        return Ok(0xFFFF_FFFF >> (32 - 8 * size));
+    fn read_system_memory(&self, offset: usize, size: usize, peek: bool) -> Result<u32, MemError> {
+        // B3.1 p648, System Control Space
+        return Ok(match offset {
+            0x010..=0x01F => {
+                let shift = 8 * (offset & 0b11);
+                let register = (offset & !0b11) - 0x010;
+                let value = if peek { self.systick.peek(register) } else { self.systick.read(register) };
+                (value >> shift) & (0xFFFF_FFFF >> (32 - 8 * size))
+            }
+            _ => self.nvic.read(offset, size),
+        });
+    }
+
+    fn write_system_memory(&mut self, offset: usize, size: usize, value: u32) -> Result<(), MemError> {
+        match offset {
+            // The SysTick registers are only word accessible
+            0x010..=0x01F if size == 4 => self.systick.write(offset - 0x010, value),
+            0x010..=0x01F => {}
+            _ => self.nvic.write(offset, size, value),
+        }
+        return Ok(());
     }
 
     fn read_mem_a_with_priv(&self, address: u32, size: usize, _access_type: &AccessType) -> Result<u32, MemError> {
         // B2.3.4 p583
         if address != align(address, size as u32) {
             // Set UFSR.UNALIGNED = true;
-            println!("UsageFault: unaligned memory access");
             return Err(MemError::Unaligned);
         }
 
         // let memaddrdesc = validate_address(address, access_type, false); // TODO
         let location = self.address_to_physical(address)?;
+        self.watchpoints.check(address, size, false);
         return match location {
             Location::Flash(i) => read_value(&*self.flash, i, size),
             Location::Ram(i) => read_value(&*self.data, i, size),
+            Location::System(i) => self.read_system_memory(i, size, false),
+            Location::Peripheral(i, offset) => self.peripherals.read(i, offset, size),
+        };
+    }
+
+    /**
+     * Reads memory for a debugger. Unlike a read by the program, this does not
+     * trigger watchpoints or change registers that clear when read, like USART RDR.
+     */
+    pub fn peek_mem(&self, address: u32, size: usize) -> Result<u32, MemError> {
+        if address != align(address, size as u32) {
+            let mut result: u32 = 0;
+            for i in 0..(size as u32) {
+                result += self.peek_mem(address + i, 1)? << (8 * i);
+            }
+            return Ok(result);
+        }
+        return match self.address_to_physical(address)? {
+            Location::Flash(i) => read_value(&*self.flash, i, size),
+            Location::Ram(i) => read_value(&*self.data, i, size),
+            Location::System(i) => self.read_system_memory(i, size, true),
+            Location::Peripheral(i, offset) => self.peripherals.peek(i, offset, size),
         };
     }
 
@@ -268,7 +645,16 @@ impl MemoryBus {
         let address = address as usize;
         let location = match address {
             0x0000_0000..=0x000F_FFFF => Location::Flash(address),
+            0x0800_0000..=0x080F_FFFF => Location::Flash(address - 0x0800_0000),
             0x2000_0000..=0x2001_EFFF => Location::Ram(address - 0x2000_0000),
+            0xE000_E000..=0xE000_EFFF => Location::System(address - 0xE000_E000),
+            0x4000_0000..=0x5FFF_FFFF |
+            0xE000_0000..=0xE00F_FFFF => {
+                return match self.peripherals.find(address as u32) {
+                    Some((i, offset)) => Ok(Location::Peripheral(i, offset)),
+                    None => Err(MemError::OutOfBounds),
+                };
+            }
             _ => {
                 return Err(MemError::OutOfBounds);
             }
@@ -276,16 +662,62 @@ impl MemoryBus {
         return Ok(location);
     }
 
+    /**
+     * Maps `peripheral` to the `size` bytes from `base`. The range must be within the
+     * peripheral region (0x4000_0000 to 0x5FFF_FFFF) or the private peripheral bus
+     * (0xE000_0000 to 0xE00F_FFFF, except the System Control Space), and not overlap
+     * any other peripheral.
+     */
+    pub fn register_peripheral(&mut self, base: u32, size: u32, peripheral: Box<dyn Peripheral>) -> Result<(), EmulatorError> {
+        return self.peripherals.register(base, size, None, peripheral);
+    }
+
+    /**
+     * Like `register_peripheral`, but the peripheral reads as zero and ignores
+     * writes while its clock is disabled in the RCC
+     */
+    pub fn register_clocked_peripheral(&mut self, base: u32, size: u32, clock: ClockGate, peripheral: Box<dyn Peripheral>) -> Result<(), EmulatorError> {
+        return self.peripherals.register(base, size, Some(clock), peripheral);
+    }
+
+    /**
+     * The peripheral registered at `base`, if it is a `T`
+     */
+    pub fn peripheral_mut<T: Peripheral + 'static>(&mut self, base: u32) -> Option<&mut T> {
+        return self.peripherals.get_mut(base);
+    }
+
+    pub fn system_clock_frequency(&self) -> u32 {
+        return self.peripherals.system_clock_frequency();
+    }
+
+    fn tick_peripherals(&mut self, cycles: u32) {
+        self.peripherals.tick(cycles, &mut self.nvic);
+    }
+
     fn write_mem_u(&mut self, address: u32, size: usize, value: u32) -> Result<(), MemError> {
         let location = self.address_to_physical(address)?;
+        self.watchpoints.check(address, size, true);
         return match location {
             Location::Flash(_) => Err(MemError::ReadOnly),
             Location::Ram(i) => {
                 write_value(value, &mut *self.data, i, size)
             }
+            Location::System(i) => self.write_system_memory(i, size, value),
+            Location::Peripheral(i, offset) => self.peripherals.write(i, offset, size, value),
         }
     }
 
+    /**
+     * Fails if a write to `address` cannot succeed, without writing anything
+     */
+    fn check_writable(&self, address: u32) -> Result<(), MemError> {
+        return match self.address_to_physical(address)? {
+            Location::Flash(_) => Err(MemError::ReadOnly),
+            _ => Ok(()),
+        };
+    }
+
     fn write_mem_a(&mut self, address: u32, size: usize, value: u32) -> Result<(), MemError> {
         // TODO
         return self.write_mem_u(address, size, value);
@@ -295,6 +727,28 @@ impl MemoryBus {
 #[derive(Debug)]
 pub struct Board {
     tick: u128,
+    cycles_per_instruction: u32,
+
+    // Simulated time in picoseconds, from the cycles run at the SYSCLK frequency
+    time: u64,
+
+    // What is left of a picosecond after dividing by the frequency, carried to
+    // the next step so that time does not drift at clocks that do not divide 10^12
+    time_remainder: u64,
+    input_timeline: Option<InputTimeline>,
+
+    semihosting: Semihosting,
+
+    // What the last semihosting call to reach the host did, and what the next
+    // one should do instead of reaching the host, for History
+    semihosting_reply: Option<SemihostingReply>,
+    semihosting_replay: Option<SemihostingReply>,
+
+    // Set once the program exits through semihosting, after which nothing is executed
+    exit_status: Option<u32>,
+
+    // The semihosting reason, if the program stopped for something other than a normal exit
+    exit_reason: Option<u32>,
     pub audio_handler: AudioHandler,
     instruction_cache: InstructionCache,
     pub cpu: CPU,
@@ -305,8 +759,21 @@ pub struct Board {
     // WIP: Still needs testing against actual board
     exclusive_monitors: ExclusiveMonitors,
 
-    // HACK: To trigger default handler
-    pending_default_handler: std::cell::Cell<bool>,
+    // Raised while executing an instruction, taken once it completes
+    pending_exception: std::cell::Cell<Option<Exception>>,
+    pending_exception_return: Option<u32>,
+
+    // Why the pending exception was raised, returned by `step` once it is taken
+    pending_error: std::cell::RefCell<Option<EmulatorError>>,
+
+    // Shared with the CPU and audio handler, so they report to the same sink
+    logger: Logger,
+
+    // The watchpoint the last step stopped on
+    watchpoint_hit: Option<Watchpoint>,
+
+    // The immediate of the BKPT the last step executed, if it was not a semihosting call
+    bkpt_hit: Option<u8>,
 }
 
 /**
@@ -316,8 +783,18 @@ pub struct Board {
  */
 impl Board {
     pub fn new() -> Board {
-        return Board {
+        let logger = Logger::new();
+        let mut board = Board {
             tick: 0,
+            cycles_per_instruction: 1,
+            time: 0,
+            time_remainder: 0,
+            input_timeline: None,
+            semihosting: Semihosting::new(),
+            semihosting_reply: None,
+            semihosting_replay: None,
+            exit_status: None,
+            exit_reason: None,
             audio_handler: AudioHandler::new(),
             cpu: CPU::new(),
             instruction_cache: InstructionCache::new(),
@@ -325,85 +802,446 @@ impl Board {
             register_formats: [RegFormat::Hex; 16],
             branch_map: HashMap::new(),
             exclusive_monitors: ExclusiveMonitors::new(),
-            pending_default_handler: std::cell::Cell::new(false),
+            pending_exception: std::cell::Cell::new(None),
+            pending_exception_return: None,
+            pending_error: std::cell::RefCell::new(None),
+            logger: logger.clone(),
+            watchpoint_hit: None,
+            bkpt_hit: None,
         };
+        board.cpu.logger = logger.clone();
+        board.audio_handler.logger = logger;
+        return board;
     }
 
-    pub fn step(&mut self) -> Result<(), String> {
+    /**
+     * Executes one instruction, then takes any exception that is due. Faults
+     * caused by the instruction are returned after their handler has been
+     * entered, so the board can still be stepped unless `is_fatal` is true.
+     */
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        self.watchpoint_hit = None;
+        self.bkpt_hit = None;
+        if self.exit_status.is_some() {
+            return Ok(());
+        }
+
+        // Accesses by the debugger since the last step do not count
+        self.memory.watchpoints.take_hit();
+
         match self.fetch() {
             Ok((i, w)) => {
                 if let Err(e) = self.execute(i, w) {
-                    println!("failed to execute instruction: {}", e);
-                    self.pending_default_handler.set(true);
+                    self.record_error(e);
+                    self.raise_exception(Exception::UsageFault);
                 }
             }
             Err(e) => {
-                println!("failed to fetch instruction: {}", e);
-                self.pending_default_handler.set(true);
+                self.record_error(e);
+                self.raise_exception(Exception::BusFault);
             }
         };
 
-        if self.pending_default_handler.get() {
-            match self.try_goto_default_handler() {
-                Ok(_) => {},
-                Err(e) => {
-                    println!("FATAL ERROR: {}", e);
-                    return Err(e);
-                }
-            }
-        }
+        if self.memory.systick.advance(self.cycles_per_instruction) {
+            self.memory.nvic.set_pending(Exception::SysTick.number(), true);
+        }
+        self.memory.tick_peripherals(self.cycles_per_instruction);
+        self.advance_time();
+
+        // Applied as soon as they are due, so that a restored snapshot can tell
+        // how far through the timeline it is from its tick and time alone
+        self.apply_input_timeline();
+
+        let handled = self.handle_exceptions();
+        self.watchpoint_hit = self.memory.watchpoints.take_hit();
+        let error = self.pending_error.borrow_mut().take();
+        handled?;
+        return match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    }
+
+    /**
+     * Steps up to `steps` times, stopping at the first error
+     */
+    pub fn step_n(&mut self, steps: u32) -> Result<(), EmulatorError> {
+        for _ in 0..steps {
+            self.step()?;
+        }
+        return Ok(());
+    }
+
+    pub fn spawn_audio(&mut self) {
+        self.audio_handler.spawn_audio();
+    }
+
+    pub fn spawn_buffered_audio(&mut self, buffer_ms_size: u32) {
+        self.audio_handler.spawn_buffered_audio(buffer_ms_size);
+    }
+
+    /**
+     * How many samples the program has played, and whether they go anywhere
+     */
+    pub fn audio_stats(&self) -> AudioStats {
+        return self.audio_handler.stats();
+    }
+
+    /**
+     * Adds a device model to the memory bus, see `MemoryBus::register_peripheral`
+     */
+    pub fn register_peripheral(&mut self, base: u32, size: u32, peripheral: Box<dyn Peripheral>) -> Result<(), EmulatorError> {
+        return self.memory.register_peripheral(base, size, peripheral);
+    }
+
+    /**
+     * The peripherals on the memory bus, in address order
+     */
+    pub fn peripherals(&self) -> Vec<PeripheralInfo> {
+        return self.memory.peripherals.describe();
+    }
+
+    /**
+     * Stops on accesses to the bytes watched by `watchpoint`, see `watchpoint_hit`
+     */
+    pub fn insert_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.memory.watchpoints.insert(watchpoint);
+    }
+
+    /**
+     * Removes a watchpoint added with `insert_watchpoint`, returning false if there was none
+     */
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        return self.memory.watchpoints.remove(watchpoint);
+    }
+
+    /**
+     * Removes every watchpoint, like when the debugger that set them goes away
+     */
+    pub fn clear_watchpoints(&mut self) {
+        self.memory.watchpoints.clear();
+    }
+
+    /**
+     * The first watchpoint hit by the last step, including by exception entry and return
+     */
+    pub fn watchpoint_hit(&self) -> Option<Watchpoint> {
+        return self.watchpoint_hit;
+    }
+
+    /**
+     * GPIO port `port`, from 'A' to 'H'
+     */
+    pub fn gpio(&mut self, port: char) -> Option<&mut Gpio> {
+        let index = GPIO_PORTS.iter().position(|&p| p == port)?;
+        return self.memory.peripheral_mut(GPIOA_BASE + GPIO_PORT_SIZE * index as u32);
+    }
+
+    /**
+     * USART2, which is connected to the ST-LINK virtual COM port
+     */
+    pub fn usart2(&mut self) -> &mut Usart {
+        return self.memory.peripheral_mut(USART2_BASE).expect("USART2 is always registered");
+    }
+
+    /**
+     * Connects USART2 to the host
+     */
+    pub fn connect_serial(&mut self, backend: &SerialBackend) -> Result<(), String> {
+        return self.usart2().connect(backend);
+    }
+
+    /**
+     * Sends every change in GPIO pin level, on any port, to `observer`
+     */
+    pub fn set_gpio_observer(&mut self, observer: Sender<PinChange>) {
+        for &port in GPIO_PORTS.iter() {
+            if let Some(gpio) = self.gpio(port) {
+                gpio.set_observer(observer.clone());
+            }
+        }
+    }
+
+    /**
+     * Sets how many processor clock cycles each instruction takes. Timers like
+     * SysTick count these cycles, so it scales emulated time against instructions.
+     */
+    pub fn set_cycles_per_instruction(&mut self, cycles: u32) {
+        self.cycles_per_instruction = cycles;
+    }
+
+    /**
+     * Plays `timeline` from now on, driving GPIO inputs as each event comes due.
+     * Tick events count all instructions executed since the program was loaded.
+     */
+    pub fn set_input_timeline(&mut self, timeline: InputTimeline) {
+        self.input_timeline = Some(timeline);
+        self.apply_input_timeline();
+    }
+
+    pub fn load_input_timeline_from_path(&mut self, path: &Path) -> Result<(), String> {
+        let timeline = InputTimeline::from_path(path)?;
+        self.set_input_timeline(timeline);
+        return Ok(());
+    }
+
+    /**
+     * The number of instructions executed since the program was loaded
+     */
+    pub fn instruction_count(&self) -> u128 {
+        return self.tick;
+    }
+
+    /**
+     * Simulated time since the program was loaded, in picoseconds
+     */
+    pub fn simulated_time(&self) -> u64 {
+        return self.time;
+    }
+
+    fn advance_time(&mut self) {
+        let frequency = u64::from(self.memory.system_clock_frequency());
+        if frequency != 0 {
+            let picoseconds = u64::from(self.cycles_per_instruction) * PICOSECONDS + self.time_remainder;
+            self.time += picoseconds / frequency;
+            self.time_remainder = picoseconds % frequency;
+        }
+    }
+
+    fn apply_input_timeline(&mut self) {
+        let events = match &mut self.input_timeline {
+            Some(t) => t.take_due(self.tick as u64, self.time),
+            None => return,
+        };
+        for event in events {
+            if let Some(gpio) = self.gpio(event.port) {
+                match event.level {
+                    PinLevel::High => gpio.set_input(event.pin, true),
+                    PinLevel::Low => gpio.set_input(event.pin, false),
+                    PinLevel::Released => gpio.release_input(event.pin),
+                }
+            }
+        }
+    }
+
+    /**
+     * The status the program exited with through semihosting, if it has exited
+     */
+    pub fn exit_status(&self) -> Option<u32> {
+        return self.exit_status;
+    }
+
+    /**
+     * The reason passed to SYS_EXIT or SYS_EXIT_EXTENDED, if the program stopped
+     * for something other than ADP_Stopped_ApplicationExit, like a runtime error
+     */
+    pub fn exit_reason(&self) -> Option<u32> {
+        return self.exit_reason;
+    }
+
+    /**
+     * The immediate of the BKPT instruction executed by the last step. Semihosting
+     * calls do not count. Execution carries on after the BKPT when stepped again.
+     */
+    pub fn bkpt_hit(&self) -> Option<u8> {
+        return self.bkpt_hit;
+    }
+
+    /**
+     * Saves the state of the board: the CPU registers, memory, exception and
+     * peripheral state, and the tick counter and simulated time. The program
+     * symbols the emulator stands in for are included, so the snapshot can be
+     * restored without loading the ELF file again. Host connections (serial
+     * backends, observers, semihosting files) and input timelines are not. An
+     * input timeline carries on from the tick and time the snapshot was taken at.
+     */
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut snapshot = SnapshotWriter::new();
+        for &byte in SNAPSHOT_MAGIC.iter() {
+            snapshot.write_u8(byte);
+        }
+        snapshot.write_u32(SNAPSHOT_VERSION);
+
+        snapshot.write_u128(self.tick);
+        snapshot.write_u64(self.time);
+        snapshot.write_u64(self.time_remainder);
+        snapshot.write_u32(self.cycles_per_instruction);
+        snapshot.write_bool(self.exit_status.is_some());
+        snapshot.write_u32(self.exit_status.unwrap_or(0));
+        snapshot.write_bool(self.exit_reason.is_some());
+        snapshot.write_u32(self.exit_reason.unwrap_or(0));
+
+        let mut branches: Vec<(&u32, &String)> = self.branch_map.iter().collect();
+        branches.sort();
+        snapshot.write_u32(branches.len() as u32);
+        for (&address, name) in branches {
+            snapshot.write_u32(address);
+            snapshot.write_bytes(name.as_bytes());
+        }
+
+        self.cpu.save(&mut snapshot);
+        self.exclusive_monitors.save(&mut snapshot);
+        self.memory.save(&mut snapshot);
+        return snapshot.into_bytes();
+    }
+
+    /**
+     * Restores a state saved by `snapshot`. If this fails part way, the board
+     * is left in an inconsistent state and should be restored again.
+     */
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), EmulatorError> {
+        let mut snapshot = SnapshotReader::new(bytes);
+        let mut magic = [0; 8];
+        for byte in magic.iter_mut() {
+            *byte = snapshot.read_u8()?;
+        }
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(EmulatorError::Snapshot(String::from("Not a board snapshot")));
+        }
+        let version = snapshot.read_u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(EmulatorError::Snapshot(format!("Unsupported snapshot version {}, expected {}", version, SNAPSHOT_VERSION)));
+        }
+
+        self.tick = snapshot.read_u128()?;
+        self.time = snapshot.read_u64()?;
+        self.time_remainder = snapshot.read_u64()?;
+        self.cycles_per_instruction = snapshot.read_u32()?;
+        let exited = snapshot.read_bool()?;
+        let status = snapshot.read_u32()?;
+        self.exit_status = if exited { Some(status) } else { None };
+        let stopped = snapshot.read_bool()?;
+        let reason = snapshot.read_u32()?;
+        self.exit_reason = if stopped { Some(reason) } else { None };
+
+        self.branch_map.clear();
+        for _ in 0..snapshot.read_u32()? {
+            let address = snapshot.read_u32()?;
+            let name = match std::str::from_utf8(snapshot.read_bytes()?) {
+                Ok(n) => n.to_string(),
+                Err(_) => return Err(EmulatorError::Snapshot(String::from("Invalid symbol name"))),
+            };
+            self.branch_map.insert(address, name);
+        }
+
+        self.cpu.restore(&mut snapshot)?;
+        self.exclusive_monitors.restore(&mut snapshot)?;
+        self.memory.restore(&mut snapshot)?;
+        if !snapshot.is_finished() {
+            return Err(EmulatorError::Snapshot(String::from("Unexpected data after the end of the snapshot")));
+        }
+
+        // The inputs driven by the events up to now are part of the GPIO state
+        if let Some(timeline) = &mut self.input_timeline {
+            timeline.seek(self.tick as u64, self.time);
+        }
+
+        // Decoded instructions may be stale now flash has changed
+        self.instruction_cache = InstructionCache::new();
+        self.pending_exception.set(None);
+        self.pending_exception_return = None;
+        self.pending_error.borrow_mut().take();
+        return Ok(());
+    }
+
+    pub fn save_snapshot_to_path(&self, path: &Path) -> Result<(), EmulatorError> {
+        return match fs::write(path, self.snapshot()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(EmulatorError::Snapshot(format!("Failed to write \"{:?}\": {}", path, e))),
+        };
+    }
+
+    pub fn load_snapshot_from_path(&mut self, path: &Path) -> Result<(), EmulatorError> {
+        let bytes = match fs::read(path) {
+            Ok(b) => b,
+            Err(e) => return Err(EmulatorError::Snapshot(format!("Failed to read \"{:?}\": {}", path, e))),
+        };
+        return self.restore(&bytes);
+    }
+
+    /**
+     * Takes what the last semihosting call that reached the host did
+     */
+    pub(crate) fn take_semihosting_reply(&mut self) -> Option<SemihostingReply> {
+        return self.semihosting_reply.take();
+    }
+
+    /**
+     * Makes the next semihosting call that would reach the host do what `reply`
+     * says instead, so that replaying a call does not repeat it on the host
+     */
+    pub(crate) fn replay_semihosting(&mut self, reply: Option<SemihostingReply>) {
+        self.semihosting_replay = reply;
+    }
 
-        return Ok(());
+    /**
+     * Sends semihosting console output to `console` instead of stdout
+     */
+    pub fn set_semihosting_console(&mut self, console: Box<dyn Write + Send>) {
+        self.semihosting.set_console(console);
     }
 
-    pub fn step_n(&mut self, steps: u32) -> Result<(), String> {
-        for _ in 0..steps {
-            self.step()?;
+    /**
+     * Reads semihosting console input from `input` instead of stdin
+     */
+    pub fn set_semihosting_input(&mut self, input: Box<dyn Read + Send>) {
+        self.semihosting.set_input(input);
+    }
+
+    /**
+     * Marks external interrupt `irq` as pending, like a peripheral would. It is
+     * taken between instructions once enabled and of sufficient priority.
+     */
+    pub fn set_pending_interrupt(&mut self, irq: u32) {
+        if irq < NUM_IRQS {
+            self.memory.nvic.set_pending(IRQ_BASE + irq, true);
         }
-        return Ok(());
     }
 
-    pub fn spawn_audio(&mut self) {
-        self.audio_handler.spawn_audio();
+    fn raise_exception(&self, exception: Exception) {
+        // Only the first exception raised by an instruction is taken
+        if self.pending_exception.get().is_none() {
+            self.pending_exception.set(Some(exception));
+        }
     }
 
-    pub fn spawn_buffered_audio(&mut self, buffer_ms_size: u32) {
-        self.audio_handler.spawn_buffered_audio(buffer_ms_size);
+    /**
+     * Sends diagnostics to `sink` instead of discarding them
+     */
+    pub fn set_event_sink(&mut self, sink: Box<dyn EventSink>) {
+        self.logger.set_sink(sink);
     }
 
-    fn get_default_or_reset_handler(&self) -> Result<u32, String> {
-        if let Ok(hard_fault_addr) = self.memory.read_mem_u(3 * 4, 4) {
-            if hard_fault_addr <= (self.memory.flash.len() as u32) {
-                return Ok(hard_fault_addr);
-            }
-        }
+    fn unpredictable(&self, what: &'static str) {
+        self.logger.emit(Event::Unpredictable { address: self.current_instruction_address(), what });
+    }
 
-        if let Ok(reset_addr) = self.memory.read_mem_u(4, 4) {
-            if reset_addr <= (self.memory.flash.len() as u32) {
-                return Ok(reset_addr);
-            }
+    fn record_error(&self, error: EmulatorError) {
+        // Like exceptions, only the first error of an instruction is kept
+        let mut pending = self.pending_error.borrow_mut();
+        if pending.is_none() {
+            *pending = Some(error);
         }
+    }
 
-        return Err(format!("Could not find fault handler"));
+    fn current_instruction_address(&self) -> u32 {
+        // The PC reads as the address of the executing instruction + 4
+        return self.cpu.read_pc().wrapping_sub(4);
     }
 
-    fn try_goto_default_handler(&mut self) -> Result<(), String> {
-        return match self.get_default_or_reset_handler() {
-            Ok(h) => {
-                self.exclusive_monitors_clear();
-                self.pending_default_handler.set(false);
-                self.branch_write_pc(h);
-                Ok(())
-            }
-            Err(e) => Err(e),
+    fn raise_memory_fault(&self, address: u32, error: &MemError) {
+        self.record_error(EmulatorError::MemoryFault { address, kind: *error });
+        match error {
+            MemError::Unaligned => self.raise_exception(Exception::UsageFault),
+            _ => self.raise_exception(Exception::BusFault),
         }
     }
 
     fn read_mem_u(&self, address: u32, size: usize) -> u32 {
         return match self.memory.read_mem_u(address, size) {
             Ok(v) => v,
-            Err(_) => {
-                self.pending_default_handler.set(true);
+            Err(e) => {
+                self.raise_memory_fault(address, &e);
                 0
             }
         }
@@ -412,8 +1250,8 @@ impl Board {
     fn read_mem_a(&self, address: u32, size: usize) -> u32 {
         return match self.memory.read_mem_a(address, size) {
             Ok(v) => v,
-            Err(_) => {
-                self.pending_default_handler.set(true);
+            Err(e) => {
+                self.raise_memory_fault(address, &e);
                 0
             }
         }
@@ -432,14 +1270,10 @@ impl Board {
         //       we just go immediately.
         if let Err(e) = self.memory.write_mem_u(address, size, value) {
             match e {
-                MemError::OutOfBounds => self.pending_default_handler.set(true),
-                MemError::ReadOnly => {
-                    println!("attempted to write to readonly memory: 0x{:08X}", address);
-                },
+                MemError::OutOfBounds => self.raise_memory_fault(address, &e),
+                MemError::ReadOnly => self.logger.emit(Event::ReadOnlyWrite { address }),
                 MemError::Unaligned => {},
-                MemError::Unimplemented => {
-                    println!("EMULATOR ERROR: unimplemented memory write");
-                }
+                MemError::Unimplemented => self.logger.emit(Event::UnimplementedWrite { address }),
             }
         }
     }
@@ -454,19 +1288,29 @@ impl Board {
      * 4. Updates instruction pointed to by instruction PC to next instruction
      * 5. Returns fetched intermediate bytecode instruction & bool of width
      */
-    fn fetch(&mut self) -> Result<(ByteInstruction, bool), String> {
+    fn fetch(&mut self) -> Result<(ByteInstruction, bool), EmulatorError> {
         let pc = self.cpu.update_instruction_address();
-        let mut instruction = self.instruction_cache.get_cached(pc)?;
+        if !self.cpu.read_thumb_mode() {
+            // B1.4.2, attempting to execute with EPSR.T == 0 is an INVSTATE UsageFault
+            self.raise_exception(Exception::UsageFault);
+            return Err(EmulatorError::InvalidState { address: pc });
+        }
+        let offset = match self.memory.flash_offset(pc) {
+            Some(o) => o,
+            None => return Err(EmulatorError::FetchFault { address: pc }),
+        };
+        let fetch_fault = |_| EmulatorError::FetchFault { address: pc };
+        let mut instruction = self.instruction_cache.get_cached(offset).map_err(fetch_fault)?;
         let mut start = tag::from(instruction);
         if !tag::has_cached(start) {
-            let raw = self.memory.get_instr_word(pc)?;
+            let raw = self.memory.get_instr_word(offset).map_err(fetch_fault)?;
             let decoded = decode_thumb(raw, InstructionContext::new(pc, self.cpu.itstate.position()));
             instruction = decoded.0;
             start = tag::from(instruction);
             if decoded.1 {
-                self.instruction_cache.write_cache_wide(pc, instruction);
+                self.instruction_cache.write_cache_wide(offset, instruction);
             } else {
-                self.instruction_cache.write_cache_narrow(pc, instruction);
+                self.instruction_cache.write_cache_narrow(offset, instruction);
             }
         }
         let wide = tag::is_wide(start);
@@ -478,7 +1322,7 @@ impl Board {
      * Execute: Takes the instruction and opcode, and executes
      * the instruction based on the opcode. It assumes
      */
-    fn execute(&mut self, instr: ByteInstruction, wide: bool) -> Result<(), String> {
+    fn execute(&mut self, instr: ByteInstruction, wide: bool) -> Result<(), EmulatorError> {
         self.tick += 1;
         let opcode = tag::get_opcode(instr.0);
         let data = instr.0 & 0xFFFF;
@@ -499,7 +1343,7 @@ impl Board {
         }
     }
 
-    fn execute_wide(&mut self, opcode: Opcode, data: u32, extra: u32) -> Result<(), String> {
+    fn execute_wide(&mut self, opcode: Opcode, data: u32, extra: u32) -> Result<(), EmulatorError> {
         match opcode {
             Opcode::AdcImm => self.w_adc_imm(data, extra),
             Opcode::AdcReg => self.w_adc_reg(data, extra),
@@ -523,8 +1367,11 @@ impl Board {
             Opcode::CmnReg => self.w_cmn_reg(data, extra),
             Opcode::CmpImm => self.w_cmp_imm(data, extra),
             Opcode::CmpReg => self.w_cmp_reg(data, extra),
+            Opcode::Dmb    => self.w_dmb(data, extra),
+            Opcode::Dsb    => self.w_dsb(data, extra),
             Opcode::EorImm => self.w_eor_imm(data, extra),
             Opcode::EorReg => self.w_eor_reg(data, extra),
+            Opcode::Isb    => self.w_isb(data, extra),
             Opcode::Ldm    => self.w_ldm(data, extra),
             Opcode::Ldmdb  => self.w_ldmdb(data, extra),
             Opcode::LdrImm => self.w_ldr_imm(data, extra),
@@ -540,9 +1387,12 @@ impl Board {
             Opcode::MovImm => self.w_mov_imm(data, extra),
             Opcode::MovReg => self.w_mov_reg(data, extra),
             Opcode::Movt   => self.w_movt(data, extra),
+            Opcode::Mrs    => self.w_mrs(data, extra),
+            Opcode::Msr    => self.w_msr(data, extra),
             Opcode::Mul    => self.w_mul(data, extra),
             Opcode::MvnImm => self.w_mvn_imm(data, extra),
             Opcode::MvnReg => self.w_mvn_reg(data, extra),
+            Opcode::Nop    => self.w_nop(data, extra),
             Opcode::OrnImm => self.w_orn_imm(data, extra),
             Opcode::OrnReg => self.w_orn_reg(data, extra),
             Opcode::OrrImm => self.w_orr_imm(data, extra),
@@ -583,15 +1433,22 @@ impl Board {
             Opcode::Umaal  => self.w_umaal(data, extra),
             Opcode::Umlal  => self.w_umlal(data, extra),
             Opcode::Umull  => self.w_umull(data, extra),
+            Opcode::Wfe    => self.w_wfe(data, extra),
+            Opcode::Wfi    => self.w_wfi(data, extra),
+            Opcode::Undefined => {
+                // The decoder keeps all but the top two bits, which are set for every wide encoding
+                let encoding = extra | (0b11 << 30);
+                return Err(EmulatorError::UndefinedInstruction { address: self.current_instruction_address(), encoding, wide: true });
+            }
             _ => {
                 // unsafe { unreachable_unchecked() }
-                return Err(format!("Unimplemented wide instruction {:?} : {:#06X} + {:#010X}", opcode, data, extra));
+                return Err(EmulatorError::UnimplementedInstruction { address: self.current_instruction_address(), opcode });
             }
         }
         return Ok(());
     }
 
-    fn execute_narrow(&mut self, opcode: Opcode, data: u32) -> Result<(), String> {
+    fn execute_narrow(&mut self, opcode: Opcode, data: u32) -> Result<(), EmulatorError> {
         match opcode {
             Opcode::AdcReg => self.n_adc_reg(data),
             Opcode::AddImm => self.n_add_imm(data),
@@ -659,9 +1516,14 @@ impl Board {
             Opcode::Udf    => self.n_udf(data),
             Opcode::Uxtb   => self.n_uxtb(data),
             Opcode::Uxth   => self.n_uxth(data),
+            Opcode::Wfe    => self.n_wfe(data),
+            Opcode::Wfi    => self.n_wfi(data),
+            Opcode::Undefined => {
+                return Err(EmulatorError::UndefinedInstruction { address: self.current_instruction_address(), encoding: data, wide: false });
+            }
             _ => {
                 // unsafe { unreachable_unchecked() }
-                return Err(format!("Unimplemented narrow instruction {:?} - {:#06X}", opcode, data));
+                return Err(EmulatorError::UnimplementedInstruction { address: self.current_instruction_address(), opcode });
             }
         }
 
@@ -671,18 +1533,18 @@ impl Board {
     /**
      * Takes a path to an ELF file and initialises the board with its contents
      */
-    pub fn load_elf_from_path(&mut self, path: &Path) -> Result<(), String> {
+    pub fn load_elf_from_path(&mut self, path: &Path) -> Result<(), EmulatorError> {
         let bytes = match fs::read(path) {
             Ok(b) => b,
             Err(e) => {
-                return Err(format!("Failed to read file \"{:?}\": {}", path, e));
+                return Err(EmulatorError::ElfLoad(format!("Failed to read file \"{:?}\": {}", path, e)));
             }
         };
 
         let elf = match Elf::parse(&bytes) {
             Ok(e) => e,
             Err(e) => {
-                return Err(format!("Failed to parse elf file \"{:?}\": {}", path, e));
+                return Err(EmulatorError::ElfLoad(format!("Failed to parse elf file \"{:?}\": {}", path, e)));
             }
         };
 
@@ -690,23 +1552,20 @@ impl Board {
             let offset = sym.st_name;
             let name = match elf.strtab.get(offset) {
                 Some(Ok(s)) => s,
-                _ => return Err(String::from("missing symbols")),
+                _ => return Err(EmulatorError::ElfLoad(String::from("missing symbols"))),
             };
 
             match name {
-                "SystemInit" |
                 "__libc_init_array" |
                 "init" |
                 "audio_init" |
                 "audio_play_sample" |
                 "init_joystick" |
                 "joystick_init_all" |
-                "joystick_enable_interrupts_all" |
                 "lcd_init" |
                 "lcd_write_char" |
                 "lcd_write_string" |
                 "lcd_update_display" |
-                "maximise_clock_speed" |
                 "BSP_AUDIO_OUT_Play_Sample" => {
                     self.branch_map.insert((sym.st_value as u32) & !0b1, name.to_string());
                 }
@@ -719,21 +1578,80 @@ impl Board {
             Err(e) => return Err(e),
         };
 
+        let pc = self.take_reset_vector();
+        self.logger.emit(Event::ProgramLoaded { entry: pc });
+        return Ok(());
+    }
+
+    /**
+     * Loads the ELF file at `path` in place of the program in flash, like after
+     * rebuilding it, and resets the board to run it. If it cannot be loaded, the
+     * old program is kept and the board is not reset.
+     */
+    pub fn reload_elf_from_path(&mut self, path: &Path) -> Result<(), EmulatorError> {
+        let flash = self.memory.flash.clone();
+        let branch_map = std::mem::take(&mut self.branch_map);
+        self.memory.flash.fill(0xFF);
+        if let Err(e) = self.load_elf_from_path(path) {
+            self.memory.flash = flash;
+            self.branch_map = branch_map;
+            return Err(e);
+        }
+        self.reset();
+        return Ok(());
+    }
+
+    /**
+     * Resets the board as if it were powered off and on again. The CPU, RAM,
+     * NVIC, SysTick and peripherals go back to their reset state, the tick
+     * counter and simulated time start again from 0, files opened through
+     * semihosting are closed, and the input timeline is replayed from the start.
+     * Flash keeps the program loaded into it, and host connections, observers
+     * and watchpoints are kept.
+     */
+    pub fn reset(&mut self) {
+        self.cpu = CPU::new();
+        self.cpu.logger = self.logger.clone();
+        self.memory.reset();
+        self.exclusive_monitors = ExclusiveMonitors::new();
+        self.instruction_cache = InstructionCache::new();
+        self.tick = 0;
+        self.time = 0;
+        self.time_remainder = 0;
+        self.exit_status = None;
+        self.exit_reason = None;
+        self.pending_exception.set(None);
+        self.pending_exception_return = None;
+        self.pending_error.borrow_mut().take();
+        self.watchpoint_hit = None;
+        self.bkpt_hit = None;
+        self.semihosting.close_files();
+        self.semihosting_reply = None;
+        self.semihosting_replay = None;
+        if let Some(timeline) = &mut self.input_timeline {
+            timeline.rewind();
+        }
+        self.apply_input_timeline();
+        self.take_reset_vector();
+    }
+
+    /**
+     * Sets SP and PC from the vector table like a reset does (B1.5.5), returning the PC
+     */
+    fn take_reset_vector(&mut self) -> u32 {
         // https://developer.arm.com/docs/dui0553/a/the-cortex-m4-processor/programmers-model/core-registers
         self.cpu.write_reg(13, self.memory.read_mem_a(0x0000_0000, 4).expect("failed to read memory at 0x0000_0000")); // set to value at address 0x0000_0000 on reset
         self.cpu.write_reg(14, 0xFFFF_FFFF); // set to 0xFFFF_FFFF on reset
         let pc = self.memory.read_mem_a(0x0000_0004, 4).expect("failed to read memory at 0x0000_0004"); // set to value at 0x0000_0004 on reset
-        println!("setting emulator pc to 0x{:08X}", pc);
         self.cpu.write_reg(15, pc & !0b1);
         self.bx_write_pc(pc);
-
-        return Ok(());
+        return pc;
     }
 
-    pub fn read_memory_region(&self, start: u32, bytes: u32) -> Result<Vec<u8>, String> {
+    pub fn read_memory_region(&self, start: u32, bytes: u32) -> Result<Vec<u8>, EmulatorError> {
         let mut out: Vec<u8> = Vec::new();
         for i in start..(start.saturating_add(bytes)) {
-            match self.memory.read_mem_u(i, 1) {
+            match self.memory.peek_mem(i, 1) {
                 Ok(i) => out.push(i as u8),
                 Err(_) => {
                     return Ok(vec![0; bytes as usize]);
@@ -743,6 +1661,63 @@ impl Board {
         return Ok(out);
     }
 
+    /**
+     * Writes `bytes` from `start` on behalf of a debugger. Nothing is written if any
+     * of the bytes are in flash or unmapped memory.
+     */
+    pub fn write_memory_region(&mut self, start: u32, bytes: &[u8]) -> Result<(), EmulatorError> {
+        for i in 0..bytes.len() as u32 {
+            let address = start.wrapping_add(i);
+            if let Err(kind) = self.memory.check_writable(address) {
+                return Err(EmulatorError::MemoryFault { address, kind });
+            }
+        }
+
+        // An aligned byte, halfword or word is written in one access, so
+        // peripheral registers see the same write the program would make
+        let size = bytes.len();
+        if (size == 1 || size == 2 || size == 4) && start.is_multiple_of(size as u32) {
+            let mut value = 0;
+            for (i, &byte) in bytes.iter().enumerate() {
+                value |= (byte as u32) << (8 * i);
+            }
+            return self.memory.write_mem_u(start, size, value).map_err(|kind| EmulatorError::MemoryFault { address: start, kind });
+        }
+
+        for (i, &byte) in bytes.iter().enumerate() {
+            let address = start.wrapping_add(i as u32);
+            if let Err(kind) = self.memory.write_mem_u(address, 1, byte as u32) {
+                return Err(EmulatorError::MemoryFault { address, kind });
+            }
+        }
+        return Ok(());
+    }
+
+    /**
+     * Erases the flash pages from `start`, which must be page aligned, like a
+     * debugger does before programming them. Erased flash reads as 0xFF.
+     */
+    pub fn erase_flash(&mut self, start: u32, length: u32) -> Result<(), EmulatorError> {
+        if !start.is_multiple_of(FLASH_PAGE_SIZE) || !length.is_multiple_of(FLASH_PAGE_SIZE) {
+            return Err(EmulatorError::MemoryFault { address: start, kind: MemError::Unaligned });
+        }
+        let range = self.memory.flash_range(start, length as usize).ok_or(EmulatorError::MemoryFault { address: start, kind: MemError::OutOfBounds })?;
+        self.memory.flash[range].fill(0xFF);
+        self.instruction_cache = InstructionCache::new();
+        return Ok(());
+    }
+
+    /**
+     * Writes `bytes` to flash from `start` on behalf of a debugger. Unlike the
+     * real flash interface, this does not need the flash to be erased first.
+     */
+    pub fn program_flash(&mut self, start: u32, bytes: &[u8]) -> Result<(), EmulatorError> {
+        let range = self.memory.flash_range(start, bytes.len()).ok_or(EmulatorError::MemoryFault { address: start, kind: MemError::OutOfBounds })?;
+        self.memory.flash[range].copy_from_slice(bytes);
+        self.instruction_cache = InstructionCache::new();
+        return Ok(());
+    }
+
     pub fn read_reg<T: Into<u32>>(&self, reg: T) -> u32 {
         let reg = reg.into();
         return self.cpu.read_reg(reg);
@@ -754,6 +1729,14 @@ impl Board {
         self.cpu.write_reg(reg, val);
     }
 
+    /**
+     * Sets how register `reg` (0 - 15) is shown when the board is printed
+     */
+    pub fn set_register_format(&mut self, reg: u32, format: RegFormat) {
+        assert!(reg <= 15);
+        self.register_formats[reg as usize] = format;
+    }
+
     fn get_register_display_value(&self, reg: u8) -> String {
         assert!(reg <= 15);
         let val = match reg {
@@ -888,8 +1871,8 @@ impl Board {
     fn bx_write_pc(&mut self, address: u32) {
         // A2.3.1 p31
         if self.cpu.current_mode == ExecMode::ModeHandler && (address >> 28) == 0xF {
-            println!("TODO: ExceptionReturn(address & !(0xF << 28))");
-            self.pending_default_handler.set(true);
+            // Deferred until the instruction completes, so any SP writeback happens first
+            self.pending_exception_return = Some(address);
         } else {
             self.blx_write_pc(address);
         }
@@ -897,11 +1880,8 @@ impl Board {
 
     fn blx_write_pc(&mut self, address: u32) {
         // A2.3.1 p31
+        // Clearing the T bit faults when the next instruction is fetched
         self.cpu.set_thumb_mode(bitset(address, 0));
-        if !self.cpu.read_thumb_mode() {
-            println!("self.raise_exception(Exception::UsageFault('Invalid State'))");
-            self.pending_default_handler.set(true);
-        }
         self.branch_to(address & !0b1);
     }
 
@@ -922,8 +1902,8 @@ impl Board {
     fn exclusive_monitors_pass(&mut self, address: u32, length: u32) -> bool {
         return match self.exclusive_monitors.exclusive_monitors_pass(address, length) {
             Ok(passed) => passed,
-            Err(_) => {
-                self.pending_default_handler.set(true);
+            Err(e) => {
+                self.raise_exception(e);
                 return false;
             }
         }
@@ -933,6 +1913,225 @@ impl Board {
         self.exclusive_monitors.clear_exclusive_local();
     }
 
+    /**
+     * Exception handling
+     */
+
+    fn handle_exceptions(&mut self) -> Result<(), EmulatorError> {
+        // A fault in the returning instruction means the return never happened
+        if let Some(exc_return) = self.pending_exception_return.take() {
+            if self.pending_exception.get().is_none() {
+                self.exception_return(exc_return)?;
+            }
+        }
+
+        // Exceptions raised by the instruction are synchronous, so they are
+        // pended with enough priority to be taken before the next instruction
+        let mut return_address = self.cpu.read_instruction_pc();
+        if let Some(exception) = self.pending_exception.take() {
+            if exception.returns_to_current_instruction() {
+                return_address = self.cpu.read_pc().wrapping_sub(4);
+            }
+            let exception = self.escalate(exception)?;
+            self.memory.nvic.set_pending(exception.number(), true);
+        }
+
+        // If a higher priority exception is also pending it arrived late, and is
+        // taken first on the same frame. The other stays pending and tail-chains.
+        if let Some(number) = self.exception_to_take() {
+            self.exception_entry(number, return_address)?;
+        }
+        return Ok(());
+    }
+
+    fn exception_to_take(&self) -> Option<u32> {
+        // B1.5.4 p528, only a higher group priority preempts
+        let number = self.memory.nvic.pending_exception()?;
+        if self.memory.nvic.group_priority(number) < self.execution_priority() {
+            return Some(number);
+        }
+        return None;
+    }
+
+    fn execution_priority(&self) -> i32 {
+        // B1.5.4 p529
+        let highest_priority = self.memory.nvic.highest_active_priority();
+
+        let mut boosted_priority = 256;
+        if self.cpu.read_basepri() != 0 {
+            boosted_priority = self.memory.nvic.group_value(self.cpu.read_basepri());
+        }
+        if self.cpu.read_primask() {
+            boosted_priority = 0;
+        }
+        if self.cpu.read_faultmask() {
+            boosted_priority = -1;
+        }
+        return cmp::min(boosted_priority, highest_priority);
+    }
+
+    fn escalate(&self, exception: Exception) -> Result<Exception, EmulatorError> {
+        // B1.5.15 p545
+        let current = self.execution_priority();
+        let number = exception.number();
+        let escalated = match exception {
+            Exception::MemManage |
+            Exception::BusFault |
+            Exception::UsageFault if !self.memory.nvic.fault_enabled(number) => Exception::HardFault,
+            Exception::MemManage |
+            Exception::BusFault |
+            Exception::UsageFault |
+            Exception::SupervisorCall |
+            Exception::DebugMonitor if self.memory.nvic.group_priority(number) >= current => Exception::HardFault,
+            e => e,
+        };
+
+        if escalated == Exception::HardFault && current <= -1 {
+            return Err(EmulatorError::Lockup { number });
+        }
+        return Ok(escalated);
+    }
+
+    fn exception_entry(&mut self, number: u32, return_address: u32) -> Result<(), EmulatorError> {
+        // B1.5.6 p531
+        self.push_stack(number, return_address)?;
+        return self.exception_taken(number);
+    }
+
+    fn push_stack(&mut self, number: u32, return_address: u32) -> Result<(), EmulatorError> {
+        // B1.5.6 p532
+        // NOTE: FP state is never stacked, and CCR.STKALIGN is always set
+        let frame_size = 0x20;
+        let sp = self.read_sp();
+        let frame_ptr_align = bitset(sp, 2);
+        let frame_ptr = sp.wrapping_sub(frame_size) & !0b100;
+        self.write_sp(frame_ptr);
+
+        let xpsr = (self.cpu.read_xpsr() & !(1 << 9)) | u32::from(frame_ptr_align) << 9;
+        let frame = [
+            self.read_reg(0u32),
+            self.read_reg(1u32),
+            self.read_reg(2u32),
+            self.read_reg(3u32),
+            self.read_reg(12u32),
+            self.read_lr(),
+            return_address,
+            xpsr,
+        ];
+        for (i, &value) in frame.iter().enumerate() {
+            let address = frame_ptr.wrapping_add(4 * i as u32);
+            if self.memory.write_mem_a(address, 4, value).is_err() {
+                // The board would raise a derived fault here, but any handler
+                // would be entered with a corrupt frame it cannot return from.
+                return Err(EmulatorError::Lockup { number });
+            }
+        }
+
+        let exc_return = if self.cpu.current_mode == ExecMode::ModeHandler {
+            0xFFFF_FFF1
+        } else if !self.cpu.read_spsel() {
+            0xFFFF_FFF9
+        } else {
+            0xFFFF_FFFD
+        };
+        self.write_lr(exc_return);
+        return Ok(());
+    }
+
+    fn exception_taken(&mut self, number: u32) -> Result<(), EmulatorError> {
+        // B1.5.6 p533
+        let vector_address = self.memory.nvic.vector_table().wrapping_add(4 * number);
+        let vector = match self.memory.read_mem_a(vector_address, 4) {
+            Ok(v) => v,
+            Err(_) => return Err(EmulatorError::Lockup { number }),
+        };
+
+        self.branch_to(vector & !0b1);
+        self.cpu.select_stack(ExecMode::ModeHandler, false);
+        self.cpu.write_ipsr(number);
+        self.cpu.set_thumb_mode(bitset(vector, 0));
+        self.cpu.itstate.state = 0;
+        self.memory.nvic.set_pending(number, false);
+        self.memory.nvic.set_active(number, true);
+        self.memory.nvic.set_vect_active(number);
+        self.exclusive_monitors_clear();
+        return Ok(());
+    }
+
+    fn exception_return(&mut self, exc_return: u32) -> Result<(), EmulatorError> {
+        // B1.5.8 p539
+        let number = self.cpu.read_ipsr();
+        let nested_activation = self.memory.nvic.active_count();
+
+        if (exc_return & 0x0FFF_FFF0) != 0x0FFF_FFF0 || !self.memory.nvic.is_active(number) {
+            self.record_error(EmulatorError::InvalidExceptionReturn { exc_return });
+            self.raise_exception(Exception::UsageFault);
+            return Ok(());
+        }
+
+        // NOTE: CCR.NONBASETHRDENA is never set, so thread mode is only returned to from the last active exception
+        let (mode, spsel) = match exc_return & 0xF {
+            0b0001 if nested_activation != 1 => (ExecMode::ModeHandler, false),
+            0b1001 if nested_activation == 1 => (ExecMode::ModeThread, false),
+            0b1101 if nested_activation == 1 => (ExecMode::ModeThread, true),
+            _ => {
+                self.record_error(EmulatorError::InvalidExceptionReturn { exc_return });
+                self.raise_exception(Exception::UsageFault);
+                return Ok(());
+            }
+        };
+
+        self.deactivate(number);
+
+        // Tail-chaining: an exception that would preempt the context being returned
+        // to is taken straight away, and reuses the frame already on the stack
+        if let Some(next) = self.exception_to_take() {
+            self.write_lr(exc_return);
+            return self.exception_taken(next);
+        }
+
+        self.cpu.select_stack(mode, spsel);
+        self.pop_stack();
+        self.memory.nvic.set_vect_active(self.cpu.read_ipsr());
+
+        if (mode == ExecMode::ModeHandler) == (self.cpu.read_ipsr() == 0) {
+            self.record_error(EmulatorError::InvalidExceptionReturn { exc_return });
+            self.raise_exception(Exception::UsageFault);
+        }
+        self.exclusive_monitors_clear();
+        return Ok(());
+    }
+
+    fn deactivate(&mut self, number: u32) {
+        // B1.5.8 p540
+        self.memory.nvic.set_active(number, false);
+        if number != Exception::NonMaskableInterrupt.number() {
+            self.cpu.write_faultmask(false);
+        }
+    }
+
+    fn pop_stack(&mut self) {
+        // B1.5.8 p541
+        let frame_ptr = self.read_sp();
+        let mut frame = [0u32; 8];
+        for (i, value) in frame.iter_mut().enumerate() {
+            *value = self.read_mem_a(frame_ptr.wrapping_add(4 * i as u32), 4);
+        }
+
+        self.write_reg(0u32, frame[0]);
+        self.write_reg(1u32, frame[1]);
+        self.write_reg(2u32, frame[2]);
+        self.write_reg(3u32, frame[3]);
+        self.write_reg(12u32, frame[4]);
+        self.write_lr(frame[5]);
+        self.branch_to(frame[6] & !0b1);
+
+        let psr = frame[7];
+        let sp_mask = u32::from(bitset(psr, 9)) << 2;
+        self.write_sp(frame_ptr.wrapping_add(0x20) | sp_mask);
+        self.cpu.write_xpsr(psr);
+    }
+
     /**
      * Instruction handlers
      */
@@ -1191,8 +2390,8 @@ impl Board {
             let result = bits::bit_field_clear(self.read_reg(rd), msbit, lsbit);
             self.write_reg(rd, result);
         } else {
-            println!("UNPREDICTABLE BFC");
-            self.pending_default_handler.set(true);
+            self.unpredictable("BFC");
+            self.raise_exception(Exception::UsageFault);
         }
     }
 
@@ -1207,8 +2406,8 @@ impl Board {
             let result = bits::bit_field_insert(self.read_reg(rd), self.read_reg(rn), msbit, lsbit);
             self.write_reg(rd, result);
         } else {
-            println!("UNPREDICTABLE BFC");
-            self.pending_default_handler.set(true);
+            self.unpredictable("BFI");
+            self.raise_exception(Exception::UsageFault);
         }
     }
 
@@ -1254,9 +2453,176 @@ impl Board {
         }
     }
 
-    fn n_bkpt(&mut self, _data: u32) {
+    fn n_bkpt(&mut self, data: u32) {
         // A7.7.17
         // TODO: When return values supported, cause a DebugMonitor exception with the input id
+        if data & 0xFF == SEMIHOSTING_BKPT {
+            let result = self.semihosting_call(self.read_reg(0u32), self.read_reg(1u32));
+            self.write_reg(0u32, result);
+        } else {
+            self.bkpt_hit = Some(data as u8);
+        }
+    }
+
+    fn read_semihosting_bytes(&self, address: u32, length: u32) -> Option<Vec<u8>> {
+        // The length comes from the program, so it is checked before anything is allocated
+        if !self.memory.is_buffer(address, length, false) {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity(length as usize);
+        for i in 0..length {
+            bytes.push(self.memory.read_mem_u(address.wrapping_add(i), 1).ok()? as u8);
+        }
+        return Some(bytes);
+    }
+
+    fn read_semihosting_string(&self, address: u32) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.memory.read_mem_u(address.wrapping_add(bytes.len() as u32), 1).ok()? as u8;
+            if byte == 0 {
+                return Some(bytes);
+            }
+            bytes.push(byte);
+        }
+    }
+
+    fn read_semihosting_args(&self, address: u32, count: u32) -> Option<Vec<u32>> {
+        let mut args = Vec::with_capacity(count as usize);
+        for i in 0..count {
+            args.push(self.memory.read_mem_u(address.wrapping_add(4 * i), 4).ok()?);
+        }
+        return Some(args);
+    }
+
+    /**
+     * Runs semihosting operation `op`, recording what it did if it reached the
+     * host, or doing what was recorded if it is being replayed
+     */
+    fn semihosting_call(&mut self, op: u32, param: u32) -> u32 {
+        if !matches!(op, SYS_OPEN | SYS_CLOSE | SYS_WRITEC | SYS_WRITE0 | SYS_WRITE | SYS_READ) {
+            return self.run_semihosting_call(op, param);
+        }
+
+        if let Some(reply) = self.semihosting_replay.take() {
+            if op == SYS_READ {
+                if let Some(args) = self.read_semihosting_args(param, 3) {
+                    for (i, &byte) in reply.bytes.iter().enumerate() {
+                        let _ = self.memory.write_mem_u(args[1].wrapping_add(i as u32), 1, u32::from(byte));
+                    }
+                }
+            }
+            return reply.result;
+        }
+
+        let result = self.run_semihosting_call(op, param);
+        let mut bytes = Vec::new();
+        if op == SYS_READ {
+            // What was read, from the part of the buffer that was filled
+            if let Some(args) = self.read_semihosting_args(param, 3) {
+                if result <= args[2] {
+                    bytes = self.read_semihosting_bytes(args[1], args[2] - result).unwrap_or_default();
+                }
+            }
+        }
+        self.semihosting_reply = Some(SemihostingReply { result, bytes });
+        return result;
+    }
+
+    /**
+     * Runs semihosting operation `op`, with `param` being r1. Operations that take
+     * several arguments read them from the block of words `param` points to.
+     * Returns the value for r0, which is -1 on failure for most operations.
+     */
+    fn run_semihosting_call(&mut self, op: u32, param: u32) -> u32 {
+        let failed = 0xFFFF_FFFF;
+        return match op {
+            SYS_OPEN => {
+                let (name, mode) = match self.read_semihosting_args(param, 3) {
+                    Some(a) => (self.read_semihosting_bytes(a[0], a[2]), a[1]),
+                    None => return failed,
+                };
+                let name = match name {
+                    Some(n) => String::from_utf8_lossy(&n).into_owned(),
+                    None => return failed,
+                };
+                self.semihosting.open(&name, mode).unwrap_or(failed)
+            }
+            SYS_CLOSE => {
+                match self.read_semihosting_args(param, 1) {
+                    Some(a) if self.semihosting.close(a[0]) => 0,
+                    _ => failed,
+                }
+            }
+            SYS_WRITEC => {
+                if let Ok(c) = self.memory.read_mem_u(param, 1) {
+                    self.semihosting.write_console(&[c as u8]);
+                }
+                0
+            }
+            SYS_WRITE0 => {
+                if let Some(s) = self.read_semihosting_string(param) {
+                    self.semihosting.write_console(&s);
+                }
+                0
+            }
+            SYS_WRITE => {
+                // Returns the number of bytes that were not written
+                let args = match self.read_semihosting_args(param, 3) {
+                    Some(a) => a,
+                    None => return failed,
+                };
+                let written = match self.read_semihosting_bytes(args[1], args[2]) {
+                    Some(bytes) => self.semihosting.write(args[0], &bytes).unwrap_or(0),
+                    None => 0,
+                };
+                args[2] - written as u32
+            }
+            SYS_READ => {
+                // Returns the number of bytes of the buffer that were not filled
+                let args = match self.read_semihosting_args(param, 3) {
+                    Some(a) => a,
+                    None => return failed,
+                };
+                if !self.memory.is_buffer(args[1], args[2], true) {
+                    return failed;
+                }
+                let bytes = match self.semihosting.read(args[0], args[2] as usize) {
+                    Some(b) => b,
+                    None => return args[2],
+                };
+                for (i, &byte) in bytes.iter().enumerate() {
+                    if self.memory.write_mem_u(args[1].wrapping_add(i as u32), 1, u32::from(byte)).is_err() {
+                        return failed;
+                    }
+                }
+                args[2] - bytes.len() as u32
+            }
+            SYS_CLOCK => {
+                // Centiseconds of simulated time
+                (self.time / (PICOSECONDS / 100)) as u32
+            }
+            SYS_EXIT => {
+                // On AArch32 the reason is passed directly, and only a normal exit means success
+                self.exit_status = Some(if param == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 });
+                self.exit_reason = if param == ADP_STOPPED_APPLICATION_EXIT { None } else { Some(param) };
+                0
+            }
+            SYS_EXIT_EXTENDED => {
+                let (status, reason) = match self.read_semihosting_args(param, 2) {
+                    Some(a) if a[0] == ADP_STOPPED_APPLICATION_EXIT => (a[1], None),
+                    Some(a) => (1, Some(a[0])),
+                    None => (1, None),
+                };
+                self.exit_status = Some(status);
+                self.exit_reason = reason;
+                0
+            }
+            _ => {
+                self.logger.emit(Event::UnsupportedSemihosting { op });
+                failed
+            }
+        };
     }
 
     fn w_bl(&mut self, _data: u32, extra: u32) {
@@ -1272,7 +2638,7 @@ impl Board {
                     // be 0xFFFF_FFFF after a failure. The carry flag seems to be set the
                     // first time after a call to this, but not afterwards.
                     if !self.audio_handler.handle((self.read_reg(0u32) & 0xFFFF) as i16) {
-                        println!("Call to {} without initialising audio", name);
+                        self.logger.emit(Event::AudioNotInitialised { name: name.clone() });
                         self.write_reg(0u32, 1);
                         self.write_reg(2u32, 1);
                         self.cpu.set_zero_flag(false);
@@ -1304,7 +2670,7 @@ impl Board {
                     self.write_reg(3u32, 0xE2B9_A7A9u32);
                     self.write_reg(12u32, 0x6C63_FE2Eu32);
                 } else {
-                    println!("Skipping call to {}", name);
+                    self.logger.emit(Event::SkippedCall { name: name.clone() });
                 }
 
             }
@@ -1414,14 +2780,45 @@ impl Board {
         self.set_flags_nzcv(result, carry, overflow);
     }
 
-    fn n_cps(&mut self, _data: u32) {
+    fn n_cps(&mut self, data: u32) {
         // A7.7.29
         // B5.2.1
-        // TODO
+        let enable = !bitset(data, 2);
+        let affect_pri = bitset(data, 1);
+        let affect_fault = bitset(data, 0);
+
+        if !self.cpu.is_privileged() {
+            return;
+        }
+        if enable {
+            if affect_pri {
+                self.cpu.write_primask(false);
+            }
+            if affect_fault {
+                self.cpu.write_faultmask(false);
+            }
+        } else {
+            if affect_pri {
+                self.cpu.write_primask(true);
+            }
+            if affect_fault && self.execution_priority() > -1 {
+                self.cpu.write_faultmask(true);
+            }
+        }
     }
 
     // A7.7.30 is CPY, a deprecated alias for MOV
 
+    fn w_dmb(&mut self, _data: u32, _extra: u32) {
+        // A7.7.33
+        // memory accesses already complete in program order
+    }
+
+    fn w_dsb(&mut self, _data: u32, _extra: u32) {
+        // A7.7.34
+        // memory accesses already complete in program order
+    }
+
     fn w_eor_imm(&mut self, data: u32, extra: u32) {
         // A7.7.35
         let imm32 = data << 30 | extra;
@@ -1464,6 +2861,11 @@ impl Board {
         }
     }
 
+    fn w_isb(&mut self, _data: u32, _extra: u32) {
+        // A7.7.37
+        // there is no pipeline to flush
+    }
+
     fn n_it(&mut self, data: u32) {
         // A7.7.38
         self.cpu.itstate.state = data;
@@ -1549,8 +2951,8 @@ impl Board {
             if (address & 0b11) == 0 {
                 self.load_write_pc(data);
             } else {
-                println!("Unpredictable");
-                self.pending_default_handler.set(true);
+                self.unpredictable("unaligned load to PC");
+                self.raise_exception(Exception::UsageFault);
             }
         } else {
             self.write_reg(rt, data);
@@ -1566,8 +2968,8 @@ impl Board {
             if (address & 0b11) == 0 {
                 self.load_write_pc(value);
             } else {
-                println!("Unpredictable");
-                self.pending_default_handler.set(true);
+                self.unpredictable("unaligned load to PC");
+                self.raise_exception(Exception::UsageFault);
             }
         } else {
             self.write_reg(rt, value);
@@ -1583,8 +2985,8 @@ impl Board {
             if (address & 0b11) == 0 {
                 self.load_write_pc(data);
             } else {
-                println!("Unpredictable");
-                self.pending_default_handler.set(true);
+                self.unpredictable("unaligned load to PC");
+                self.raise_exception(Exception::UsageFault);
             }
         } else {
             self.write_reg(rt, data);
@@ -1615,8 +3017,8 @@ impl Board {
         let value = self.read_mem_u(address, 4);
         if rt == 15 {
             if address & 0b11 != 0 {
-                println!("UNPREDICTABLE: ldr.W");
-                self.pending_default_handler.set(true);
+                self.unpredictable("unaligned load to PC");
+                self.raise_exception(Exception::UsageFault);
             }
             self.load_write_pc(value);
         } else {
@@ -1896,6 +3298,83 @@ impl Board {
         self.write_reg(rd, modified);
     }
 
+    fn w_mrs(&mut self, data: u32, extra: u32) {
+        // A7.7.82
+        // B5.2.2
+        let rd = data;
+        let sysm = extra;
+
+        let mut value = 0;
+        match sysm >> 3 {
+            0b00000 => {
+                if bitset(sysm, 0) {
+                    value |= self.cpu.read_ipsr() & 0x1FF;
+                }
+                // EPSR always reads as zero
+                if !bitset(sysm, 2) {
+                    value |= self.cpu.read_apsr();
+                }
+            }
+            0b00001 if self.cpu.is_privileged() => {
+                match sysm & 0b111 {
+                    0b000 => value = self.cpu.read_msp(),
+                    0b001 => value = self.cpu.read_psp(),
+                    _ => {}
+                }
+            }
+            0b00010 => {
+                match sysm & 0b111 {
+                    0b000 => value = u32::from(self.cpu.read_primask()),
+                    0b001 | 0b010 => value = u32::from(self.cpu.read_basepri()),
+                    0b011 => value = u32::from(self.cpu.read_faultmask()),
+                    0b100 => value = self.cpu.read_control(),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        self.write_reg(rd, value);
+    }
+
+    fn w_msr(&mut self, data: u32, extra: u32) {
+        // A7.7.83
+        // B5.2.3
+        let rn = data;
+        let sysm = extra & 0xFF;
+        let mask = extra >> 8;
+        let value = self.read_reg(rn);
+
+        match sysm >> 3 {
+            0b00000 if !bitset(sysm, 2) => {
+                self.cpu.write_apsr(value, bitset(mask, 1), bitset(mask, 0));
+            }
+            0b00001 if self.cpu.is_privileged() => {
+                match sysm & 0b111 {
+                    0b000 => self.cpu.write_msp(value),
+                    0b001 => self.cpu.write_psp(value),
+                    _ => {}
+                }
+            }
+            0b00010 if self.cpu.is_privileged() => {
+                let basepri = self.cpu.read_basepri();
+                let new_basepri = (value & 0xFF) as u8;
+                match sysm & 0b111 {
+                    0b000 => self.cpu.write_primask(bitset(value, 0)),
+                    0b001 => self.cpu.write_basepri(new_basepri),
+                    0b010 if new_basepri != 0 && (new_basepri < basepri || basepri == 0) => {
+                        self.cpu.write_basepri(new_basepri);
+                    }
+                    0b011 if self.execution_priority() > -1 => {
+                        self.cpu.write_faultmask(bitset(value, 0));
+                    }
+                    0b100 => self.cpu.write_control(value),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
     fn n_mul(&mut self, data: u32) {
         let rdm = data & 0x7;
         let rn = data >> 3;
@@ -1963,6 +3442,11 @@ impl Board {
         // do nothing
     }
 
+    fn w_nop(&mut self, _data: u32, _extra: u32) {
+        // A7.7.88
+        // do nothing
+    }
+
     fn w_orn_imm(&mut self, data: u32, extra: u32) {
         // A7.7.89
         let imm32 = data << 30 | extra;
@@ -2637,7 +4121,7 @@ impl Board {
 
     fn n_svc(&mut self, _data: u32) {
         // A7.7.178
-        // TODO: CallSupervisor()
+        self.raise_exception(Exception::SupervisorCall);
     }
 
     fn n_sxtb(&mut self, data: u32) {
@@ -2722,16 +4206,18 @@ impl Board {
         self.set_flags_nzc(result, carry);
     }
 
-    fn n_udf(&mut self, _data: u32) {
+    fn n_udf(&mut self, data: u32) {
         // A7.7.194
-        println!("Undefined exception");
-        self.pending_default_handler.set(true);
+        let encoding = 0xDE00 | data;
+        self.record_error(EmulatorError::UndefinedInstruction { address: self.current_instruction_address(), encoding, wide: false });
+        self.raise_exception(Exception::UsageFault);
     }
 
-    fn w_udf(&mut self, _data: u32, _extra: u32) {
+    fn w_udf(&mut self, data: u32, _extra: u32) {
         // A7.7.194
-        println!("Undefined exception");
-        self.pending_default_handler.set(true);
+        let encoding = 0xF7F0_A000 | (data & 0xF000) << 4 | (data & 0xFFF);
+        self.record_error(EmulatorError::UndefinedInstruction { address: self.current_instruction_address(), encoding, wide: true });
+        self.raise_exception(Exception::UsageFault);
     }
 
     fn w_udiv(&mut self, data: u32, extra: u32) {
@@ -2742,8 +4228,7 @@ impl Board {
         let m = self.read_reg(rm);
         let result = if m == 0 {
             if /*IntegerZeroDivideTrappingEnabled*/ false {
-                println!("GenerateIntegerZeroDivide");
-                self.pending_default_handler.set(true);
+                self.raise_exception(Exception::UsageFault);
                 return;
             } else {
                 0
@@ -2830,6 +4315,26 @@ impl Board {
         let result = self.read_reg(rm) & 0xFFFF;
         self.write_reg(rd, result);
     }
+
+    fn n_wfe(&mut self, _data: u32) {
+        // A7.7.261
+        // wakes immediately, interrupts are taken between instructions anyway
+    }
+
+    fn w_wfe(&mut self, _data: u32, _extra: u32) {
+        // A7.7.261
+        // wakes immediately, interrupts are taken between instructions anyway
+    }
+
+    fn n_wfi(&mut self, _data: u32) {
+        // A7.7.262
+        // wakes immediately, interrupts are taken between instructions anyway
+    }
+
+    fn w_wfi(&mut self, _data: u32, _extra: u32) {
+        // A7.7.262
+        // wakes immediately, interrupts are taken between instructions anyway
+    }
 }
 
 impl fmt::Display for Board {
@@ -0,0 +1,495 @@
+use crate::EmulatorError;
+use crate::snapshot::{SnapshotWriter, SnapshotReader};
+use crate::utils::bits::bitset;
+
+use std::cmp;
+
+// Number of external interrupts on the STM32L476 (RM0351 Table 58)
+pub const NUM_IRQS: u32 = 82;
+
+// Exception number of IRQ 0; the vector table is indexed by exception number
+pub const IRQ_BASE: u32 = 16;
+
+// Words needed for one bit per external interrupt
+const IRQ_WORDS: usize = 3;
+
+// The STM32L4 implements the upper 4 bits of each priority field (__NVIC_PRIO_BITS)
+const PRIORITY_MASK: u8 = 0xF0;
+
+// CPUID of a Cortex-M4 r0p1
+const CPUID: u32 = 0x410F_C241;
+
+// CCR.STKALIGN is set on reset
+const CCR_RESET: u32 = 0x0000_0200;
+
+// (SHCSR bit, exception number) pairs of the system handler active bits
+const SHCSR_ACTIVE: [(u32, u32); 7] = [(0, 4), (1, 5), (3, 6), (7, 11), (8, 12), (10, 14), (11, 15)];
+
+// (SHCSR bit, exception number) pairs of the system handler pended bits
+const SHCSR_PENDED: [(u32, u32); 4] = [(12, 6), (13, 4), (14, 5), (15, 11)];
+
+// (SHCSR bit, exception number) pairs of the configurable fault enable bits
+const SHCSR_ENABLE: [(u32, u32); 3] = [(16, 4), (17, 5), (18, 6)];
+
+/**
+ * Exception state of the processor, and the System Control Space registers
+ * that expose it. This covers the NVIC proper (B3.4) and the parts of the
+ * System Control Block that configure exceptions (B3.2).
+ *
+ * System exceptions and external interrupts are addressed by exception number
+ * throughout, so IRQ n is exception number 16 + n.
+ */
+#[derive(Debug)]
+pub struct Nvic {
+    irq_enabled: [u32; IRQ_WORDS],
+    irq_pending: [u32; IRQ_WORDS],
+    irq_active: [u32; IRQ_WORDS],
+    irq_priority: [u8; NUM_IRQS as usize],
+
+    // Bit n is set when system exception n is pending / active
+    system_pending: u32,
+    system_active: u32,
+
+    // SHPR1-3, the priorities of exceptions 4 to 15
+    system_priority: [u8; 12],
+
+    // The fault enable bits of SHCSR
+    fault_enabled: u32,
+
+    vtor: u32,
+    prigroup: u32,
+    scr: u32,
+    ccr: u32,
+
+    // Mirrors IPSR for ICSR.VECTACTIVE
+    vect_active: u32,
+}
+
+impl Nvic {
+    pub fn new() -> Nvic {
+        return Nvic {
+            irq_enabled: [0; IRQ_WORDS],
+            irq_pending: [0; IRQ_WORDS],
+            irq_active: [0; IRQ_WORDS],
+            irq_priority: [0; NUM_IRQS as usize],
+            system_pending: 0,
+            system_active: 0,
+            system_priority: [0; 12],
+            fault_enabled: 0,
+            vtor: 0,
+            prigroup: 0,
+            scr: 0,
+            ccr: CCR_RESET,
+            vect_active: 0,
+        };
+    }
+
+    pub fn reset(&mut self) {
+        *self = Nvic::new();
+    }
+
+    pub fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_words(&self.irq_enabled);
+        snapshot.write_words(&self.irq_pending);
+        snapshot.write_words(&self.irq_active);
+        snapshot.write_bytes(&self.irq_priority);
+        snapshot.write_bytes(&self.system_priority);
+        snapshot.write_words(&[
+            self.system_pending, self.system_active, self.fault_enabled,
+            self.vtor, self.prigroup, self.scr, self.ccr, self.vect_active,
+        ]);
+    }
+
+    pub fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        snapshot.read_words_into(&mut self.irq_enabled)?;
+        snapshot.read_words_into(&mut self.irq_pending)?;
+        snapshot.read_words_into(&mut self.irq_active)?;
+        snapshot.read_bytes_into(&mut self.irq_priority)?;
+        snapshot.read_bytes_into(&mut self.system_priority)?;
+        let mut words = [0; 8];
+        snapshot.read_words_into(&mut words)?;
+        let [system_pending, system_active, fault_enabled, vtor, prigroup, scr, ccr, vect_active] = words;
+        self.system_pending = system_pending;
+        self.system_active = system_active;
+        self.fault_enabled = fault_enabled;
+        self.vtor = vtor;
+        self.prigroup = prigroup;
+        self.scr = scr;
+        self.ccr = ccr;
+        self.vect_active = vect_active;
+        return Ok(());
+    }
+
+    fn is_valid(&self, number: u32) -> bool {
+        return number > 0 && number < IRQ_BASE + NUM_IRQS;
+    }
+
+    fn irq_bit(number: u32) -> (usize, u32) {
+        let irq = number - IRQ_BASE;
+        return ((irq / 32) as usize, 1 << (irq % 32));
+    }
+
+    pub fn is_pending(&self, number: u32) -> bool {
+        if number < IRQ_BASE {
+            return bitset(self.system_pending, number);
+        }
+        if !self.is_valid(number) {
+            return false;
+        }
+        let (word, bit) = Nvic::irq_bit(number);
+        return self.irq_pending[word] & bit != 0;
+    }
+
+    pub fn set_pending(&mut self, number: u32, pending: bool) {
+        if !self.is_valid(number) {
+            return;
+        }
+        if number < IRQ_BASE {
+            if pending {
+                self.system_pending |= 1 << number;
+            } else {
+                self.system_pending &= !(1 << number);
+            }
+            return;
+        }
+        let (word, bit) = Nvic::irq_bit(number);
+        if pending {
+            self.irq_pending[word] |= bit;
+        } else {
+            self.irq_pending[word] &= !bit;
+        }
+    }
+
+    pub fn is_active(&self, number: u32) -> bool {
+        if number < IRQ_BASE {
+            return bitset(self.system_active, number);
+        }
+        if !self.is_valid(number) {
+            return false;
+        }
+        let (word, bit) = Nvic::irq_bit(number);
+        return self.irq_active[word] & bit != 0;
+    }
+
+    pub fn set_active(&mut self, number: u32, active: bool) {
+        if !self.is_valid(number) {
+            return;
+        }
+        if number < IRQ_BASE {
+            if active {
+                self.system_active |= 1 << number;
+            } else {
+                self.system_active &= !(1 << number);
+            }
+            return;
+        }
+        let (word, bit) = Nvic::irq_bit(number);
+        if active {
+            self.irq_active[word] |= bit;
+        } else {
+            self.irq_active[word] &= !bit;
+        }
+    }
+
+    pub fn active_count(&self) -> u32 {
+        return self.system_active.count_ones() + self.irq_active.iter().map(|w| w.count_ones()).sum::<u32>();
+    }
+
+    pub fn set_vect_active(&mut self, number: u32) {
+        self.vect_active = number;
+    }
+
+    /**
+     * An external interrupt is only taken when enabled. System exceptions
+     * are only ever pended when they can be taken.
+     */
+    pub fn is_enabled(&self, number: u32) -> bool {
+        if number < IRQ_BASE {
+            return true;
+        }
+        if !self.is_valid(number) {
+            return false;
+        }
+        let (word, bit) = Nvic::irq_bit(number);
+        return self.irq_enabled[word] & bit != 0;
+    }
+
+    pub fn fault_enabled(&self, number: u32) -> bool {
+        return bitset(self.fault_enabled, number);
+    }
+
+    pub fn vector_table(&self) -> u32 {
+        return self.vtor;
+    }
+
+    pub fn priority(&self, number: u32) -> i32 {
+        // B1.5.4 p528
+        return match number {
+            1 => -3, // Reset
+            2 => -2, // NMI
+            3 => -1, // HardFault
+            4..=15 => i32::from(self.system_priority[number as usize - 4]),
+            _ if self.is_valid(number) => i32::from(self.irq_priority[(number - IRQ_BASE) as usize]),
+            _ => 256,
+        };
+    }
+
+    /**
+     * Only the group priority of an exception decides preemption. The
+     * subpriority bits selected by AIRCR.PRIGROUP are masked off.
+     */
+    pub fn group_priority(&self, number: u32) -> i32 {
+        let priority = self.priority(number);
+        if !(0..=255).contains(&priority) {
+            return priority;
+        }
+        return self.group_value(priority as u8);
+    }
+
+    pub fn group_value(&self, priority: u8) -> i32 {
+        // B1.5.4 p527
+        let subgroup_mask = (2u32 << self.prigroup) - 1;
+        return (u32::from(priority) & !subgroup_mask) as i32;
+    }
+
+    /**
+     * Group priority of the highest priority active exception,
+     * or 256 if none are active
+     */
+    pub fn highest_active_priority(&self) -> i32 {
+        // B1.5.4 p529
+        let mut highest = 256;
+        let mut active = self.system_active;
+        while active != 0 {
+            let number = active.trailing_zeros();
+            highest = cmp::min(highest, self.group_priority(number));
+            active &= active - 1;
+        }
+        for (i, &word) in self.irq_active.iter().enumerate() {
+            let mut active = word;
+            while active != 0 {
+                let number = IRQ_BASE + 32 * i as u32 + active.trailing_zeros();
+                highest = cmp::min(highest, self.group_priority(number));
+                active &= active - 1;
+            }
+        }
+        return highest;
+    }
+
+    /**
+     * The pending and enabled exception that would be taken next. Ties in group
+     * priority go to the lower subpriority, then to the lower exception number.
+     */
+    pub fn pending_exception(&self) -> Option<u32> {
+        // B1.5.4 p528
+        let mut best: Option<(i32, i32, u32)> = None;
+        let mut consider = |number: u32| {
+            let key = (self.group_priority(number), self.priority(number), number);
+            match best {
+                Some(b) if b <= key => {}
+                _ => best = Some(key),
+            }
+        };
+
+        let mut pending = self.system_pending;
+        while pending != 0 {
+            consider(pending.trailing_zeros());
+            pending &= pending - 1;
+        }
+        for i in 0..IRQ_WORDS {
+            let mut pending = self.irq_pending[i] & self.irq_enabled[i];
+            while pending != 0 {
+                consider(IRQ_BASE + 32 * i as u32 + pending.trailing_zeros());
+                pending &= pending - 1;
+            }
+        }
+        return best.map(|(_, _, number)| number);
+    }
+
+    /**
+     * System Control Space registers. Offsets are from 0xE000_E000.
+     * Registers that are not modelled read as zero and ignore writes.
+     */
+    pub fn read(&self, offset: usize, size: usize) -> u32 {
+        let shift = 8 * (offset & 0b11) as u32;
+        let word = self.read_register(offset & !0b11);
+        return (word >> shift) & (0xFFFF_FFFF >> (32 - 8 * size));
+    }
+
+    pub fn write(&mut self, offset: usize, size: usize, value: u32) {
+        let shift = 8 * (offset & 0b11) as u32;
+        let mask = (0xFFFF_FFFF >> (32 - 8 * size)) << shift;
+        self.write_register(offset & !0b11, (value << shift) & mask, mask);
+    }
+
+    fn read_register(&self, offset: usize) -> u32 {
+        return match offset {
+            // B3.4.3 NVIC_ISERn / NVIC_ICERn
+            0x100..=0x1BF => self.irq_word(&self.irq_enabled, offset & 0x3F),
+            // B3.4.5 NVIC_ISPRn / NVIC_ICPRn
+            0x200..=0x2BF => self.irq_word(&self.irq_pending, offset & 0x3F),
+            // B3.4.7 NVIC_IABRn
+            0x300..=0x33F => self.irq_word(&self.irq_active, offset & 0x3F),
+            // B3.4.8 NVIC_IPRn
+            0x400..=0x5EF => {
+                let base = offset - 0x400;
+                let byte = |i: usize| u32::from(self.irq_priority.get(base + i).copied().unwrap_or(0));
+                byte(0) | byte(1) << 8 | byte(2) << 16 | byte(3) << 24
+            }
+            // B3.2.3 CPUID
+            0xD00 => CPUID,
+            // B3.2.4 ICSR
+            0xD04 => self.read_icsr(),
+            // B3.2.5 VTOR
+            0xD08 => self.vtor,
+            // B3.2.6 AIRCR, VECTKEYSTAT reads as 0xFA05
+            0xD0C => 0xFA05_0000 | self.prigroup << 8,
+            // B3.2.7 SCR
+            0xD10 => self.scr,
+            // B3.2.8 CCR
+            0xD14 => self.ccr,
+            // B3.2.10 SHPR1-3
+            0xD18..=0xD23 => {
+                let base = offset - 0xD18;
+                let byte = |i: usize| u32::from(self.system_priority[base + i]);
+                byte(0) | byte(1) << 8 | byte(2) << 16 | byte(3) << 24
+            }
+            // B3.2.13 SHCSR
+            0xD24 => self.read_shcsr(),
+            _ => 0,
+        };
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32, mask: u32) {
+        match offset {
+            0x100..=0x13F => self.set_irq_word(IrqBank::Enabled, offset & 0x3F, value, true),
+            0x180..=0x1BF => self.set_irq_word(IrqBank::Enabled, offset & 0x3F, value, false),
+            0x200..=0x23F => self.set_irq_word(IrqBank::Pending, offset & 0x3F, value, true),
+            0x280..=0x2BF => self.set_irq_word(IrqBank::Pending, offset & 0x3F, value, false),
+            0x400..=0x5EF => {
+                for i in 0..4 {
+                    if mask & (0xFF << (8 * i)) != 0 {
+                        if let Some(p) = self.irq_priority.get_mut(offset - 0x400 + i) {
+                            *p = (value >> (8 * i)) as u8 & PRIORITY_MASK;
+                        }
+                    }
+                }
+            }
+            0xD04 => self.write_icsr(value),
+            0xD08 => {
+                // TBLOFF is bits 29:7, but the table of 98 vectors needs 512 byte alignment
+                self.vtor = (self.vtor & !mask) | (value & 0x3FFF_FE00);
+            }
+            0xD0C => {
+                // Writes without the VECTKEY are ignored
+                if mask == 0xFFFF_FFFF && value >> 16 == 0x05FA {
+                    self.prigroup = (value >> 8) & 0b111;
+                }
+            }
+            0xD10 => self.scr = (self.scr & !mask) | (value & 0b1_0110),
+            0xD14 => self.ccr = (self.ccr & !mask) | (value & 0x31B),
+            0xD18..=0xD23 => {
+                for i in 0..4 {
+                    if mask & (0xFF << (8 * i)) != 0 {
+                        self.system_priority[offset - 0xD18 + i] = (value >> (8 * i)) as u8 & PRIORITY_MASK;
+                    }
+                }
+            }
+            0xD24 => self.write_shcsr(value, mask),
+            // B3.4.9 STIR
+            0xF00 => self.set_pending(IRQ_BASE + (value & 0x1FF), true),
+            _ => {}
+        }
+    }
+
+    fn irq_word(&self, bank: &[u32; IRQ_WORDS], offset: usize) -> u32 {
+        return bank.get(offset / 4).copied().unwrap_or(0);
+    }
+
+    fn set_irq_word(&mut self, bank: IrqBank, offset: usize, value: u32, set: bool) {
+        let bank = match bank {
+            IrqBank::Enabled => &mut self.irq_enabled,
+            IrqBank::Pending => &mut self.irq_pending,
+        };
+        let index = offset / 4;
+        if index >= IRQ_WORDS {
+            return;
+        }
+        // Bits past the last implemented interrupt are RAZ/WI
+        let implemented = NUM_IRQS - 32 * index as u32;
+        let value = if implemented >= 32 { value } else { value & ((1 << implemented) - 1) };
+        if set {
+            bank[index] |= value;
+        } else {
+            bank[index] &= !value;
+        }
+    }
+
+    fn read_icsr(&self) -> u32 {
+        // B3.2.4 p655
+        let isr_pending = self.irq_pending.iter().any(|&w| w != 0);
+        let vect_pending = self.pending_exception().unwrap_or(0);
+        let ret_to_base = self.vect_active != 0 && self.active_count() <= 1;
+        return u32::from(self.is_pending(2)) << 31
+            | u32::from(self.is_pending(14)) << 28
+            | u32::from(self.is_pending(15)) << 26
+            | u32::from(isr_pending) << 22
+            | (vect_pending & 0x1FF) << 12
+            | u32::from(ret_to_base) << 11
+            | self.vect_active & 0x1FF;
+    }
+
+    fn write_icsr(&mut self, value: u32) {
+        if bitset(value, 31) {
+            self.set_pending(2, true);
+        }
+        if bitset(value, 28) {
+            self.set_pending(14, true);
+        } else if bitset(value, 27) {
+            self.set_pending(14, false);
+        }
+        if bitset(value, 26) {
+            self.set_pending(15, true);
+        } else if bitset(value, 25) {
+            self.set_pending(15, false);
+        }
+    }
+
+    fn read_shcsr(&self) -> u32 {
+        // B3.2.13 p664
+        let mut result = 0;
+        for &(bit, number) in SHCSR_ACTIVE.iter() {
+            result |= u32::from(self.is_active(number)) << bit;
+        }
+        for &(bit, number) in SHCSR_PENDED.iter() {
+            result |= u32::from(self.is_pending(number)) << bit;
+        }
+        for &(bit, number) in SHCSR_ENABLE.iter() {
+            result |= u32::from(self.fault_enabled(number)) << bit;
+        }
+        return result;
+    }
+
+    fn write_shcsr(&mut self, value: u32, mask: u32) {
+        for &(bit, number) in SHCSR_ACTIVE.iter() {
+            if bitset(mask, bit) {
+                self.set_active(number, bitset(value, bit));
+            }
+        }
+        for &(bit, number) in SHCSR_PENDED.iter() {
+            if bitset(mask, bit) {
+                self.set_pending(number, bitset(value, bit));
+            }
+        }
+        for &(bit, number) in SHCSR_ENABLE.iter() {
+            if bitset(mask, bit) {
+                self.fault_enabled = (self.fault_enabled & !(1 << number)) | u32::from(bitset(value, bit)) << number;
+            }
+        }
+    }
+}
+
+enum IrqBank {
+    Enabled,
+    Pending,
+}
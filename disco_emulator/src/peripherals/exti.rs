@@ -0,0 +1,161 @@
+use crate::{EmulatorError, MemError};
+use crate::snapshot::{SnapshotWriter, SnapshotReader};
+use super::{Peripheral, Interrupts};
+
+// RM0351 2.2.2
+pub const EXTI_BASE: u32 = 0x4001_0400;
+pub const EXTI_SIZE: u32 = 0x400;
+
+// RM0351 14.5 register offsets, lines 0 - 31 then 32 - 40
+const IMR1: u32 = 0x00;
+const EMR1: u32 = 0x04;
+const RTSR1: u32 = 0x08;
+const FTSR1: u32 = 0x0C;
+const SWIER1: u32 = 0x10;
+const PR1: u32 = 0x14;
+const IMR2: u32 = 0x20;
+const EMR2: u32 = 0x24;
+const RTSR2: u32 = 0x28;
+const FTSR2: u32 = 0x2C;
+const SWIER2: u32 = 0x30;
+const PR2: u32 = 0x34;
+
+// Lines that can be configured for edge detection (RM0351 14.5.3 - 14.5.4,
+// 14.5.11 - 14.5.12), the others are direct lines from their peripheral
+const CONFIGURABLE1: u32 = 0x007E_FFFF;
+const CONFIGURABLE2: u32 = 0x0000_0078;
+
+// RM0351 13.3 vector table, the NVIC interrupt of each GPIO line
+const LINE_IRQS: [u32; 16] = [6, 7, 8, 9, 10, 23, 23, 23, 23, 23, 40, 40, 40, 40, 40, 40];
+
+/**
+ * Extended interrupts and events controller (RM0351 14). Lines 0 - 15 are
+ * driven by the GPIO pins selected in SYSCFG, and request their EXTI
+ * interrupt while they are both pending and unmasked.
+ */
+#[derive(Debug)]
+pub struct Exti {
+    imr: [u32; 2],
+    emr: [u32; 2],
+    rtsr: [u32; 2],
+    ftsr: [u32; 2],
+    pr: [u32; 2],
+}
+
+impl Exti {
+    pub fn new() -> Exti {
+        let mut exti = Exti {
+            imr: [0; 2],
+            emr: [0; 2],
+            rtsr: [0; 2],
+            ftsr: [0; 2],
+            pr: [0; 2],
+        };
+        exti.reset();
+        return exti;
+    }
+
+    /**
+     * Signals an edge on configurable `line`. It becomes pending if the edge
+     * is selected in RTSR or FTSR and the line is not masked.
+     */
+    pub fn edge(&mut self, line: u32, rising: bool) {
+        let (bank, bit) = ((line / 32) as usize, 1 << (line % 32));
+        let selected = if rising { self.rtsr[bank] } else { self.ftsr[bank] };
+        if selected & self.imr[bank] & bit != 0 {
+            self.pr[bank] |= bit;
+        }
+    }
+
+    pub fn is_pending(&self, line: u32) -> bool {
+        return (self.pr[(line / 32) as usize] >> (line % 32)) & 1 == 1;
+    }
+
+    fn read_register(&self, offset: u32) -> u32 {
+        return match offset {
+            IMR1 => self.imr[0],
+            EMR1 => self.emr[0],
+            RTSR1 => self.rtsr[0],
+            FTSR1 => self.ftsr[0],
+            PR1 => self.pr[0],
+            IMR2 => self.imr[1],
+            EMR2 => self.emr[1],
+            RTSR2 => self.rtsr[1],
+            FTSR2 => self.ftsr[1],
+            PR2 => self.pr[1],
+            _ => 0, // SWIER reads as 0 once the interrupt is pending
+        };
+    }
+
+    fn write_register(&mut self, offset: u32, value: u32, mask: u32) {
+        let merge = |old: u32| (old & !mask) | (value & mask);
+        match offset {
+            IMR1 => self.imr[0] = merge(self.imr[0]),
+            EMR1 => self.emr[0] = merge(self.emr[0]),
+            RTSR1 => self.rtsr[0] = merge(self.rtsr[0]) & CONFIGURABLE1,
+            FTSR1 => self.ftsr[0] = merge(self.ftsr[0]) & CONFIGURABLE1,
+            SWIER1 => self.pr[0] |= value & mask & self.imr[0] & CONFIGURABLE1,
+            PR1 => self.pr[0] &= !(value & mask), // rc_w1
+            IMR2 => self.imr[1] = merge(self.imr[1]) & 0xFF,
+            EMR2 => self.emr[1] = merge(self.emr[1]) & 0xFF,
+            RTSR2 => self.rtsr[1] = merge(self.rtsr[1]) & CONFIGURABLE2,
+            FTSR2 => self.ftsr[1] = merge(self.ftsr[1]) & CONFIGURABLE2,
+            SWIER2 => self.pr[1] |= value & mask & self.imr[1] & CONFIGURABLE2,
+            PR2 => self.pr[1] &= !(value & mask),
+            _ => {}
+        }
+    }
+}
+
+impl Peripheral for Exti {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, MemError> {
+        let shift = 8 * (offset & 0b11);
+        let word = self.read_register(offset & !0b11);
+        return Ok((word >> shift) & (0xFFFF_FFFF >> (32 - 8 * size)));
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), MemError> {
+        let shift = 8 * (offset & 0b11);
+        let mask = (0xFFFF_FFFF >> (32 - 8 * size)) << shift;
+        self.write_register(offset & !0b11, value << shift, mask);
+        return Ok(());
+    }
+
+    fn tick(&mut self, _cycles: u32, interrupts: &mut Interrupts) {
+        let requesting = self.pr[0] & self.imr[0];
+        for (line, &irq) in LINE_IRQS.iter().enumerate() {
+            if (requesting >> line) & 1 == 1 {
+                interrupts.request(irq);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        // RM0351 14.5.1 and 14.5.9, the direct lines are unmasked
+        self.imr = [0xFF82_0000, 0x0000_0087];
+        self.emr = [0; 2];
+        self.rtsr = [0; 2];
+        self.ftsr = [0; 2];
+        self.pr = [0; 2];
+    }
+
+    fn name(&self) -> String {
+        return String::from("EXTI");
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_words(&self.imr);
+        snapshot.write_words(&self.emr);
+        snapshot.write_words(&self.rtsr);
+        snapshot.write_words(&self.ftsr);
+        snapshot.write_words(&self.pr);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        snapshot.read_words_into(&mut self.imr)?;
+        snapshot.read_words_into(&mut self.emr)?;
+        snapshot.read_words_into(&mut self.rtsr)?;
+        snapshot.read_words_into(&mut self.ftsr)?;
+        return snapshot.read_words_into(&mut self.pr);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{EmulatorError, MemError};
+use crate::snapshot::{SnapshotWriter, SnapshotReader};
+use super::{Peripheral, Interrupts};
+
+use std::sync::mpsc::Sender;
+
+// RM0351 2.2.2, the GPIO ports are 0x400 apart on AHB2
+pub const GPIOA_BASE: u32 = 0x4800_0000;
+pub const GPIO_PORT_SIZE: u32 = 0x400;
+pub const GPIO_PORTS: [char; 8] = ['A', 'B', 'C', 'D', 'E', 'F', 'G', 'H'];
+
+/**
+ * A pin changing level, as seen through IDR. Both outputs
+ * driven by the program and inputs driven externally are reported.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PinChange {
+    pub port: char,
+    pub pin: u32,
+    pub high: bool,
+
+    // Clock cycles counted by the port when the change happened
+    pub cycle: u64,
+}
+
+/**
+ * One GPIO port of the STM32L476 (RM0351 8.5)
+ */
+#[derive(Debug)]
+pub struct Gpio {
+    port: char,
+    moder: u32,
+    otyper: u32,
+    ospeedr: u32,
+    pupdr: u32,
+    odr: u32,
+    lckr: u32,
+    afrl: u32,
+    afrh: u32,
+    ascr: u32,
+
+    // Levels of the pins driven from outside the chip
+    input: u32,
+    driven: u32,
+
+    // Pins that changed level since the last `take_edges`, for EXTI
+    rising: u32,
+    falling: u32,
+
+    cycles: u64,
+    observer: Option<Sender<PinChange>>,
+}
+
+impl Gpio {
+    pub fn new(port: char) -> Gpio {
+        let mut gpio = Gpio {
+            port,
+            moder: 0,
+            otyper: 0,
+            ospeedr: 0,
+            pupdr: 0,
+            odr: 0,
+            lckr: 0,
+            afrl: 0,
+            afrh: 0,
+            ascr: 0,
+            input: 0,
+            driven: 0,
+            rising: 0,
+            falling: 0,
+            cycles: 0,
+            observer: None,
+        };
+        gpio.reset_registers();
+        return gpio;
+    }
+
+    fn reset_registers(&mut self) {
+        // RM0351 8.5.1 - 8.5.4, ports A and B reset with the debug pins configured
+        let (moder, ospeedr, pupdr) = match self.port {
+            'A' => (0xABFF_FFFF, 0x0C00_0000, 0x6400_0000),
+            'B' => (0xFFFF_FEBF, 0x0000_0000, 0x0000_0100),
+            'H' => (0x0000_000F, 0x0000_0000, 0x0000_0000),
+            _ => (0xFFFF_FFFF, 0x0000_0000, 0x0000_0000),
+        };
+        self.moder = moder;
+        self.otyper = 0;
+        self.ospeedr = ospeedr;
+        self.pupdr = pupdr;
+        self.odr = 0;
+        self.lckr = 0;
+        self.afrl = 0;
+        self.afrh = 0;
+        self.ascr = 0;
+    }
+
+    pub fn port(&self) -> char {
+        return self.port;
+    }
+
+    pub fn set_observer(&mut self, observer: Sender<PinChange>) {
+        self.observer = Some(observer);
+    }
+
+    /**
+     * The level of every pin, as read from IDR
+     */
+    pub fn levels(&self) -> u32 {
+        let mut levels = 0;
+        for pin in 0..16 {
+            let high = match (self.moder >> (2 * pin)) & 0b11 {
+                0b01 => (self.odr >> pin) & 1 == 1,
+                0b11 => false, // analog, the input buffer is disabled
+                _ if (self.driven >> pin) & 1 == 1 => (self.input >> pin) & 1 == 1,
+                _ => (self.pupdr >> (2 * pin)) & 0b11 == 0b01,
+            };
+            levels |= u32::from(high) << pin;
+        }
+        return levels;
+    }
+
+    pub fn read_pin(&self, pin: u32) -> bool {
+        return (self.levels() >> pin) & 1 == 1;
+    }
+
+    /**
+     * Drives input `pin` high or low from outside the chip, like a button would
+     */
+    pub fn set_input(&mut self, pin: u32, high: bool) {
+        let before = self.levels();
+        self.driven |= 1 << pin;
+        self.input = (self.input & !(1 << pin)) | u32::from(high) << pin;
+        self.notify(before);
+    }
+
+    /**
+     * Stops driving input `pin`, leaving it to its pull-up or pull-down
+     */
+    pub fn release_input(&mut self, pin: u32) {
+        let before = self.levels();
+        self.driven &= !(1 << pin);
+        self.notify(before);
+    }
+
+    /**
+     * Returns the pins that had a rising and a falling edge since the last call
+     */
+    pub fn take_edges(&mut self) -> (u32, u32) {
+        let edges = (self.rising, self.falling);
+        self.rising = 0;
+        self.falling = 0;
+        return edges;
+    }
+
+    fn notify(&mut self, before: u32) {
+        let after = self.levels();
+        let changed = before ^ after;
+        self.rising |= changed & after;
+        self.falling |= changed & before;
+
+        let observer = match &self.observer {
+            Some(o) => o,
+            None => return,
+        };
+        for pin in 0..16 {
+            if (changed >> pin) & 1 == 1 {
+                let _ = observer.send(PinChange {
+                    port: self.port,
+                    pin,
+                    high: (after >> pin) & 1 == 1,
+                    cycle: self.cycles,
+                });
+            }
+        }
+    }
+
+    fn read_register(&self, offset: u32) -> u32 {
+        return match offset {
+            0x00 => self.moder,
+            0x04 => self.otyper,
+            0x08 => self.ospeedr,
+            0x0C => self.pupdr,
+            0x10 => self.levels(),
+            0x14 => self.odr,
+            0x1C => self.lckr,
+            0x20 => self.afrl,
+            0x24 => self.afrh,
+            0x2C => self.ascr,
+            _ => 0, // BSRR and BRR are write only
+        };
+    }
+
+    fn write_register(&mut self, offset: u32, value: u32, mask: u32) {
+        let merge = |old: u32| (old & !mask) | (value & mask);
+        match offset {
+            0x00 => self.moder = merge(self.moder),
+            0x04 => self.otyper = merge(self.otyper) & 0xFFFF,
+            0x08 => self.ospeedr = merge(self.ospeedr),
+            0x0C => self.pupdr = merge(self.pupdr),
+            0x14 => self.odr = merge(self.odr) & 0xFFFF,
+            0x18 => {
+                // RM0351 8.4.7, set takes priority when both bits are written
+                let value = value & mask;
+                self.odr = (self.odr & !(value >> 16)) | (value & 0xFFFF);
+            }
+            0x1C => self.lckr = merge(self.lckr) & 0x1_FFFF,
+            0x20 => self.afrl = merge(self.afrl),
+            0x24 => self.afrh = merge(self.afrh),
+            0x28 => self.odr &= !(value & mask & 0xFFFF),
+            0x2C => self.ascr = merge(self.ascr) & 0xFFFF,
+            _ => {}
+        }
+    }
+}
+
+impl Peripheral for Gpio {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, MemError> {
+        let shift = 8 * (offset & 0b11);
+        let word = self.read_register(offset & !0b11);
+        return Ok((word >> shift) & (0xFFFF_FFFF >> (32 - 8 * size)));
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), MemError> {
+        // RM0351 8.4, the registers can be accessed by bytes, half-words or words
+        let shift = 8 * (offset & 0b11);
+        let mask = (0xFFFF_FFFF >> (32 - 8 * size)) << shift;
+        let before = self.levels();
+        self.write_register(offset & !0b11, value << shift, mask);
+        self.notify(before);
+        return Ok(());
+    }
+
+    fn tick(&mut self, cycles: u32, _interrupts: &mut Interrupts) {
+        self.cycles += u64::from(cycles);
+    }
+
+    fn reset(&mut self) {
+        let before = self.levels();
+        self.reset_registers();
+        self.notify(before);
+        self.rising = 0;
+        self.falling = 0;
+    }
+
+    fn name(&self) -> String {
+        return format!("GPIO{}", self.port);
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_words(&[
+            self.moder, self.otyper, self.ospeedr, self.pupdr, self.odr,
+            self.lckr, self.afrl, self.afrh, self.ascr,
+            self.input, self.driven, self.rising, self.falling,
+        ]);
+        snapshot.write_u64(self.cycles);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        let mut words = [0; 13];
+        snapshot.read_words_into(&mut words)?;
+        let [moder, otyper, ospeedr, pupdr, odr, lckr, afrl, afrh, ascr, input, driven, rising, falling] = words;
+        self.moder = moder;
+        self.otyper = otyper;
+        self.ospeedr = ospeedr;
+        self.pupdr = pupdr;
+        self.odr = odr;
+        self.lckr = lckr;
+        self.afrl = afrl;
+        self.afrh = afrh;
+        self.ascr = ascr;
+        self.input = input;
+        self.driven = driven;
+        self.rising = rising;
+        self.falling = falling;
+        self.cycles = snapshot.read_u64()?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,390 @@
+pub mod exti;
+pub mod gpio;
+pub mod rcc;
+pub mod registers;
+pub mod syscfg;
+pub mod usart;
+
+use crate::{EmulatorError, MemError};
+use crate::nvic::{Nvic, IRQ_BASE, NUM_IRQS};
+use crate::snapshot::{SnapshotWriter, SnapshotReader};
+use exti::Exti;
+use gpio::Gpio;
+use rcc::{ClockGate, Rcc};
+use syscfg::Syscfg;
+
+use std::any::Any;
+use std::cell::RefCell;
+
+/**
+ * Lets a `dyn Peripheral` be downcast back to the type that was registered.
+ * Implemented for every type, so peripherals never need to implement it.
+ */
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        return self;
+    }
+}
+
+/**
+ * A memory mapped device model. Once registered on the memory bus, every
+ * access within its address range is forwarded to it, with the address
+ * given as an offset from the base of the range.
+ */
+pub trait Peripheral: AsAny + Send {
+    /**
+     * Reads `size` (1, 2 or 4) bytes. Reads may have side effects, like
+     * clearing a status flag, so they take `&mut self`.
+     */
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, MemError>;
+
+    /**
+     * Reads like `read`, but for a debugger, so without side effects. Peripherals
+     * with registers that change when read must override this.
+     */
+    fn peek(&mut self, offset: u32, size: usize) -> Result<u32, MemError> {
+        return self.read(offset, size);
+    }
+
+    /**
+     * Writes the lower `size` (1, 2 or 4) bytes of `value`
+     */
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), MemError>;
+
+    /**
+     * Called after every instruction with the number of clock cycles it took
+     */
+    fn tick(&mut self, _cycles: u32, _interrupts: &mut Interrupts) {}
+
+    /**
+     * Returns every register to its reset value
+     */
+    fn reset(&mut self);
+
+    /**
+     * Writes the registers and internal state to a board snapshot. Connections
+     * to the host, like observers and serial backends, are not included.
+     */
+    fn save(&self, _snapshot: &mut SnapshotWriter) {}
+
+    /**
+     * Reads back the state written by `save`
+     */
+    fn restore(&mut self, _snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        return Ok(());
+    }
+
+    /**
+     * A short name for the peripheral, like "GPIOA", used when listing them
+     */
+    fn name(&self) -> String {
+        return String::from("peripheral");
+    }
+}
+
+/**
+ * A registered peripheral, as listed by `Board::peripherals`
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeripheralInfo {
+    pub name: String,
+    pub base: u32,
+    pub size: u32,
+    pub clocked: bool,
+}
+
+/**
+ * The interrupt lines of the NVIC, as seen by peripherals
+ */
+pub struct Interrupts<'a> {
+    nvic: &'a mut Nvic,
+}
+
+impl Interrupts<'_> {
+    pub(crate) fn new(nvic: &mut Nvic) -> Interrupts<'_> {
+        return Interrupts {
+            nvic,
+        };
+    }
+
+    /**
+     * Asserts interrupt line `irq`. Lines are level sensitive, so a peripheral
+     * should keep asserting it on every tick while the interrupt condition holds.
+     * An interrupt whose handler is running is only pended again after it returns.
+     */
+    pub fn request(&mut self, irq: u32) {
+        let number = IRQ_BASE + irq;
+        if irq < NUM_IRQS && !self.nvic.is_active(number) {
+            self.nvic.set_pending(number, true);
+        }
+    }
+}
+
+struct MappedPeripheral {
+    base: u32,
+    size: u32,
+    clock: Option<ClockGate>,
+
+    // Memory reads take `&self`, but may still change peripheral state
+    peripheral: RefCell<Box<dyn Peripheral>>,
+}
+
+/**
+ * The peripherals registered on the bus, and the address ranges they cover
+ */
+pub struct PeripheralMap {
+    mapped: Vec<MappedPeripheral>,
+
+    // Index of the RCC, which decides if the others are clocked
+    rcc: Option<usize>,
+
+    // Indices of SYSCFG and EXTI, which route GPIO edges to interrupts
+    syscfg: Option<usize>,
+    exti: Option<usize>,
+}
+
+impl PeripheralMap {
+    pub fn new() -> PeripheralMap {
+        return PeripheralMap {
+            mapped: Vec::new(),
+            rcc: None,
+            syscfg: None,
+            exti: None,
+        };
+    }
+
+    fn is_mappable(base: u32, size: u32) -> bool {
+        let last = match base.checked_add(size - 1) {
+            Some(l) => l,
+            None => return false,
+        };
+        let in_range = |start: u32, end: u32| start <= base && last <= end;
+        let overlaps_scs = base <= 0xE000_EFFF && last >= 0xE000_E000;
+        return in_range(0x4000_0000, 0x5FFF_FFFF) || (in_range(0xE000_0000, 0xE00F_FFFF) && !overlaps_scs);
+    }
+
+    pub fn register(&mut self, base: u32, size: u32, clock: Option<ClockGate>, peripheral: Box<dyn Peripheral>) -> Result<(), EmulatorError> {
+        if size == 0 || !PeripheralMap::is_mappable(base, size) {
+            return Err(EmulatorError::PeripheralRange { base, size });
+        }
+
+        let last = base + (size - 1);
+        for m in self.mapped.iter() {
+            if base <= m.base + (m.size - 1) && m.base <= last {
+                return Err(EmulatorError::PeripheralOverlap { base, existing: m.base });
+            }
+        }
+
+        let peripheral_ref: &dyn Peripheral = peripheral.as_ref();
+        if peripheral_ref.as_any().is::<Rcc>() {
+            self.rcc = Some(self.mapped.len());
+        } else if peripheral_ref.as_any().is::<Syscfg>() {
+            self.syscfg = Some(self.mapped.len());
+        } else if peripheral_ref.as_any().is::<Exti>() {
+            self.exti = Some(self.mapped.len());
+        }
+
+        self.mapped.push(MappedPeripheral {
+            base,
+            size,
+            clock,
+            peripheral: RefCell::new(peripheral),
+        });
+        return Ok(());
+    }
+
+    /**
+     * Peripherals without a clock gate, or without an RCC to gate them, are always clocked
+     */
+    fn is_clocked(&self, index: usize) -> bool {
+        let (gate, rcc) = match (self.mapped[index].clock, self.rcc) {
+            (Some(g), Some(r)) => (g, r),
+            _ => return true,
+        };
+        let rcc_ref = self.mapped[rcc].peripheral.borrow();
+        let rcc_ref: &dyn Peripheral = rcc_ref.as_ref();
+        return match rcc_ref.as_any().downcast_ref::<Rcc>() {
+            Some(rcc) => rcc.is_clock_enabled(gate),
+            None => true,
+        };
+    }
+
+    /**
+     * The SYSCLK frequency in Hz, or the 4 MHz reset frequency without an RCC
+     */
+    pub fn system_clock_frequency(&self) -> u32 {
+        let rcc = match self.rcc {
+            Some(r) => self.mapped[r].peripheral.borrow(),
+            None => return 4_000_000,
+        };
+        let rcc: &dyn Peripheral = rcc.as_ref();
+        return match rcc.as_any().downcast_ref::<Rcc>() {
+            Some(rcc) => rcc.system_clock_frequency(),
+            None => 4_000_000,
+        };
+    }
+
+    /**
+     * Every registered peripheral, in address order
+     */
+    pub fn describe(&self) -> Vec<PeripheralInfo> {
+        let mut peripherals: Vec<PeripheralInfo> = self.mapped.iter().enumerate().map(|(i, m)| PeripheralInfo {
+            name: m.peripheral.borrow().name(),
+            base: m.base,
+            size: m.size,
+            clocked: self.is_clocked(i),
+        }).collect();
+        peripherals.sort_by_key(|p| p.base);
+        return peripherals;
+    }
+
+    /**
+     * Finds the peripheral covering `address`, returning its index and the offset into it
+     */
+    pub fn find(&self, address: u32) -> Option<(usize, u32)> {
+        for (i, m) in self.mapped.iter().enumerate() {
+            if address >= m.base && address - m.base < m.size {
+                return Some((i, address - m.base));
+            }
+        }
+        return None;
+    }
+
+    pub fn read(&self, index: usize, offset: u32, size: usize) -> Result<u32, MemError> {
+        if !self.is_clocked(index) {
+            return Ok(0);
+        }
+        return self.mapped[index].peripheral.borrow_mut().read(offset, size);
+    }
+
+    pub fn peek(&self, index: usize, offset: u32, size: usize) -> Result<u32, MemError> {
+        if !self.is_clocked(index) {
+            return Ok(0);
+        }
+        return self.mapped[index].peripheral.borrow_mut().peek(offset, size);
+    }
+
+    pub fn write(&mut self, index: usize, offset: u32, size: usize, value: u32) -> Result<(), MemError> {
+        if !self.is_clocked(index) {
+            return Ok(());
+        }
+        return self.mapped[index].peripheral.get_mut().write(offset, size, value);
+    }
+
+    fn downcast_mut<T: Peripheral + 'static>(&mut self, index: usize) -> Option<&mut T> {
+        let peripheral: &mut dyn Peripheral = self.mapped[index].peripheral.get_mut().as_mut();
+        return peripheral.as_any_mut().downcast_mut::<T>();
+    }
+
+    /**
+     * Passes the edges seen on GPIO pins since the last call to the EXTI
+     * lines they are selected for in SYSCFG (RM0351 14.4)
+     */
+    fn route_gpio_edges(&mut self) {
+        let (syscfg, exti) = match (self.syscfg, self.exti) {
+            (Some(s), Some(e)) => (s, e),
+            _ => return,
+        };
+
+        let mut line_ports = [0; 16];
+        if let Some(syscfg) = self.downcast_mut::<Syscfg>(syscfg) {
+            for (line, port) in line_ports.iter_mut().enumerate() {
+                *port = syscfg.exti_port(line as u32);
+            }
+        }
+
+        let mut edges = Vec::new();
+        for i in 0..self.mapped.len() {
+            if let Some(gpio) = self.downcast_mut::<Gpio>(i) {
+                let port = (gpio.port() as u8 - b'A') as usize;
+                let (rising, falling) = gpio.take_edges();
+                if rising | falling != 0 {
+                    edges.push((port, rising, falling));
+                }
+            }
+        }
+
+        let exti = match self.downcast_mut::<Exti>(exti) {
+            Some(e) => e,
+            None => return,
+        };
+        for (port, rising, falling) in edges {
+            for (line, &selected) in line_ports.iter().enumerate() {
+                if selected != port {
+                    continue;
+                }
+                if (rising >> line) & 1 == 1 {
+                    exti.edge(line as u32, true);
+                }
+                if (falling >> line) & 1 == 1 {
+                    exti.edge(line as u32, false);
+                }
+            }
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32, nvic: &mut Nvic) {
+        self.route_gpio_edges();
+        let mut interrupts = Interrupts::new(nvic);
+        for m in self.mapped.iter_mut() {
+            m.peripheral.get_mut().tick(cycles, &mut interrupts);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for m in self.mapped.iter_mut() {
+            m.peripheral.get_mut().reset();
+        }
+    }
+
+    pub fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u32(self.mapped.len() as u32);
+        for m in self.mapped.iter() {
+            // Nested, so a peripheral can never read past its own state
+            let mut state = SnapshotWriter::new();
+            m.peripheral.borrow().save(&mut state);
+            snapshot.write_u32(m.base);
+            snapshot.write_bytes(&state.into_bytes());
+        }
+    }
+
+    /**
+     * Restores the state saved by `save`, which must come from a board with
+     * the same peripherals registered in the same order
+     */
+    pub fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        let count = snapshot.read_u32()? as usize;
+        if count != self.mapped.len() {
+            return Err(EmulatorError::Snapshot(format!("Snapshot has {} peripherals, the board has {}", count, self.mapped.len())));
+        }
+        for m in self.mapped.iter_mut() {
+            let base = snapshot.read_u32()?;
+            if base != m.base {
+                return Err(EmulatorError::Snapshot(format!("Snapshot has a peripheral at 0x{:08X}, the board has one at 0x{:08X}", base, m.base)));
+            }
+            let mut state = SnapshotReader::new(snapshot.read_bytes()?);
+            m.peripheral.get_mut().restore(&mut state)?;
+            if !state.is_finished() {
+                return Err(EmulatorError::Snapshot(format!("Unexpected state for the peripheral at 0x{:08X}", base)));
+            }
+        }
+        return Ok(());
+    }
+
+    /**
+     * The peripheral registered at `base`, if it is a `T`
+     */
+    pub fn get_mut<T: Peripheral + 'static>(&mut self, base: u32) -> Option<&mut T> {
+        let index = self.mapped.iter().position(|m| m.base == base)?;
+        return self.downcast_mut::<T>(index);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{EmulatorError, MemError};
+use crate::snapshot::{SnapshotWriter, SnapshotReader};
+use super::Peripheral;
+
+// RM0351 2.2.2
+pub const RCC_BASE: u32 = 0x4002_1000;
+pub const RCC_SIZE: u32 = 0x400;
+
+// Offsets of the peripheral clock enable registers
+pub const AHB1ENR: u32 = 0x48;
+pub const AHB2ENR: u32 = 0x4C;
+pub const AHB3ENR: u32 = 0x50;
+pub const APB1ENR1: u32 = 0x58;
+pub const APB1ENR2: u32 = 0x5C;
+pub const APB2ENR: u32 = 0x60;
+
+// Offsets of the other registers with behaviour beyond storing their value
+const CR: u32 = 0x00;
+const CFGR: u32 = 0x08;
+const PLLCFGR: u32 = 0x0C;
+const CIFR: u32 = 0x1C;
+const CICR: u32 = 0x20;
+const BDCR: u32 = 0x90;
+const CSR: u32 = 0x94;
+
+// RM0351 6.4, (offset, reset value) of the registers that do not reset to 0
+const RESET_VALUES: [(u32, u32); 13] = [
+    (CR, 0x0000_0063),
+    (0x04, 0x4000_0000), // ICSCR
+    (PLLCFGR, 0x0000_1000),
+    (0x10, 0x0000_1000), // PLLSAI1CFGR
+    (0x14, 0x0000_1000), // PLLSAI2CFGR
+    (AHB1ENR, 0x0000_0100),
+    (0x68, 0x0001_1303), // AHB1SMENR
+    (0x6C, 0x0005_32FF), // AHB2SMENR
+    (0x70, 0x0000_0101), // AHB3SMENR
+    (0x78, 0xF2FE_CA3F), // APB1SMENR1
+    (0x7C, 0x0000_0025), // APB1SMENR2
+    (0x80, 0x0167_7C01), // APB2SMENR
+    (CSR, 0x0C00_0600),
+];
+
+// RM0351 6.4.1, MSI frequencies selected by MSIRANGE and MSISRANGE
+const MSI_FREQUENCIES: [u32; 12] = [
+    100_000, 200_000, 400_000, 800_000, 1_000_000, 2_000_000,
+    4_000_000, 8_000_000, 16_000_000, 24_000_000, 32_000_000, 48_000_000,
+];
+const HSI_FREQUENCY: u32 = 16_000_000;
+
+// The discovery board has no HSE crystal, so assume the HAL default
+const HSE_FREQUENCY: u32 = 8_000_000;
+
+// (on bit, ready bit) pairs in CR
+const CR_OSCILLATORS: [(u32, u32); 6] = [
+    (0, 1),   // MSI
+    (8, 10),  // HSI16
+    (16, 17), // HSE
+    (24, 25), // PLL
+    (26, 27), // PLLSAI1
+    (28, 29), // PLLSAI2
+];
+
+/**
+ * An enable bit in one of the RCC peripheral clock enable registers.
+ * While it is clear, the peripheral reads as zero and ignores writes.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClockGate {
+    pub register: u32,
+    pub bit: u32,
+}
+
+impl ClockGate {
+    pub fn new(register: u32, bit: u32) -> ClockGate {
+        return ClockGate {
+            register,
+            bit,
+        };
+    }
+}
+
+/**
+ * Reset and clock control (RM0351 6). Oscillators become ready as soon as they
+ * are switched on, and the system clock switch takes effect immediately.
+ */
+#[derive(Debug)]
+pub struct Rcc {
+    registers: [u32; (RCC_SIZE / 4) as usize],
+}
+
+impl Rcc {
+    pub fn new() -> Rcc {
+        let mut rcc = Rcc {
+            registers: [0; (RCC_SIZE / 4) as usize],
+        };
+        rcc.reset();
+        return rcc;
+    }
+
+    fn get(&self, offset: u32) -> u32 {
+        return self.registers[(offset / 4) as usize];
+    }
+
+    fn set(&mut self, offset: u32, value: u32) {
+        self.registers[(offset / 4) as usize] = value;
+    }
+
+    pub fn is_clock_enabled(&self, gate: ClockGate) -> bool {
+        return (self.get(gate.register) >> gate.bit) & 1 == 1;
+    }
+
+    /**
+     * The oscillator that SYSCLK is running from, as encoded in CFGR.SW
+     */
+    pub fn system_clock_source(&self) -> u32 {
+        return (self.get(CFGR) >> 2) & 0b11;
+    }
+
+    fn msi_frequency(&self) -> u32 {
+        // MSIRGSEL picks between the range in CR and the one used after standby in CSR
+        let cr = self.get(CR);
+        let range = if (cr >> 3) & 1 == 1 { (cr >> 4) & 0xF } else { (self.get(CSR) >> 8) & 0xF };
+        return match MSI_FREQUENCIES.get(range as usize) {
+            Some(&f) => f,
+            None => 0,
+        };
+    }
+
+    /**
+     * The SYSCLK frequency in Hz, from the oscillator selected by CFGR.SWS (RM0351 6.2)
+     */
+    pub fn system_clock_frequency(&self) -> u32 {
+        return match (self.get(CFGR) >> 2) & 0b11 {
+            0b00 => self.msi_frequency(),
+            0b01 => HSI_FREQUENCY,
+            0b10 => HSE_FREQUENCY,
+            _ => {
+                // RM0351 6.4.4, f(VCO) = f(PLL input) * PLLN / PLLM, SYSCLK = f(VCO) / PLLR
+                let pllcfgr = self.get(PLLCFGR);
+                let input = match pllcfgr & 0b11 {
+                    0b01 => self.msi_frequency(),
+                    0b10 => HSI_FREQUENCY,
+                    0b11 => HSE_FREQUENCY,
+                    _ => 0,
+                };
+                let m = ((pllcfgr >> 4) & 0b111) + 1;
+                let n = (pllcfgr >> 8) & 0x7F;
+                let r = 2 * (((pllcfgr >> 25) & 0b11) + 1);
+                (u64::from(input) * u64::from(n) / u64::from(m * r)) as u32
+            }
+        };
+    }
+
+    fn update_ready_bits(&mut self) {
+        let mut cr = self.get(CR);
+        for &(on, ready) in CR_OSCILLATORS.iter() {
+            cr = (cr & !(1 << ready)) | ((cr >> on) & 1) << ready;
+        }
+        self.set(CR, cr);
+
+        // LSE in BDCR and LSI in CSR
+        let bdcr = self.get(BDCR);
+        self.set(BDCR, (bdcr & !(1 << 1)) | (bdcr & 1) << 1);
+        let csr = self.get(CSR);
+        self.set(CSR, (csr & !(1 << 1)) | (csr & 1) << 1);
+
+        // CFGR.SWS follows CFGR.SW once the selected oscillator is ready
+        let cfgr = self.get(CFGR);
+        let ready_bit = match cfgr & 0b11 {
+            0b00 => 1,  // MSI
+            0b01 => 10, // HSI16
+            0b10 => 17, // HSE
+            _ => 25,    // PLL
+        };
+        if (cr >> ready_bit) & 1 == 1 {
+            self.set(CFGR, (cfgr & !(0b11 << 2)) | (cfgr & 0b11) << 2);
+        }
+    }
+}
+
+impl Peripheral for Rcc {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, MemError> {
+        let shift = 8 * (offset & 0b11);
+        let word = self.get(offset & !0b11);
+        return Ok((word >> shift) & (0xFFFF_FFFF >> (32 - 8 * size)));
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), MemError> {
+        let shift = 8 * (offset & 0b11);
+        let mask = (0xFFFF_FFFF >> (32 - 8 * size)) << shift;
+        let value = (value << shift) & mask;
+        let offset = offset & !0b11;
+
+        match offset {
+            CR => {
+                // The ready bits are read only
+                let read_only = 0x2A02_0402;
+                let cr = self.get(CR);
+                self.set(CR, (cr & (!mask | read_only)) | (value & !read_only));
+            }
+            CFGR => {
+                // SWS is read only
+                let cfgr = self.get(CFGR);
+                self.set(CFGR, (cfgr & (!mask | 0b1100)) | (value & !0b1100));
+            }
+            CIFR => {}
+            CICR => {
+                let cifr = self.get(CIFR);
+                self.set(CIFR, cifr & !value);
+            }
+            _ => {
+                let old = self.get(offset);
+                self.set(offset, (old & !mask) | value);
+            }
+        }
+        self.update_ready_bits();
+        return Ok(());
+    }
+
+    fn reset(&mut self) {
+        self.registers = [0; (RCC_SIZE / 4) as usize];
+        for &(offset, value) in RESET_VALUES.iter() {
+            self.set(offset, value);
+        }
+    }
+
+    fn name(&self) -> String {
+        return String::from("RCC");
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_words(&self.registers);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        return snapshot.read_words_into(&mut self.registers);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{EmulatorError, MemError};
+use crate::snapshot::{SnapshotWriter, SnapshotReader};
+use super::Peripheral;
+
+/**
+ * A peripheral whose registers just hold what is written to them. This is enough
+ * for configuration that the emulator has no use for, like flash wait states or
+ * the voltage regulator range, while still letting code read back its settings.
+ */
+#[derive(Debug)]
+pub struct RegisterBank {
+    name: &'static str,
+    registers: Box<[u32]>,
+
+    // (offset, reset value) of the registers that do not reset to 0
+    reset_values: &'static [(u32, u32)],
+
+    // Offsets of registers that ignore writes
+    read_only: &'static [u32],
+}
+
+impl RegisterBank {
+    pub fn new(name: &'static str, size: u32, reset_values: &'static [(u32, u32)], read_only: &'static [u32]) -> RegisterBank {
+        let mut bank = RegisterBank {
+            name,
+            registers: vec![0; (size / 4) as usize].into_boxed_slice(),
+            reset_values,
+            read_only,
+        };
+        bank.reset();
+        return bank;
+    }
+}
+
+impl Peripheral for RegisterBank {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, MemError> {
+        let shift = 8 * (offset & 0b11);
+        let word = self.registers[(offset / 4) as usize];
+        return Ok((word >> shift) & (0xFFFF_FFFF >> (32 - 8 * size)));
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), MemError> {
+        if self.read_only.contains(&(offset & !0b11)) {
+            return Ok(());
+        }
+        let shift = 8 * (offset & 0b11);
+        let mask = (0xFFFF_FFFF >> (32 - 8 * size)) << shift;
+        let register = &mut self.registers[(offset / 4) as usize];
+        *register = (*register & !mask) | ((value << shift) & mask);
+        return Ok(());
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_words(&self.registers);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        return snapshot.read_words_into(&mut self.registers);
+    }
+
+    fn reset(&mut self) {
+        for register in self.registers.iter_mut() {
+            *register = 0;
+        }
+        for &(offset, value) in self.reset_values.iter() {
+            self.registers[(offset / 4) as usize] = value;
+        }
+    }
+
+    fn name(&self) -> String {
+        return String::from(self.name);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::{EmulatorError, MemError};
+use crate::snapshot::{SnapshotWriter, SnapshotReader};
+use super::Peripheral;
+
+// RM0351 2.2.2
+pub const SYSCFG_BASE: u32 = 0x4001_0000;
+pub const SYSCFG_SIZE: u32 = 0x400;
+
+// Offset of EXTICR1, EXTICR2 - 4 follow it
+const EXTICR1: u32 = 0x08;
+
+// RM0351 9.2, (offset, reset value) of the registers that do not reset to 0
+const RESET_VALUES: [(u32, u32); 1] = [
+    (0x04, 0x7C00_0001), // CFGR1
+];
+
+/**
+ * System configuration controller (RM0351 9). Only the EXTI line to GPIO
+ * port selection has an effect, the other registers just hold their value.
+ */
+#[derive(Debug)]
+pub struct Syscfg {
+    registers: [u32; 10],
+}
+
+impl Syscfg {
+    pub fn new() -> Syscfg {
+        let mut syscfg = Syscfg {
+            registers: [0; 10],
+        };
+        syscfg.reset();
+        return syscfg;
+    }
+
+    /**
+     * The GPIO port (0 for A to 7 for H) selected as the source of EXTI `line`, for lines 0 - 15
+     */
+    pub fn exti_port(&self, line: u32) -> usize {
+        // RM0351 9.2.3 - 9.2.6, four bits per line
+        let exticr = self.registers[((EXTICR1 / 4) + line / 4) as usize];
+        return ((exticr >> (4 * (line % 4))) & 0b111) as usize;
+    }
+}
+
+impl Peripheral for Syscfg {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, MemError> {
+        let shift = 8 * (offset & 0b11);
+        let word = match self.registers.get((offset / 4) as usize) {
+            Some(&w) => w,
+            None => 0,
+        };
+        return Ok((word >> shift) & (0xFFFF_FFFF >> (32 - 8 * size)));
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), MemError> {
+        let shift = 8 * (offset & 0b11);
+        let mask = (0xFFFF_FFFF >> (32 - 8 * size)) << shift;
+        if let Some(register) = self.registers.get_mut((offset / 4) as usize) {
+            *register = (*register & !mask) | ((value << shift) & mask);
+        }
+        return Ok(());
+    }
+
+    fn reset(&mut self) {
+        self.registers = [0; 10];
+        for &(offset, value) in RESET_VALUES.iter() {
+            self.registers[(offset / 4) as usize] = value;
+        }
+    }
+
+    fn name(&self) -> String {
+        return String::from("SYSCFG");
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_words(&self.registers);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        return snapshot.read_words_into(&mut self.registers);
+    }
+}
@@ -0,0 +1,364 @@
+use crate::{EmulatorError, MemError};
+use crate::snapshot::{SnapshotWriter, SnapshotReader};
+use super::{Peripheral, Interrupts};
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+// RM0351 2.2.2, USART2 is the ST-LINK virtual COM port on the discovery board
+pub const USART2_BASE: u32 = 0x4000_4400;
+pub const USART_SIZE: u32 = 0x400;
+pub const USART2_IRQ: u32 = 38;
+
+// RM0351 40.8 register offsets
+const CR1: u32 = 0x00;
+const CR2: u32 = 0x04;
+const CR3: u32 = 0x08;
+const BRR: u32 = 0x0C;
+const GTPR: u32 = 0x10;
+const RTOR: u32 = 0x14;
+const RQR: u32 = 0x18;
+const ISR: u32 = 0x1C;
+const ICR: u32 = 0x20;
+const RDR: u32 = 0x24;
+const TDR: u32 = 0x28;
+
+// CR1 bits
+const UE: u32 = 1 << 0;
+const RE: u32 = 1 << 2;
+const TE: u32 = 1 << 3;
+const RXNEIE: u32 = 1 << 5;
+const TCIE: u32 = 1 << 6;
+const TXEIE: u32 = 1 << 7;
+
+// ISR bits
+const RXNE: u32 = 1 << 5;
+const TC: u32 = 1 << 6;
+const TXE: u32 = 1 << 7;
+const TEACK: u32 = 1 << 21;
+const REACK: u32 = 1 << 22;
+
+// RQR bits
+const RXFRQ: u32 = 1 << 3;
+
+/**
+ * Where the bytes of a serial port go to and come from on the host
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum SerialBackend {
+    // Transmit to stdout, receive from stdin
+    Stdio,
+
+    // Transmit to a file, nothing is received
+    File(PathBuf),
+
+    // Listen on a local TCP port, and transmit to and receive from the connected client
+    Tcp(u16),
+}
+
+impl SerialBackend {
+    /**
+     * Parses `stdio`, `tcp:<port>` or `file:<path>`
+     */
+    pub fn parse(text: &str) -> Result<SerialBackend, String> {
+        if text == "stdio" {
+            return Ok(SerialBackend::Stdio);
+        }
+        if let Some(port) = text.strip_prefix("tcp:") {
+            return match port.parse::<u16>() {
+                Ok(p) => Ok(SerialBackend::Tcp(p)),
+                Err(e) => Err(format!("Invalid serial TCP port \"{}\": {}", port, e)),
+            };
+        }
+        if let Some(path) = text.strip_prefix("file:") {
+            return Ok(SerialBackend::File(PathBuf::from(path)));
+        }
+        return Err(format!("Unknown serial backend \"{}\", expected stdio, tcp:<port> or file:<path>", text));
+    }
+}
+
+/**
+ * Sends everything written to the TCP client, once one has connected
+ */
+struct TcpOutput {
+    stream: Arc<Mutex<Option<TcpStream>>>,
+}
+
+impl Write for TcpOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(stream) = self.stream.lock().unwrap().as_mut() {
+            // A client disconnecting should not stop the program
+            let _ = stream.write_all(buf);
+        }
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(stream) = self.stream.lock().unwrap().as_mut() {
+            let _ = stream.flush();
+        }
+        return Ok(());
+    }
+}
+
+fn spawn_reader<R: Read + Send + 'static>(mut reader: R, input: Sender<u8>) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        loop {
+            let count = match reader.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(c) => c,
+            };
+            for &byte in buf[..count].iter() {
+                if input.send(byte).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/**
+ * A USART of the STM32L476 (RM0351 40), in asynchronous mode. Bytes are sent as soon
+ * as they are written to TDR, so TXE and TC stay set, and received bytes are
+ * moved into RDR one at a time as the program reads them. The baud rate in
+ * BRR is stored but does not affect timing.
+ */
+pub struct Usart {
+    irq: u32,
+    cr1: u32,
+    cr2: u32,
+    cr3: u32,
+    brr: u32,
+    gtpr: u32,
+    rtor: u32,
+    isr: u32,
+    rdr: u32,
+
+    output: Option<Box<dyn Write + Send>>,
+    input: Option<Receiver<u8>>,
+}
+
+impl Usart {
+    pub fn new(irq: u32) -> Usart {
+        let mut usart = Usart {
+            irq,
+            cr1: 0,
+            cr2: 0,
+            cr3: 0,
+            brr: 0,
+            gtpr: 0,
+            rtor: 0,
+            isr: 0,
+            rdr: 0,
+            output: None,
+            input: None,
+        };
+        usart.reset();
+        return usart;
+    }
+
+    /**
+     * Sends every transmitted byte to `output`
+     */
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = Some(output);
+    }
+
+    /**
+     * Receives the bytes sent through `input`
+     */
+    pub fn set_input(&mut self, input: Receiver<u8>) {
+        self.input = Some(input);
+    }
+
+    pub fn connect(&mut self, backend: &SerialBackend) -> Result<(), String> {
+        let (tx, rx) = channel();
+        match backend {
+            SerialBackend::Stdio => {
+                self.set_output(Box::new(io::stdout()));
+                spawn_reader(io::stdin(), tx);
+            }
+            SerialBackend::File(path) => {
+                let file = match File::create(path) {
+                    Ok(f) => f,
+                    Err(e) => return Err(format!("Failed to create serial output file \"{:?}\": {}", path, e)),
+                };
+                self.set_output(Box::new(file));
+            }
+            SerialBackend::Tcp(port) => {
+                let listener = match TcpListener::bind(("127.0.0.1", *port)) {
+                    Ok(l) => l,
+                    Err(e) => return Err(format!("Failed to listen for serial connections on port {}: {}", port, e)),
+                };
+                let stream = Arc::new(Mutex::new(None));
+                let client = Arc::clone(&stream);
+                thread::spawn(move || {
+                    // Clients can reconnect, each replacing the last
+                    for incoming in listener.incoming() {
+                        let incoming = match incoming {
+                            Ok(s) => s,
+                            Err(_) => continue,
+                        };
+                        if let Ok(reader) = incoming.try_clone() {
+                            spawn_reader(reader, tx.clone());
+                        }
+                        *client.lock().unwrap() = Some(incoming);
+                    }
+                });
+                self.set_output(Box::new(TcpOutput { stream }));
+            }
+        }
+        self.set_input(rx);
+        return Ok(());
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if let Some(output) = self.output.as_mut() {
+            let _ = output.write_all(&[byte]);
+            let _ = output.flush();
+        }
+    }
+
+    fn read_register(&mut self, offset: u32) -> u32 {
+        if offset == RDR {
+            // RM0351 40.8.10, reading RDR clears RXNE
+            self.isr &= !RXNE;
+        }
+        return self.register(offset);
+    }
+
+    fn register(&self, offset: u32) -> u32 {
+        return match offset {
+            CR1 => self.cr1,
+            CR2 => self.cr2,
+            CR3 => self.cr3,
+            BRR => self.brr,
+            GTPR => self.gtpr,
+            RTOR => self.rtor,
+            ISR => self.isr,
+            RDR => self.rdr,
+            _ => 0, // RQR, ICR and TDR read as 0
+        };
+    }
+
+    fn write_register(&mut self, offset: u32, value: u32, mask: u32) {
+        let merge = |old: u32| (old & !mask) | (value & mask);
+        match offset {
+            CR1 => {
+                self.cr1 = merge(self.cr1);
+                let enabled = self.cr1 & UE != 0;
+                self.isr &= !(TEACK | REACK);
+                if enabled && self.cr1 & TE != 0 {
+                    self.isr |= TEACK;
+                }
+                if enabled && self.cr1 & RE != 0 {
+                    self.isr |= REACK;
+                }
+            }
+            CR2 => self.cr2 = merge(self.cr2),
+            CR3 => self.cr3 = merge(self.cr3),
+            BRR => self.brr = merge(self.brr) & 0xFFFF,
+            GTPR => self.gtpr = merge(self.gtpr) & 0xFFFF,
+            RTOR => self.rtor = merge(self.rtor),
+            RQR => {
+                if value & mask & RXFRQ != 0 {
+                    self.isr &= !RXNE;
+                }
+            }
+            // Clearing TC has no effect, as transmission is instant
+            ICR => {}
+            TDR => {
+                if self.cr1 & (UE | TE) == UE | TE {
+                    self.transmit((value & mask) as u8);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Peripheral for Usart {
+    fn read(&mut self, offset: u32, size: usize) -> Result<u32, MemError> {
+        let shift = 8 * (offset & 0b11);
+        let word = self.read_register(offset & !0b11);
+        return Ok((word >> shift) & (0xFFFF_FFFF >> (32 - 8 * size)));
+    }
+
+    fn peek(&mut self, offset: u32, size: usize) -> Result<u32, MemError> {
+        let shift = 8 * (offset & 0b11);
+        let word = self.register(offset & !0b11);
+        return Ok((word >> shift) & (0xFFFF_FFFF >> (32 - 8 * size)));
+    }
+
+    fn write(&mut self, offset: u32, size: usize, value: u32) -> Result<(), MemError> {
+        let shift = 8 * (offset & 0b11);
+        let mask = (0xFFFF_FFFF >> (32 - 8 * size)) << shift;
+        self.write_register(offset & !0b11, value << shift, mask);
+        return Ok(());
+    }
+
+    fn tick(&mut self, _cycles: u32, interrupts: &mut Interrupts) {
+        let receiving = self.cr1 & (UE | RE) == UE | RE;
+        if receiving && self.isr & RXNE == 0 {
+            // Bytes wait on the host until the last one is read, so there are no overruns
+            if let Some(byte) = self.input.as_ref().and_then(|i| i.try_recv().ok()) {
+                self.rdr = u32::from(byte);
+                self.isr |= RXNE;
+            }
+        }
+
+        let requesting = (self.cr1 & RXNEIE != 0 && self.isr & RXNE != 0)
+            || (self.cr1 & TCIE != 0 && self.isr & TC != 0)
+            || (self.cr1 & TXEIE != 0 && self.isr & TXE != 0);
+        if requesting && self.cr1 & UE != 0 {
+            interrupts.request(self.irq);
+        }
+    }
+
+    fn reset(&mut self) {
+        // RM0351 40.8.8, the transmitter starts empty and idle
+        self.cr1 = 0;
+        self.cr2 = 0;
+        self.cr3 = 0;
+        self.brr = 0;
+        self.gtpr = 0;
+        self.rtor = 0;
+        self.isr = TXE | TC;
+        self.rdr = 0;
+    }
+
+    fn name(&self) -> String {
+        // Only USART2 is registered, the others are told apart by interrupt (RM0351 table 57)
+        return String::from(match self.irq {
+            37 => "USART1",
+            USART2_IRQ => "USART2",
+            39 => "USART3",
+            _ => "USART",
+        });
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_words(&[self.cr1, self.cr2, self.cr3, self.brr, self.gtpr, self.rtor, self.isr, self.rdr]);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        let mut words = [0; 8];
+        snapshot.read_words_into(&mut words)?;
+        let [cr1, cr2, cr3, brr, gtpr, rtor, isr, rdr] = words;
+        self.cr1 = cr1;
+        self.cr2 = cr2;
+        self.cr3 = cr3;
+        self.brr = brr;
+        self.gtpr = gtpr;
+        self.rtor = rtor;
+        self.isr = isr;
+        self.rdr = rdr;
+        return Ok(());
+    }
+}
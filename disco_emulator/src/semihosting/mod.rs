@@ -0,0 +1,195 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+
+// The BKPT immediate that requests a semihosting operation on M-profile
+pub const SEMIHOSTING_BKPT: u32 = 0xAB;
+
+// Semihosting for AArch32 and AArch64 2.0, the operation numbers passed in r0
+pub const SYS_OPEN: u32 = 0x01;
+pub const SYS_CLOSE: u32 = 0x02;
+pub const SYS_WRITEC: u32 = 0x03;
+pub const SYS_WRITE0: u32 = 0x04;
+pub const SYS_WRITE: u32 = 0x05;
+pub const SYS_READ: u32 = 0x06;
+pub const SYS_CLOCK: u32 = 0x10;
+pub const SYS_EXIT: u32 = 0x18;
+pub const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+// The reasons SYS_EXIT and SYS_EXIT_EXTENDED give for stopping. Only
+// ADP_Stopped_ApplicationExit is a normal exit.
+pub const ADP_STOPPED_BRANCH_THROUGH_ZERO: u32 = 0x2_0000;
+pub const ADP_STOPPED_UNDEFINED_INSTR: u32 = 0x2_0001;
+pub const ADP_STOPPED_PREFETCH_ABORT: u32 = 0x2_0003;
+pub const ADP_STOPPED_DATA_ABORT: u32 = 0x2_0004;
+pub const ADP_STOPPED_ADDRESS_EXCEPTION: u32 = 0x2_0005;
+pub const ADP_STOPPED_BREAK_POINT: u32 = 0x2_0020;
+pub const ADP_STOPPED_WATCH_POINT: u32 = 0x2_0021;
+pub const ADP_STOPPED_STEP_COMPLETE: u32 = 0x2_0022;
+pub const ADP_STOPPED_USER_INTERRUPTION: u32 = 0x2_0025;
+pub const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x2_0026;
+pub const ADP_STOPPED_STACK_OVERFLOW: u32 = 0x2_0027;
+pub const ADP_STOPPED_DIVISION_BY_ZERO: u32 = 0x2_0028;
+
+/**
+ * What a semihosting call that reached the host did to the program
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SemihostingReply {
+    // The value returned in r0
+    pub result: u32,
+
+    // What SYS_READ filled the buffer with
+    pub bytes: Vec<u8>,
+}
+
+enum HostFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/**
+ * The host side of semihosting: the files the program has opened, and where
+ * console output goes. Handles index `files`, starting from 1 as 0 is not
+ * a valid handle.
+ */
+pub struct Semihosting {
+    files: Vec<Option<HostFile>>,
+    console: Box<dyn Write + Send>,
+    input: Box<dyn Read + Send>,
+}
+
+impl fmt::Debug for Semihosting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "<semihosting>");
+    }
+}
+
+impl Semihosting {
+    pub fn new() -> Semihosting {
+        return Semihosting {
+            files: Vec::new(),
+            console: Box::new(io::stdout()),
+            input: Box::new(io::stdin()),
+        };
+    }
+
+    /**
+     * Closes every file the program opened. The console stays where it was sent.
+     */
+    pub fn close_files(&mut self) {
+        self.files.clear();
+    }
+
+    /**
+     * Sends console output (SYS_WRITEC, SYS_WRITE0 and writes to `:tt`) to `console`
+     */
+    pub fn set_console(&mut self, console: Box<dyn Write + Send>) {
+        self.console = console;
+    }
+
+    /**
+     * Reads console input (reads from `:tt`) from `input`
+     */
+    pub fn set_input(&mut self, input: Box<dyn Read + Send>) {
+        self.input = input;
+    }
+
+    pub fn write_console(&mut self, bytes: &[u8]) {
+        let _ = self.console.write_all(bytes);
+        let _ = self.console.flush();
+    }
+
+    /**
+     * SYS_OPEN, where `mode` is the index of the equivalent fopen mode string in
+     * "r", "rb", "r+", "r+b", "w", "wb", "w+", "w+b", "a", "ab", "a+", "a+b".
+     * The special file `:tt` is the console.
+     */
+    pub fn open(&mut self, name: &str, mode: u32) -> Option<u32> {
+        let file = if name == ":tt" {
+            match mode {
+                0..=3 => HostFile::Stdin,
+                4..=7 => HostFile::Stdout,
+                _ => HostFile::Stderr,
+            }
+        } else {
+            let mut options = OpenOptions::new();
+            match mode {
+                0 | 1 => options.read(true),
+                2 | 3 => options.read(true).write(true),
+                4 | 5 => options.write(true).create(true).truncate(true),
+                6 | 7 => options.read(true).write(true).create(true).truncate(true),
+                8 | 9 => options.append(true).create(true),
+                10 | 11 => options.read(true).append(true).create(true),
+                _ => return None,
+            };
+            HostFile::File(options.open(name).ok()?)
+        };
+
+        let index = match self.files.iter().position(|f| f.is_none()) {
+            Some(i) => i,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        self.files[index] = Some(file);
+        return Some(index as u32 + 1);
+    }
+
+    fn file(&mut self, handle: u32) -> Option<&mut HostFile> {
+        let index = (handle as usize).checked_sub(1)?;
+        return self.files.get_mut(index)?.as_mut();
+    }
+
+    pub fn close(&mut self, handle: u32) -> bool {
+        return match self.file(handle) {
+            Some(_) => {
+                self.files[handle as usize - 1] = None;
+                true
+            }
+            None => false,
+        };
+    }
+
+    /**
+     * SYS_WRITE, returning the number of bytes written
+     */
+    pub fn write(&mut self, handle: u32, bytes: &[u8]) -> Option<usize> {
+        match self.file(handle)? {
+            HostFile::Stdin => return None,
+            HostFile::Stdout => self.write_console(bytes),
+            HostFile::Stderr => {
+                let _ = io::stderr().write_all(bytes);
+            }
+            HostFile::File(f) => f.write_all(bytes).ok()?,
+        };
+        return Some(bytes.len());
+    }
+
+    /**
+     * SYS_READ, returning up to `length` bytes. Fewer are returned at the end of the file.
+     */
+    pub fn read(&mut self, handle: u32, length: usize) -> Option<Vec<u8>> {
+        let mut buffer = vec![0; length];
+        let count = match self.file(handle)? {
+            HostFile::Stdin => self.input.read(&mut buffer).ok()?,
+            HostFile::Stdout | HostFile::Stderr => return None,
+            HostFile::File(f) => {
+                let mut count = 0;
+                while count < length {
+                    match f.read(&mut buffer[count..]) {
+                        Ok(0) => break,
+                        Ok(c) => count += c,
+                        Err(_) => return None,
+                    }
+                }
+                count
+            }
+        };
+        buffer.truncate(count);
+        return Some(buffer);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::EmulatorError;
+
+// Every snapshot starts with the magic number, then the format version
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"DISCOSNP";
+
+// Increased whenever the layout changes, older versions are rejected
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+/**
+ * Builds the binary form of a snapshot. Values are little endian, and
+ * variable length data is prefixed with its length as a u32.
+ */
+#[derive(Default)]
+pub struct SnapshotWriter {
+    bytes: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> SnapshotWriter {
+        return SnapshotWriter {
+            bytes: Vec::new(),
+        };
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        return self.bytes;
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u128(&mut self, value: u128) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn write_words(&mut self, words: &[u32]) {
+        self.write_u32(words.len() as u32);
+        for &word in words.iter() {
+            self.write_u32(word);
+        }
+    }
+}
+
+/**
+ * Reads back the values of a `SnapshotWriter`, in the order they were written
+ */
+pub struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl SnapshotReader<'_> {
+    pub fn new(bytes: &[u8]) -> SnapshotReader<'_> {
+        return SnapshotReader {
+            bytes,
+            position: 0,
+        };
+    }
+
+    pub fn is_finished(&self) -> bool {
+        return self.position == self.bytes.len();
+    }
+
+    fn take(&mut self, count: usize) -> Result<&[u8], EmulatorError> {
+        let end = match self.position.checked_add(count) {
+            Some(e) if e <= self.bytes.len() => e,
+            _ => return Err(EmulatorError::Snapshot(String::from("Snapshot ends early"))),
+        };
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        return Ok(taken);
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, EmulatorError> {
+        return Ok(self.read_u8()? != 0);
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, EmulatorError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, EmulatorError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        return Ok(u32::from_le_bytes(bytes));
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, EmulatorError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        return Ok(u64::from_le_bytes(bytes));
+    }
+
+    pub fn read_u128(&mut self) -> Result<u128, EmulatorError> {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(self.take(16)?);
+        return Ok(u128::from_le_bytes(bytes));
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&[u8], EmulatorError> {
+        let length = self.read_u32()? as usize;
+        return self.take(length);
+    }
+
+    /**
+     * Reads bytes written with `write_bytes`, which must be exactly as many as `out` holds
+     */
+    pub fn read_bytes_into(&mut self, out: &mut [u8]) -> Result<(), EmulatorError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != out.len() {
+            return Err(EmulatorError::Snapshot(format!("Expected {} bytes, found {}", out.len(), bytes.len())));
+        }
+        out.copy_from_slice(bytes);
+        return Ok(());
+    }
+
+    /**
+     * Reads words written with `write_words`, which must be exactly as many as `out` holds
+     */
+    pub fn read_words_into(&mut self, out: &mut [u32]) -> Result<(), EmulatorError> {
+        let length = self.read_u32()? as usize;
+        if length != out.len() {
+            return Err(EmulatorError::Snapshot(format!("Expected {} words, found {}", out.len(), length)));
+        }
+        for word in out.iter_mut() {
+            *word = self.read_u32()?;
+        }
+        return Ok(());
+    }
+}
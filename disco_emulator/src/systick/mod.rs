@@ -0,0 +1,161 @@
+use crate::EmulatorError;
+use crate::snapshot::{SnapshotWriter, SnapshotReader};
+use crate::utils::bits::bitset;
+
+use std::cell::Cell;
+
+// SYST_CSR bits
+const ENABLE: u32 = 1 << 0;
+const TICKINT: u32 = 1 << 1;
+const CLKSOURCE: u32 = 1 << 2;
+const COUNTFLAG: u32 = 1 << 16;
+
+// The counter and reload value are 24 bits wide
+const COUNTER_MASK: u32 = 0x00FF_FFFF;
+
+// With CLKSOURCE clear the STM32L4 counts on the AHB clock divided by 8
+const EXTERNAL_DIVIDER: u32 = 8;
+
+// SKEW is set and TENMS is 0, as the 10ms count depends on how the clock is configured
+const CALIB: u32 = 0x4000_0000;
+
+/**
+ * The SysTick timer (B3.3). It counts processor clock cycles, which are derived
+ * from the number of executed instructions by the board.
+ */
+#[derive(Debug)]
+pub struct SysTick {
+    csr: u32,
+    rvr: u32,
+    cvr: u32,
+
+    // Cleared by reading CSR, so must be writable from a read
+    count_flag: Cell<bool>,
+
+    // Cycles not yet counted when using the divided reference clock
+    prescaled: u32,
+}
+
+impl SysTick {
+    pub fn new() -> SysTick {
+        return SysTick {
+            csr: 0,
+            rvr: 0,
+            cvr: 0,
+            count_flag: Cell::new(false),
+            prescaled: 0,
+        };
+    }
+
+    pub fn reset(&mut self) {
+        *self = SysTick::new();
+    }
+
+    pub fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_words(&[self.csr, self.rvr, self.cvr, self.prescaled]);
+        snapshot.write_bool(self.count_flag.get());
+    }
+
+    pub fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), EmulatorError> {
+        let mut words = [0; 4];
+        snapshot.read_words_into(&mut words)?;
+        let [csr, rvr, cvr, prescaled] = words;
+        self.csr = csr;
+        self.rvr = rvr;
+        self.cvr = cvr;
+        self.prescaled = prescaled;
+        self.count_flag.set(snapshot.read_bool()?);
+        return Ok(());
+    }
+
+    /**
+     * Advances the counter by `cycles` processor clock cycles. Returns true
+     * if the counter reached zero with TICKINT set, so the SysTick exception
+     * should be pended.
+     */
+    pub fn advance(&mut self, cycles: u32) -> bool {
+        if self.csr & ENABLE == 0 {
+            return false;
+        }
+
+        let mut remaining = if self.csr & CLKSOURCE != 0 {
+            cycles
+        } else {
+            self.prescaled += cycles;
+            let ticks = self.prescaled / EXTERNAL_DIVIDER;
+            self.prescaled %= EXTERNAL_DIVIDER;
+            ticks
+        };
+
+        // B3.3.1 p676, counting from 1 to 0 sets COUNTFLAG, then the
+        // next tick reloads the counter from RVR
+        let mut fired = false;
+        while remaining > 0 {
+            if self.cvr == 0 {
+                if self.rvr == 0 {
+                    break;
+                }
+                self.cvr = self.rvr;
+                remaining -= 1;
+                continue;
+            }
+
+            let step = remaining.min(self.cvr);
+            self.cvr -= step;
+            remaining -= step;
+            if self.cvr == 0 {
+                self.count_flag.set(true);
+                fired |= self.csr & TICKINT != 0;
+            }
+        }
+        return fired;
+    }
+
+    /**
+     * Registers at 0xE000_E010, offsets are from there
+     */
+    pub fn read(&self, offset: usize) -> u32 {
+        return match offset {
+            // B3.3.3 SYST_CSR
+            0x0 => {
+                let flag = self.count_flag.replace(false);
+                self.csr | if flag { COUNTFLAG } else { 0 }
+            }
+            // B3.3.4 SYST_RVR
+            0x4 => self.rvr,
+            // B3.3.5 SYST_CVR
+            0x8 => self.cvr,
+            // B3.3.6 SYST_CALIB
+            0xC => CALIB,
+            _ => 0,
+        };
+    }
+
+    /**
+     * Like `read`, but COUNTFLAG is not cleared, for a debugger
+     */
+    pub fn peek(&self, offset: usize) -> u32 {
+        return match offset {
+            0x0 => self.csr | if self.count_flag.get() { COUNTFLAG } else { 0 },
+            _ => self.read(offset),
+        };
+    }
+
+    pub fn write(&mut self, offset: usize, value: u32) {
+        match offset {
+            0x0 => {
+                if !bitset(self.csr, 0) && bitset(value, 0) {
+                    self.prescaled = 0;
+                }
+                self.csr = value & (ENABLE | TICKINT | CLKSOURCE);
+            }
+            0x4 => self.rvr = value & COUNTER_MASK,
+            0x8 => {
+                // Any write clears the counter and COUNTFLAG
+                self.cvr = 0;
+                self.count_flag.set(false);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::cell::Cell;
+
+/**
+ * Which accesses a watchpoint stops on
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub length: u32,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn matches(&self, address: u32, size: usize, write: bool) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Write => write,
+            WatchKind::Read => !write,
+            WatchKind::Access => true,
+        };
+        let end = self.address as u64 + self.length as u64;
+        return kind_matches && (address as u64) < end && address as u64 + size as u64 > self.address as u64;
+    }
+}
+
+/**
+ * The watchpoints set by a debugger, checked on every load and store the
+ * memory bus makes. Like the DWT comparators (C1.8), a hit is only reported
+ * once the instruction that made the access has completed.
+ */
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+
+    // The first watchpoint hit since `take_hit`. Loads only borrow the bus, so this is a Cell.
+    hit: Cell<Option<Watchpoint>>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        return Watchpoints {
+            watchpoints: Vec::new(),
+            hit: Cell::new(None),
+        };
+    }
+
+    pub fn insert(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove(&mut self, watchpoint: Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|&w| w != watchpoint);
+        return self.watchpoints.len() != count;
+    }
+
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn check(&self, address: u32, size: usize, write: bool) {
+        if self.watchpoints.is_empty() || self.hit.get().is_some() {
+            return;
+        }
+        if let Some(&w) = self.watchpoints.iter().find(|w| w.matches(address, size, write)) {
+            self.hit.set(Some(w));
+        }
+    }
+
+    pub fn take_hit(&self) -> Option<Watchpoint> {
+        return self.hit.take();
+    }
+}
@@ -1,20 +1,24 @@
 #![allow(dead_code)]
 
 mod server;
-use server::{GdbServer, get_elf_file_path_from_argv, get_debug_from_argv};
+use server::{GdbServer, Transport, get_elf_file_path_from_argv, get_debug_from_argv};
 
-use disco_emulator::{self, Board};
+use disco_emulator::{self, Board, ConsoleSink, LogLevel};
 
 use std::sync::mpsc::sync_channel;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::process;
+use std::time::{Duration, Instant};
 
-fn main() {
-    if get_debug_from_argv() {
-        println!("CLI args: {:?}", std::env::args());
-    }
+// Exit statuses of --run when the program does not exit through semihosting
+const EXIT_LOAD_FAILED: i32 = 123;
+const EXIT_TIMEOUT: i32 = 124;
+const EXIT_INSTRUCTION_LIMIT: i32 = 125;
+const EXIT_FATAL_ERROR: i32 = 126;
 
+fn main() {
     if get_version_from_argv() {
         println!("disco-emulator v{}", disco_emulator::get_version());
         println!("disco-server v{}", env!("CARGO_PKG_VERSION"));
@@ -24,7 +28,14 @@ fn main() {
     if let Some((start, count)) = get_audio_samples() {
         let elf_path = get_elf_file_path_from_argv().expect("Path to elf file required");
         let mut board = Board::new();
+        board.set_event_sink(Box::new(ConsoleSink::new(get_log_level())));
         board.load_elf_from_path(&elf_path).expect("Failed to load from ELF file");
+        if let Some(cycles) = disco_emulator::get_cycles_per_instruction_from_argv() {
+            board.set_cycles_per_instruction(cycles);
+        }
+        if let Some(path) = disco_emulator::get_input_timeline_path_from_argv() {
+            board.load_input_timeline_from_path(&path).expect("Failed to load input timeline");
+        }
 
         let (tx, rx) = sync_channel(1);
         let rx = Arc::new(Mutex::new(rx));
@@ -54,7 +65,12 @@ fn main() {
         board.audio_handler.set_observer(tx);
 
         while !end.load(Ordering::Relaxed) {
-            board.step().unwrap();
+            if let Err(e) = board.step() {
+                if e.is_fatal() {
+                    panic!("{}", e);
+                }
+                println!("{}", e);
+            }
         }
 
         handle.join().unwrap();
@@ -68,8 +84,161 @@ fn main() {
         return;
     }
 
+    if get_run_from_argv() {
+        process::exit(run_to_completion());
+    }
+
+    // With --stdio, GDB talks to the server over stdout, so this comes before anything is printed
+    let transport = match Transport::from_argv() {
+        Ok(Some(t)) => t,
+        Ok(None) => {
+            println!("must provide tcp::<port>, tcp:<address>:<port>, unix:<path> or --stdio");
+            process::exit(1);
+        }
+        Err(e) => {
+            println!("cannot use stdio for GDB: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if get_debug_from_argv() {
+        println!("CLI args: {:?}", std::env::args());
+    }
+
     println!("started emulator server");
-    GdbServer::start_server();
+    GdbServer::start_server(transport);
+}
+
+/**
+ * Runs the ELF file without a debugger until it exits through semihosting, returning
+ * its exit status. Stops early on an instruction limit, a timeout or a fatal error.
+ */
+fn run_to_completion() -> i32 {
+    let elf_path = match get_elf_file_path_from_argv() {
+        Some(p) => p,
+        None => {
+            println!("Path to elf file required");
+            return EXIT_LOAD_FAILED;
+        }
+    };
+
+    let mut board = Board::new();
+    board.set_event_sink(Box::new(ConsoleSink::new(get_log_level())));
+    if let Err(e) = board.load_elf_from_path(&elf_path) {
+        println!("Failed to load from ELF file: {}", e);
+        return EXIT_LOAD_FAILED;
+    }
+    if let Some(cycles) = disco_emulator::get_cycles_per_instruction_from_argv() {
+        board.set_cycles_per_instruction(cycles);
+    }
+    if let Some(backend) = disco_emulator::get_serial_backend_from_argv() {
+        if let Err(e) = board.connect_serial(&backend) {
+            println!("{}", e);
+            return EXIT_LOAD_FAILED;
+        }
+    }
+    if let Some(path) = disco_emulator::get_input_timeline_path_from_argv() {
+        if let Err(e) = board.load_input_timeline_from_path(&path) {
+            println!("{}", e);
+            return EXIT_LOAD_FAILED;
+        }
+    }
+
+    let max_instructions = match get_max_instructions_from_argv() {
+        Ok(m) => m,
+        Err(e) => {
+            println!("Failed to read instruction limit: {}", e);
+            return EXIT_LOAD_FAILED;
+        }
+    };
+    let deadline = match get_timeout_from_argv() {
+        Ok(t) => t.map(|t| Instant::now() + t),
+        Err(e) => {
+            println!("Failed to read timeout: {}", e);
+            return EXIT_LOAD_FAILED;
+        }
+    };
+    let mut executed: u64 = 0;
+    loop {
+        if let Some(status) = board.exit_status() {
+            return exit_code(status);
+        }
+        if max_instructions.is_some_and(|m| executed >= m) {
+            println!("Instruction limit of {} reached", executed);
+            return EXIT_INSTRUCTION_LIMIT;
+        }
+
+        // Checking the clock is slow, so only do it every so often
+        if executed & 0xFFFF == 0 && deadline.is_some_and(|d| Instant::now() >= d) {
+            println!("Timed out after {} instructions", executed);
+            return EXIT_TIMEOUT;
+        }
+
+        if let Err(e) = board.step() {
+            if e.is_fatal() {
+                println!("Stopped on fatal error: {}", e);
+                return EXIT_FATAL_ERROR;
+            }
+            // The fault handler has been entered, and may well recover
+            println!("{}", e);
+        }
+        executed += 1;
+    }
+}
+
+/**
+ * The process exit code for a program's exit status. Only the low 8 bits of an
+ * exit code reach the parent, and 123 and up are the emulator's own codes, so
+ * any failure status outside 1..=122 becomes 1.
+ */
+fn exit_code(status: u32) -> i32 {
+    return match status {
+        0..=122 => status as i32,
+        _ => 1,
+    };
+}
+
+// Everything is printed unless `--log-level` says otherwise
+fn get_log_level() -> LogLevel {
+    return disco_emulator::get_log_level_from_argv().unwrap_or(LogLevel::Debug);
+}
+
+fn get_run_from_argv() -> bool {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--run" {
+            return true;
+        }
+    }
+    return false;
+}
+
+fn get_max_instructions_from_argv() -> Result<Option<u64>, String> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--max-instructions" {
+            let count = args.next().ok_or("missing instruction count")?;
+            return match count.parse::<u64>() {
+                Ok(c) => Ok(Some(c)),
+                Err(e) => Err(format!("{}: {}", count, e)),
+            };
+        }
+    }
+    return Ok(None);
+}
+
+fn get_timeout_from_argv() -> Result<Option<Duration>, String> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--timeout" {
+            let seconds = args.next().ok_or("missing number of seconds")?;
+            return match Duration::try_from_secs_f64(seconds.parse::<f64>().unwrap_or(f64::NAN)) {
+                Ok(t) => Ok(Some(t)),
+                Err(_) => Err(format!("{} is not a non-negative number of seconds", seconds)),
+            };
+        }
+    }
+    return Ok(None);
 }
 
 fn get_version_from_argv() -> bool {
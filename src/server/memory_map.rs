@@ -0,0 +1,28 @@
+use disco_emulator::{MemoryKind, MemoryRegion, FLASH_PAGE_SIZE};
+
+/**
+ * The memory map GDB reads with qXfer:memory-map:read. GDB programs flash
+ * regions with the vFlash packets and uses hardware breakpoints in them, and
+ * treats memory outside the map as inaccessible. GDB only knows RAM, ROM and
+ * flash, so peripheral space is described as RAM.
+ */
+pub fn memory_map_xml(regions: &[MemoryRegion]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n");
+    xml += "<!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" \"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n";
+    xml += "<memory-map>\n";
+    for region in regions {
+        xml += &format!("  <!-- {} -->\n", region.name);
+        match region.kind {
+            MemoryKind::Flash => {
+                xml += &format!("  <memory type=\"flash\" start=\"0x{:08x}\" length=\"0x{:x}\">\n", region.start, region.length);
+                xml += &format!("    <property name=\"blocksize\">0x{:x}</property>\n", FLASH_PAGE_SIZE);
+                xml += "  </memory>\n";
+            }
+            MemoryKind::Ram | MemoryKind::Peripheral => {
+                xml += &format!("  <memory type=\"ram\" start=\"0x{:08x}\" length=\"0x{:x}\"/>\n", region.start, region.length);
+            }
+        }
+    }
+    xml += "</memory-map>\n";
+    return xml;
+}
@@ -2,18 +2,36 @@ mod packet;
 use packet::{hex_to_word, word_to_hex, validate_packet, get_checksum_hex, get_u8_from_hex, leading_alpha};
 
 mod query;
-use query::{Query, Set};
+use query::{Query, Set, GdbFeature};
 
-use std::collections::{HashSet, VecDeque};
+mod memory_map;
+use memory_map::memory_map_xml;
+
+mod monitor;
+use monitor::MonitorCommand;
+
+mod registers;
+use registers::{TARGET_XML, register_numbers, register_size, read_register, write_register};
+
+mod target;
+use target::{Target, Command, Stop, SessionEvent};
+
+mod transport;
+use transport::{Listener, Connection};
+pub use transport::Transport;
+
+use std::collections::VecDeque;
 use std::env;
-use std::ffi::{OsString};
 use std::io::prelude::*;
-use std::io::{Read};
-use std::net::{TcpStream, TcpListener, Shutdown};
+use std::io::{self, Read};
 use std::path::{PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
 use std::vec;
 
-use disco_emulator::Board;
+use disco_emulator::{Board, EmulatorError, MemError, ReverseStop, Watchpoint, WatchKind,
+    ADP_STOPPED_BRANCH_THROUGH_ZERO, ADP_STOPPED_UNDEFINED_INSTR, ADP_STOPPED_PREFETCH_ABORT, ADP_STOPPED_DATA_ABORT, ADP_STOPPED_ADDRESS_EXCEPTION,
+    ADP_STOPPED_BREAK_POINT, ADP_STOPPED_WATCH_POINT, ADP_STOPPED_STEP_COMPLETE, ADP_STOPPED_USER_INTERRUPTION, ADP_STOPPED_STACK_OVERFLOW, ADP_STOPPED_DIVISION_BY_ZERO, ConsoleSink, LogLevel, SerialBackend, get_log_level_from_argv, get_cycles_per_instruction_from_argv, get_input_timeline_path_from_argv, get_serial_backend_from_argv};
 
 #[derive(Debug)]
 enum PacketState {
@@ -22,9 +40,72 @@ enum PacketState {
     Checksum,
 }
 
-#[derive(Debug)]
+// GDB's own signal numbers (include/gdb/signals.def), which stop replies use whatever the host
+#[derive(Debug, Copy, Clone)]
 enum Signal {
+    Interrupt,
+    IllegalInstruction,
+    Trap,
+    Abort,
+    FloatingPointException,
+    SegmentationFault,
+}
+
+impl Signal {
+    fn number(self) -> u8 {
+        return match self {
+            Signal::Interrupt => 2,
+            Signal::IllegalInstruction => 4,
+            Signal::Trap => 5,
+            Signal::Abort => 6,
+            Signal::FloatingPointException => 8,
+            Signal::SegmentationFault => 11,
+        };
+    }
+
+    /**
+     * The signal for a fault, like a Unix process would get for it
+     */
+    fn from_error(error: &EmulatorError) -> Signal {
+        return match error {
+            EmulatorError::FetchFault { .. } |
+            EmulatorError::MemoryFault { .. } |
+            EmulatorError::InvalidExceptionReturn { .. } => Signal::SegmentationFault,
+            EmulatorError::InvalidState { .. } |
+            EmulatorError::UndefinedInstruction { .. } |
+            EmulatorError::UnimplementedInstruction { .. } => Signal::IllegalInstruction,
+            _ => Signal::Abort,
+        };
+    }
+
+    /**
+     * The signal for a semihosting exit reason other than ADP_Stopped_ApplicationExit
+     */
+    fn from_exit_reason(reason: u32) -> Signal {
+        return match reason {
+            ADP_STOPPED_BRANCH_THROUGH_ZERO |
+            ADP_STOPPED_PREFETCH_ABORT |
+            ADP_STOPPED_DATA_ABORT |
+            ADP_STOPPED_ADDRESS_EXCEPTION |
+            ADP_STOPPED_STACK_OVERFLOW => Signal::SegmentationFault,
+            ADP_STOPPED_UNDEFINED_INSTR => Signal::IllegalInstruction,
+            ADP_STOPPED_DIVISION_BY_ZERO => Signal::FloatingPointException,
+            ADP_STOPPED_USER_INTERRUPTION => Signal::Interrupt,
+            ADP_STOPPED_BREAK_POINT |
+            ADP_STOPPED_WATCH_POINT |
+            ADP_STOPPED_STEP_COMPLETE => Signal::Trap,
+            _ => Signal::Abort,
+        };
+    }
+}
 
+/**
+ * How a session ended without the debugger going away
+ */
+#[derive(Debug)]
+enum SessionEnd {
+    Detach,
+    Kill,
 }
 
 #[derive(Debug)]
@@ -59,98 +140,169 @@ enum Request {
     ToggleDebug,
     Detach { pid: Option<u32> },
     ReadRegisters,
-    WriteRegisters { values: Vec<u32> },
+    WriteRegisters { bytes: Vec<u8> },
     SetThreadSupport { values: Vec<u8> }, // deprecated over vCont
     StepClockCycle { address: Option<u32>, count: u32 },
     Kill,
     ReadMemory { address: u32, length: u32 },
     WriteMemory { address: u32, length: u32, bytes: Vec<u8> },
     ReadRegister { number: u32 },
-    WriteRegister { number: u32, value: u32 },
+    WriteRegister { number: u32, bytes: Vec<u8> },
     Query { query: Query },
     Set { set: Set },
     ResetSystem,
     RestartProgram,
+    Run { path: Option<PathBuf> },
     SingleStep { address: Option<u32>, signal: Option<Signal> },
     SearchBackwards { address: u32, pattern: u32, mask: u32 },
     ThreadAlive { id: i32 },
     MustReplyEmpty,
     UnknownVPacket,
+    FlashErase { address: u32, length: u32 },
+    FlashWrite { address: u32, bytes: Vec<u8> },
+    FlashDone,
     EditBreakpoint { address: u32, set: bool, btype: BreakpointType, kind: BreakpointKind },
+    EditWatchpoint { address: u32, length: u32, set: bool, btype: BreakpointType },
 }
 
-pub struct GdbServer<'a> {
-    stream: & 'a mut TcpStream,
-    tcp_buffer: Box<[u8]>,
+pub struct GdbServer {
+    stream: Box<dyn Write>,
+    packet_size: usize,
     packet_builder: Vec<u8>,
     packet_builder_state: PacketState,
     packet_checksum: Option<u8>,
     acknowledge: bool,
     packets: VecDeque<(Vec<u8>, bool)>,
-    board: Board,
-    hw_breakpoints: HashSet<u32>,
+
+    // The board runs on its own thread, see `Target`
+    commands: Sender<Command>,
+    events: Receiver<SessionEvent>,
+
+    // How the target last stopped, for '?'
+    last_stop: Stop,
+
+    // Whether GDB understands the swbreak and hwbreak stop reasons
+    swbreak: bool,
+    hwbreak: bool,
+
+    // Set by '!'. In extended mode, killing the program resets it instead of ending the session.
+    extended: bool,
     debug: bool,
 }
 
-impl GdbServer<'_> {
-    fn new(stream: &mut TcpStream, buffer_size: usize) -> GdbServer {
+impl GdbServer {
+    fn new(connection: &Connection, buffer_size: usize, commands: Sender<Command>) -> GdbServer {
+        let (events, receiver) = channel();
+
+        // Attaching stops the target if it was left running. The stop is waited
+        // for before reading from GDB, so that it cannot be mistaken for a disconnection.
+        commands.send(Command::Attach(events.clone())).expect("target thread stopped");
+        let last_stop = loop {
+            // Anything else was left over from while no debugger was attached
+            if let SessionEvent::Stopped(stop) = receiver.recv().expect("target thread stopped") {
+                break stop;
+            }
+        };
+
+        let reader = connection.reader().expect("failed to clone the connection");
+        spawn_reader(reader, buffer_size, events);
         return GdbServer {
-            stream: stream,
-            tcp_buffer: vec![0; buffer_size].into_boxed_slice(),
+            stream: connection.writer().expect("failed to clone the connection"),
+            packet_size: buffer_size,
             packet_builder: Vec::new(),
             packet_builder_state: PacketState::Start,
             packet_checksum: None,
             acknowledge: true,
             packets: VecDeque::new(),
-            board: Board::new(),
-            hw_breakpoints: HashSet::new(),
+            commands,
+            events: receiver,
+            last_stop,
+            swbreak: false,
+            hwbreak: false,
+            extended: false,
             debug: get_debug_from_argv(),
         }
     }
 
-    pub fn start_server() {
-        let port: String = get_tcp_port_from_argv().expect("must provide TCP port");
+    pub fn start_server(transport: Transport) {
+        // GDB would be reading the same stdin as the program
+        let stdio = matches!(transport, Transport::Stdio(_));
+        if stdio && matches!(get_serial_backend_from_argv(), Some(SerialBackend::Stdio)) {
+            println!("--serial stdio cannot be used with --stdio");
+            return;
+        }
 
-        let listener = match TcpListener::bind(format!("127.0.0.1:{}", port)) {
-            Ok(s) => s,
+        let mut listener = match Listener::bind(transport) {
+            Ok(l) => l,
             Err(e) => {
-                println!("error connecting to port {}: {:?}", port, e);
+                println!("error listening for GDB: {}", e);
                 return;
             }
         };
+        // With port 0 the system picks one, so tools need to be told which
+        println!("listening on {}", listener.address());
 
-        match listener.accept() {
-            Ok((socket, _addr)) => {
-                println!("connected emulator");
-                GdbServer::handle_client(socket);
+        // The board outlives each session, so with --multi a debugger can connect
+        // again and carry on. Connections made during a session wait their turn.
+        let multi = get_multi_from_argv();
+        let keep_running = get_keep_running_from_argv();
+        let mut board = load_board();
+        if stdio {
+            // Stdin has GDB's packets, so reading the semihosting console finds the end of the file
+            board.set_semihosting_input(Box::new(io::empty()));
+        }
+        let commands = Target::spawn(board);
+        loop {
+            match listener.accept() {
+                Ok(Some(connection)) => {
+                    println!("connected emulator");
+                    GdbServer::handle_client(connection, &commands, keep_running);
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    println!("error accepting connection: {:?}", e);
+                    return;
+                }
             }
-            Err(e) => {
-                println!("error accepting connection: {:?}", e);
+            if !multi {
                 return;
             }
+            println!("waiting for the next debugger connection");
         }
     }
 
-    fn handle_client(mut stream: TcpStream) {
-        let mut server = GdbServer::new(&mut stream, 4096);
+    fn handle_client(connection: Connection, commands: &Sender<Command>, keep_running: bool) {
+        let mut server = GdbServer::new(&connection, 4096, commands.clone());
 
-        if let Err(e) = server.run() {
+        let end = server.run();
+        if let Err(e) = &end {
             println!("server error: {:?}", e);
         };
-
-        stream.shutdown(Shutdown::Both).expect("shutdown call failed");
+        server.end_session(end, keep_running);
+        connection.shutdown();
     }
 
-    fn run(&mut self) -> Result<(), ()> {
-        if let Err(e) = self.stream.set_nodelay(true) {
-            println!("cannot set no delay on TCP stream: {}", e);
-        };
-
-        if let Some(path) = get_elf_file_path_from_argv() {
-            self.board.load_elf_from_path(&path).expect("failed to load from ELF file");
-        } else {
-            println!("ELF file path not provided");
+    /**
+     * Leaves the target ready for the next session. Killing the program
+     * resets it. Otherwise it carries on from where it is: running if
+     * `keep_running` is set, or halted.
+     */
+    fn end_session(&mut self, end: Result<SessionEnd, ()>, keep_running: bool) {
+        // GDB sets its breakpoints again when it connects
+        self.with_target(|t| t.clear_breakpoints());
+        match end {
+            Ok(SessionEnd::Kill) => {
+                self.send_command(Command::Interrupt);
+                self.with_target(|t| t.reset());
+            }
+            _ if keep_running => self.send_command(Command::Continue),
+            _ => self.send_command(Command::Interrupt),
         }
+    }
+
+    fn run(&mut self) -> Result<SessionEnd, ()> {
+        // Going backwards stops where the session started
+        self.with_target(|t| t.history.clear());
 
         loop {
             let request = match self.receive_request() {
@@ -171,104 +323,203 @@ impl GdbServer<'_> {
                 Request::MustReplyEmpty => {
                     self.send_reply_empty();
                 }
+                Request::FlashErase { address, length } => {
+                    let result = self.with_target(move |t| t.board.erase_flash(address, length));
+                    self.send_flash_reply(result);
+                }
+                Request::FlashWrite { address, bytes } => {
+                    let result = self.with_target(move |t| t.board.program_flash(address, &bytes));
+                    self.send_flash_reply(result);
+                }
+                Request::FlashDone => {
+                    self.with_target(|t| t.history.record_change(&t.board));
+                    self.send_reply_ok();
+                }
+                Request::Kill if self.extended => {
+                    // GDB starts the program again with vRun, so only the session ends
+                    self.with_target(|t| t.reset());
+                    self.last_stop = Stop::Stepped;
+                    self.send_acknowledge();
+                }
                 Request::Kill => {
-                    println!("shutting down server loop");
-                    return Ok(());
+                    println!("program killed, ending the session");
+                    return Ok(SessionEnd::Kill);
+                }
+                Request::Detach { .. } => {
+                    println!("debugger detached");
+                    self.send_reply_ok();
+                    return Ok(SessionEnd::Detach);
                 }
                 Request::EnableExtendedMode => {
+                    self.extended = true;
                     self.send_reply_ok();
                 }
+                Request::ResetSystem | Request::RestartProgram => {
+                    // Neither has a reply
+                    self.with_target(|t| t.reset());
+                    self.last_stop = Stop::Stepped;
+                    self.send_acknowledge();
+                }
+                Request::Run { path } => {
+                    // Running loads the ELF file again, so a rebuilt program is picked up
+                    let result = match path.or_else(get_elf_file_path_from_argv) {
+                        Some(path) => self.with_target(move |t| t.reload(&path)),
+                        None => {
+                            self.with_target(|t| t.reset());
+                            Ok(())
+                        }
+                    };
+                    match result {
+                        Ok(()) => self.send_stop_reply(&Stop::Stepped),
+                        Err(e) => {
+                            println!("{}", e);
+                            self.send_reply(b"E01");
+                        }
+                    }
+                }
                 Request::SetThreadSupport {..} => {
                     self.send_reply_ok();
                 }
                 Request::SingleStep { address, .. /*signal*/ } => {
                     if let Some(a) = address {
-                        self.board.cpu.write_instruction_pc(a);
+                        self.with_target(move |t| t.board.cpu.write_instruction_pc(a));
                     }
-                    self.board.step().expect("failed to step board emulation");
-                    self.send_reply(b"S05");
+                    self.resume(Command::Step)?;
                 }
                 Request::Continue { address, .. /*signal*/ } => {
                     if let Some(a) = address {
-                        self.board.cpu.write_instruction_pc(a);
-                    }
-
-                    // HACK: Really, the board should be running on a separate thread to
-                    //       the TCP handler. However, right now we just intermittently
-                    //       check the stream for the interupt. Bigger the skip size ->
-                    //       fewer times we check -> faster emulation -> more latency
-                    //       in the interrupt.
-                    self.stream.set_nonblocking(true).expect("set_nonblocking call failed");
-                    self.send_acknowledge();
-                    while !self.hw_breakpoints.contains(&self.board.cpu.read_instruction_pc()) {
-                        match self.stream.read(&mut self.tcp_buffer) {
-                            Ok(size) => {
-                                if size == 1 && self.tcp_buffer[0] == 0x03 {
-                                    println!("received interrupt");
-                                    break;
-                                } else {
-                                    println!("got unexpected {:?}", &self.tcp_buffer[0..size]);
-                                }
-                            },
-                            Err(_) => {}
-                        };
-                        for _ in 0..128 {
-                            if !self.hw_breakpoints.contains(&self.board.cpu.read_instruction_pc()) {
-                                self.board.step().expect("failed to step board emulation");
-                            } else {
-                                break;
-                            }
-                        }
+                        self.with_target(move |t| t.board.cpu.write_instruction_pc(a));
                     }
-
-                    self.stream.set_nonblocking(false).expect("set_nonblocking call failed");
-                    self.send_reply(b"S05");
+                    self.resume(Command::Continue)?;
+                }
+                Request::Interrupt => {
+                    // The target stopped on its own before the interrupt arrived
+                }
+                Request::BackwardsSingleStep => {
+                    let stop = self.with_target(|t| t.history.step_back(&mut t.board));
+                    self.send_reverse_stop(stop);
+                }
+                Request::BackwardsContinue => {
+                    let stop = self.with_target(|t| t.history.continue_back(&mut t.board, &t.breakpoints));
+                    self.send_reverse_stop(stop);
                 }
                 Request::IndicateHaltReason => {
-                    self.send_reply(b"S05");
+                    let stop = self.last_stop.clone();
+                    self.send_stop_reply(&stop);
                 }
-                Request::EditBreakpoint {address, set, .. } => {
-                    if set {
-                        self.hw_breakpoints.insert(address);
-                    } else {
-                        self.hw_breakpoints.remove(&address);
-                    }
+                Request::EditBreakpoint { address, set, btype, .. } => {
+                    let software = matches!(btype, BreakpointType::Software);
+                    self.send_command(Command::EditBreakpoint { address, set, software });
+                    self.send_reply_ok();
+                }
+                Request::EditWatchpoint { address, length, set, btype } => {
+                    let kind = match btype {
+                        BreakpointType::WriteWatchpoint => WatchKind::Write,
+                        BreakpointType::ReadWatchpoint => WatchKind::Read,
+                        _ => WatchKind::Access,
+                    };
+                    let watchpoint = Watchpoint { address, length, kind };
+                    self.with_target(move |t| {
+                        if set {
+                            t.board.insert_watchpoint(watchpoint);
+                        } else {
+                            t.board.remove_watchpoint(watchpoint);
+                        }
+                    });
                     self.send_reply_ok();
                 }
                 Request::ReadRegisters => {
-                    let mut vals = String::new();
-                    for i in 0..=14u32 {
-                        let rval = self.board.read_reg(i);
-                        vals += &word_to_hex(rval.swap_bytes());
-                    }
-                    vals += &word_to_hex(self.board.cpu.read_instruction_pc().swap_bytes());
-                    vals += &word_to_hex(self.board.cpu.read_xpsr().swap_bytes());
+                    let vals = self.with_target(|t| {
+                        let mut vals = String::new();
+                        for number in register_numbers() {
+                            for b in read_register(&t.board, number).unwrap() {
+                                vals += &format!("{:02x}", b);
+                            }
+                        }
+                        vals
+                    });
 
                     self.send_reply(vals.as_bytes());
                 }
                 Request::ReadRegister { number } => {
-                    let value = match number {
-                        0..=14u32 => self.board.read_reg(number),
-                        15u32 => self.board.cpu.read_instruction_pc(),
-                        25u32 => self.board.cpu.read_xpsr(),
-                        _ => {
-                            println!("Unknown register number: {}", number);
-                            self.send_reply(b"1111112211111144");
-                            continue;
+                    match self.with_target(move |t| read_register(&t.board, number)) {
+                        Some(bytes) => {
+                            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                            self.send_reply(hex.as_bytes());
                         }
-                    };
-                    self.send_reply(word_to_hex(value.swap_bytes()).as_bytes());
+                        None => {
+                            if self.debug {
+                                println!("Unknown register number: {}", number);
+                            }
+                            self.send_reply(b"E01");
+                        }
+                    }
                 }
                 Request::ReadMemory { address, length } => {
-                    let vals = self.board.read_memory_region(address, length).expect("cannot read board memory region");
+                    let vals = self.with_target(move |t| t.board.read_memory_region(address, length)).expect("cannot read board memory region");
                     let mut strs: Vec<u8> = Vec::new();
                     for val in vals {
                         strs.extend(format!("{:02x}", val).bytes());
                     }
                     self.send_reply(strs.as_slice());
                 }
-                Request::WriteMemory { address: _, length: _, bytes: _ } => {
-                    self.send_reply_empty();
+                Request::WriteMemory { address, length: _, bytes } => {
+                    let result = self.with_target(move |t| {
+                        // GDB's load writes the program to flash, which is
+                        // already there if it is the ELF file the emulator loaded
+                        let unchanged = t.board.read_memory_region(address, bytes.len() as u32).is_ok_and(|b| b == bytes);
+                        return match t.board.write_memory_region(address, &bytes) {
+                            Ok(()) => {
+                                t.history.record_change(&t.board);
+                                Ok(())
+                            }
+                            Err(EmulatorError::MemoryFault { kind: MemError::ReadOnly, .. }) if unchanged => Ok(()),
+                            Err(e) => Err(e),
+                        };
+                    });
+                    match result {
+                        Ok(()) => self.send_reply_ok(),
+                        Err(e) => {
+                            if self.debug {
+                                println!("{}", e);
+                            }
+                            self.send_reply(b"E01");
+                        }
+                    }
+                }
+                Request::WriteRegister { number, bytes } => {
+                    let written = self.with_target(move |t| {
+                        let written = write_register(&mut t.board, number, &bytes);
+                        if written {
+                            t.history.record_change(&t.board);
+                        }
+                        return written;
+                    });
+                    if written {
+                        self.send_reply_ok();
+                    } else {
+                        if self.debug {
+                            println!("Unknown register number: {}", number);
+                        }
+                        self.send_reply(b"E01");
+                    }
+                }
+                Request::WriteRegisters { bytes } => {
+                    self.with_target(move |t| {
+                        // Same layout as ReadRegisters. GDB may send fewer registers
+                        // than there are, so whole registers are written until the data runs out.
+                        let mut rest = bytes.as_slice();
+                        for number in register_numbers() {
+                            let size = register_size(number).unwrap();
+                            if rest.len() < size {
+                                break;
+                            }
+                            write_register(&mut t.board, number, &rest[..size]);
+                            rest = &rest[size..];
+                        }
+                        t.history.record_change(&t.board);
+                    });
+                    self.send_reply_ok();
                 }
                 Request::Query { query } => {
                     match query {
@@ -279,28 +530,29 @@ impl GdbServer<'_> {
                             self.send_reply(b"Text=0;Data=0;Bss=0");
                         }
                         Query::ExecCommand { command } => {
-                            match command.as_slice() {
-                                // Platformio needs these to work when using
-                                // its default init script.
-                                // b"reset halt" => {
-                                //     println!("restart not supported");
-                                //     self.send_reply_ok();
-                                // }
-                                // b"init" => {
-                                //     self.send_reply_ok();
-                                // }
-                                _ => {
-                                    if self.debug {
-                                        println!("unknown command: {:?}", std::str::from_utf8(&command));
-                                    }
-                                    self.send_reply_empty();
+                            self.monitor_command(&command);
+                        }
+                        Query::Supported { features } => {
+                            for feature in features {
+                                match feature {
+                                    GdbFeature::SoftwareBreakpoint { supported } => self.swbreak = supported,
+                                    GdbFeature::HardwareBreakpoint { supported } => self.hwbreak = supported,
+                                    _ => {}
                                 }
                             }
-                        }
-                        Query::Supported {..} => {
-                            let m = format!("PacketSize={:X?};QStartNoAckMode+", self.tcp_buffer.len());
+                            let m = format!("PacketSize={:X?};QStartNoAckMode+;ReverseStep+;ReverseContinue+;swbreak+;hwbreak+;qXfer:features:read+;qXfer:memory-map:read+", self.packet_size);
                             self.send_reply(m.as_ref());
                         }
+                        Query::TransferRead { object, annex, offset, length } => {
+                            match (object.as_str(), annex.as_str()) {
+                                ("features", "target.xml") => self.send_transfer_reply(TARGET_XML.as_bytes(), offset, length),
+                                ("memory-map", "") => {
+                                    let xml = self.with_target(|t| memory_map_xml(&t.board.memory.regions()));
+                                    self.send_transfer_reply(xml.as_bytes(), offset, length);
+                                }
+                                _ => self.send_reply(b"E00"),
+                            }
+                        }
                         Query::TracepointStatus => {
                             self.send_reply(b"T0"); // no trace running
                         }
@@ -352,6 +604,175 @@ impl GdbServer<'_> {
         }
     }
 
+    /**
+     * Runs a `monitor` command. What it prints goes to the GDB console in
+     * 'O' packets, followed by OK, or E01 if the command failed.
+     */
+    fn monitor_command(&mut self, command: &[u8]) {
+        let text = String::from_utf8_lossy(command).into_owned();
+        let result = match MonitorCommand::parse(&text) {
+            Ok(command) => self.with_target(move |t| command.run(t)),
+            Err(usage) => Err(usage),
+        };
+        self.send_acknowledge();
+        match result {
+            Ok(output) => {
+                self.send_console_output(&output);
+                self.send_packet(b"OK");
+            }
+            Err(message) => {
+                self.send_console_output(&message);
+                self.send_packet(b"E01");
+            }
+        }
+    }
+
+    /**
+     * Runs `f` on the target thread, and waits for its result
+     */
+    fn with_target<T: Send + 'static>(&self, f: impl FnOnce(&mut Target) -> T + Send + 'static) -> T {
+        let (sender, receiver) = channel();
+        self.send_command(Command::Inspect(Box::new(move |target| {
+            let _ = sender.send(f(target));
+        })));
+        return receiver.recv().expect("target thread stopped");
+    }
+
+    fn send_command(&self, command: Command) {
+        self.commands.send(command).expect("target thread stopped");
+    }
+
+    /**
+     * Steps or continues the target, and replies once it stops
+     */
+    fn resume(&mut self, command: Command) -> Result<(), ()> {
+        // Acknowledged now, as console output may come before the stop reply
+        self.send_acknowledge();
+        self.send_command(command);
+        let stop = self.wait_for_stop()?;
+        match &stop {
+            Stop::Fault(e) => println!("{}", e),
+            Stop::Exited { status, .. } => println!("program exited with status {}", status),
+            Stop::Interrupted => println!("received interrupt"),
+            _ => {}
+        }
+        self.send_stop_reply(&stop);
+        return Ok(());
+    }
+
+    /**
+     * Waits for the target to stop, forwarding interrupts from GDB to it.
+     * Other packets are kept until the stop has been replied to.
+     */
+    fn wait_for_stop(&mut self) -> Result<Stop, ()> {
+        loop {
+            match self.events.recv() {
+                Ok(SessionEvent::Stopped(stop)) => return Ok(stop),
+                Ok(SessionEvent::Output(text)) => self.send_console_output(&text),
+                Ok(SessionEvent::Received(data)) => {
+                    self.process_received(&data)?;
+                    let count = self.packets.len();
+                    self.packets.retain(|(p, single)| !(*single && p[0] == 0x03));
+                    if self.packets.len() != count {
+                        self.send_command(Command::Interrupt);
+                    }
+                }
+                Ok(SessionEvent::Disconnected) | Err(_) => {
+                    println!("debugger disconnected while the target was running");
+                    return Err(());
+                }
+            }
+        }
+    }
+
+    /**
+     * Replies to a step or continue with how it stopped. Unless the program
+     * exited, this includes the signal, SP, PC and xPSR, and the breakpoint
+     * or watchpoint that was hit.
+     */
+    fn send_stop_reply(&mut self, stop: &Stop) {
+        self.last_stop = stop.clone();
+        let (signal, reason) = match stop {
+            Stop::Exited { status, reason: None } => {
+                self.send_reply(format!("W{:02x}", status & 0xFF).as_bytes());
+                return;
+            }
+            Stop::Exited { reason: Some(reason), .. } => {
+                self.send_reply(format!("X{:02x}", Signal::from_exit_reason(*reason).number()).as_bytes());
+                return;
+            }
+            Stop::Breakpoint { software: true } if self.swbreak => (Signal::Trap, String::from("swbreak:;")),
+            Stop::Breakpoint { software: false } if self.hwbreak => (Signal::Trap, String::from("hwbreak:;")),
+            Stop::Watchpoint(w) => {
+                let name = match w.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                (Signal::Trap, format!("{}:{:x};", name, w.address))
+            }
+            Stop::Fault(e) => (Signal::from_error(e), String::new()),
+            Stop::Interrupted => (Signal::Interrupt, String::new()),
+            _ => (Signal::Trap, String::new()),
+        };
+        self.send_stop_packet(signal, &reason);
+    }
+
+    /**
+     * Sends a T stop reply, with the registers GDB needs to show where the target stopped
+     */
+    fn send_stop_packet(&mut self, signal: Signal, reason: &str) {
+        let (sp, pc, xpsr) = self.with_target(|t| (t.board.read_sp(), t.board.cpu.read_instruction_pc(), t.board.cpu.read_xpsr()));
+        let reply = format!(
+            "T{:02x}0d:{};0f:{};19:{};{}",
+            signal.number(),
+            word_to_hex(sp.swap_bytes()),
+            word_to_hex(pc.swap_bytes()),
+            word_to_hex(xpsr.swap_bytes()),
+            reason,
+        );
+        self.send_reply(reply.as_bytes());
+    }
+
+    fn send_reverse_stop(&mut self, stop: Result<ReverseStop, EmulatorError>) {
+        match stop {
+            Ok(ReverseStop::Beginning) => self.send_stop_packet(Signal::Trap, "replaylog:begin;"),
+            Ok(ReverseStop::Breakpoint) => {
+                let software = self.with_target(|t| t.software_breakpoints.contains(&t.board.cpu.read_instruction_pc()));
+                self.send_stop_reply(&Stop::Breakpoint { software });
+            }
+            Ok(ReverseStop::Stepped) => self.send_stop_reply(&Stop::Stepped),
+            Err(e) => {
+                println!("{}", e);
+                self.send_reply(b"E01");
+            }
+        }
+    }
+
+    /**
+     * Replies to a qXfer read with up to `length` bytes of `data` from `offset`,
+     * starting with 'l' if that is the end of the data and 'm' if there is more
+     */
+    fn send_transfer_reply(&mut self, data: &[u8], offset: u32, length: u32) {
+        let start = (offset as usize).min(data.len());
+        let end = start.saturating_add(length as usize).min(data.len());
+        let mut reply = vec![if end == data.len() { b'l' } else { b'm' }];
+        reply.extend(escape_binary(&data[start..end]));
+        self.send_reply(&reply);
+    }
+
+    fn send_flash_reply(&mut self, result: Result<(), EmulatorError>) {
+        match result {
+            Ok(()) => self.send_reply_ok(),
+            Err(e) => {
+                if self.debug {
+                    println!("{}", e);
+                }
+                self.send_reply(b"E01");
+            }
+        }
+    }
+
     fn send_reply_empty(&mut self) {
         self.send_reply(b"");
     }
@@ -361,8 +782,29 @@ impl GdbServer<'_> {
     }
 
     fn send_reply(&mut self, contents: &[u8]) {
-        let mut out: Vec<u8> = Vec::new();
         self.send_acknowledge(); // ack request
+        self.send_packet(contents);
+    }
+
+    /**
+     * Sends text to be printed in the GDB console, split over as many 'O'
+     * packets as it takes. Only valid while GDB waits for a stop reply or
+     * the reply to a `monitor` command.
+     */
+    fn send_console_output(&mut self, text: &str) {
+        // Each byte is sent as two hex digits, after the 'O'
+        let chunk_size = (self.packet_size.saturating_sub(1) / 2).max(1);
+        for chunk in text.as_bytes().chunks(chunk_size) {
+            let mut packet = String::from("O");
+            for b in chunk {
+                packet += &format!("{:02x}", b);
+            }
+            self.send_packet(packet.as_bytes());
+        }
+    }
+
+    fn send_packet(&mut self, contents: &[u8]) {
+        let mut out: Vec<u8> = Vec::new();
         out.push(b'$');
         out.extend_from_slice(contents);
         out.push(b'#');
@@ -370,12 +812,17 @@ impl GdbServer<'_> {
         if self.debug {
             println!("sending reply: {:?}", std::str::from_utf8(out.as_ref()));
         }
-        self.stream.write(out.as_ref()).expect("failed to send message");
+        // A debugger that went away is noticed by the reader thread, which ends the session
+        if let Err(e) = self.stream.write_all(out.as_ref()) {
+            println!("failed to send message: {}", e);
+        }
     }
 
     fn send_acknowledge(&mut self) {
         if self.acknowledge {
-            self.stream.write(b"+").expect("failed to send acknowledgement");
+            if let Err(e) = self.stream.write_all(b"+") {
+                println!("failed to send acknowledgement: {}", e);
+            }
         }
     }
 
@@ -410,11 +857,13 @@ impl GdbServer<'_> {
             b'!' if single => Ok(Request::EnableExtendedMode),
             b'?' if single => Ok(Request::IndicateHaltReason),
             b'A' => Ok(Request::Unhandled), // InitializeArgv
-            b'b' => Ok(Request::Unhandled), // Baud rate & Backwards continue / single step
+            b'b' if packet == b"bc" => Ok(Request::BackwardsContinue),
+            b'b' if packet == b"bs" => Ok(Request::BackwardsSingleStep),
+            b'b' => Ok(Request::Unhandled), // Baud rate
             b'B' => Ok(Request::Unhandled), // EditBreakpointDeprecated
             b'c' | b'C' => self.parse_continue(&packet),
             b'd' => Ok(Request::Unhandled), // toggle debug flag
-            b'D' => Ok(Request::Unhandled), // detach
+            b'D' => self.parse_detach(&packet),
             b'F' => Ok(Request::Unhandled), // reply from GDB from 'F' request
             b'g' if single => Ok(Request::ReadRegisters),
             b'G' => self.parse_write_registers(&packet),
@@ -429,12 +878,12 @@ impl GdbServer<'_> {
             b'q' => self.parse_query(&packet),
             b'Q' => self.parse_set(&packet),
             b'r' if single => Ok(Request::ResetSystem),
-            b'R' if single => Ok(Request::RestartProgram),
+            b'R' => Ok(Request::RestartProgram),
             b's' | b'S' => self.parse_step(&packet),
             b't' => Ok(Request::Unhandled), // backwards search
             b'T' => Ok(Request::Unhandled), // thread alive
             b'v' => self.parse_v_packet(&packet),
-            b'X' => Ok(Request::Unhandled), // write memory
+            b'X' => self.parse_write_binary(&packet),
             b'z' | b'Z' => self.parse_edit_breakpoint(&packet), // edit breakpoint
             _ => Ok(Request::Unhandled), // whatever falls through
         };
@@ -456,8 +905,24 @@ impl GdbServer<'_> {
         });
     }
 
-    fn parse_write_registers(&mut self, _packet: &[u8]) -> Result<Request, ()> {
-        return Err(());
+    fn parse_detach(&mut self, packet: &[u8]) -> Result<Request, ()> {
+        assert!(packet[0] == b'D');
+        // D;pid in multiprocess mode, although there is only ever one process
+        return match &packet[1..] {
+            b"" => Ok(Request::Detach { pid: None }),
+            pid => Ok(Request::Detach { pid: Some(hex_to_word(pid.strip_prefix(b";").ok_or(())?)?) }),
+        };
+    }
+
+    fn parse_write_registers(&mut self, packet: &[u8]) -> Result<Request, ()> {
+        assert!(packet[0] == b'G');
+        let bytes = parse_hex_bytes(&packet[1..])?;
+        let total: usize = register_numbers().filter_map(register_size).sum();
+        if bytes.len() % 4 != 0 || bytes.len() > total {
+            println!("invalid write registers instruction");
+            return Err(());
+        }
+        return Ok(Request::WriteRegisters { bytes });
     }
 
     fn parse_edit_breakpoint(&mut self, packet: &[u8]) -> Result<Request, ()> {
@@ -465,16 +930,26 @@ impl GdbServer<'_> {
         let mut iter = packet[1..].split(|&c| c == b',' || c == b';');
 
         match (iter.next(), iter.next(), iter.next()) {
-            (Some(t), Some(addr), Some(k)) => {
-                return Ok(Request::EditBreakpoint {
+            // The kind of a watchpoint is the number of bytes to watch
+            (Some(t @ (b"2" | b"3" | b"4")), Some(addr), Some(k)) => {
+                return Ok(Request::EditWatchpoint {
                     address: hex_to_word(addr)?,
+                    length: hex_to_word(k)?,
                     set: packet[0] == b'Z',
                     btype: match t {
-                        b"0" => BreakpointType::Hardware,
-                        b"1" => BreakpointType::Software,
                         b"2" => BreakpointType::WriteWatchpoint,
                         b"3" => BreakpointType::ReadWatchpoint,
-                        b"4" => BreakpointType::AccessWatchpoint,
+                        _ => BreakpointType::AccessWatchpoint,
+                    },
+                });
+            }
+            (Some(t), Some(addr), Some(k)) => {
+                return Ok(Request::EditBreakpoint {
+                    address: hex_to_word(addr)?,
+                    set: packet[0] == b'Z',
+                    btype: match t {
+                        b"0" => BreakpointType::Software,
+                        b"1" => BreakpointType::Hardware,
                         _ => {
                             println!("unrecognised breakpoint type");
                             return Err(());
@@ -516,33 +991,26 @@ impl GdbServer<'_> {
         }
     }
 
-    fn parse_write_address(&mut self, _packet: &[u8]) -> Result<Request, ()> {
-        return Ok(Request::WriteMemory {
-            address: 0,
-            length: 0,
-            bytes: Vec::new(),
-        });
+    fn parse_write_address(&mut self, packet: &[u8]) -> Result<Request, ()> {
+        assert!(packet[0] == b'M');
+        let (address, length, data) = parse_write_header(&packet[1..])?;
+        let bytes = parse_hex_bytes(data)?;
+        if bytes.len() != length as usize {
+            println!("invalid write memory instruction");
+            return Err(());
+        }
+        return Ok(Request::WriteMemory { address, length, bytes });
+    }
 
-        // BUG: It seemed to stall when we actually changed a lot of memory.
-        //      workaround by not doing it, because we already loaded the
-        //      board contents.
-        // assert!(packet[0] == b'M');
-        // println!("parsing write address");
-        // packet = &packet[1..];
-        // let mut iter = packet.split(|&c| c == b',' || c == b':');
-        // match (iter.next(), iter.next(), iter.next(), iter.next()) {
-        //     (Some(a), Some(l), Some(b), None) => {
-        //         return Ok(Request::WriteMemory {
-        //             address: hex_to_word(a)?,
-        //             length: hex_to_word(l)?,
-        //             bytes: parse_hex_bytes(b)?,
-        //         });
-        //     }
-        //     _ => {
-        //         println!("invalid write memory instruction");
-        //         return Err(());
-        //     }
-        // }
+    fn parse_write_binary(&mut self, packet: &[u8]) -> Result<Request, ()> {
+        assert!(packet[0] == b'X');
+        let (address, length, data) = parse_write_header(&packet[1..])?;
+        let bytes = unescape_binary(data)?;
+        if bytes.len() != length as usize {
+            println!("invalid binary write memory instruction");
+            return Err(());
+        }
+        return Ok(Request::WriteMemory { address, length, bytes });
     }
 
     fn parse_read_register(&mut self, mut packet: &[u8]) -> Result<Request, ()> {
@@ -565,9 +1033,10 @@ impl GdbServer<'_> {
         let mut iter = packet.split(|&c| c == b'=');
         match (iter.next(), iter.next(), iter.next()) {
             (Some(r), Some(v), None) => {
+                // Values are in target byte order, like the replies to 'p'
                 return Ok(Request::WriteRegister {
                     number: hex_to_word(r)?,
-                    value: hex_to_word(v)?,
+                    bytes: parse_hex_bytes(v)?,
                 });
             },
             _ => {
@@ -590,8 +1059,14 @@ impl GdbServer<'_> {
         return Ok(Request::Query { query: match command {
             b"C" if all => Query::CurrentThread,
             b"Supported" => {
-                // we don't really care what it declares
-                Query::Supported { features: Vec::new() }
+                // Only the features that change what the server sends matter
+                let features = packet[command.len()..].strip_prefix(b":").unwrap_or(&[]);
+                Query::Supported { features: features.split(|&c| c == b';').filter_map(|f| match f {
+                    b"swbreak+" => Some(GdbFeature::SoftwareBreakpoint { supported: true }),
+                    b"hwbreak+" => Some(GdbFeature::HardwareBreakpoint { supported: true }),
+                    b"vContSupported+" => Some(GdbFeature::VContSupported { supported: true }),
+                    _ => None,
+                }).collect() }
             },
             b"Rcmd" => {
                 if let Ok(command) = parse_hex_bytes(&packet[5..]) {
@@ -601,6 +1076,22 @@ impl GdbServer<'_> {
                     return Err(());
                 }
             }
+            b"Xfer" => {
+                // qXfer:object:read:annex:offset,length
+                let mut fields = packet[command.len()..].splitn(5, |&c| c == b':').skip(1);
+                match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                    (Some(object), Some(b"read"), Some(annex), Some(range)) => {
+                        let (offset, length) = parse_offset_length(range)?;
+                        Query::TransferRead {
+                            object: String::from_utf8_lossy(object).into_owned(),
+                            annex: String::from_utf8_lossy(annex).into_owned(),
+                            offset,
+                            length,
+                        }
+                    }
+                    _ => return Ok(Request::Unhandled),
+                }
+            }
             b"Offsets" if all => Query::SectionOffsets,
             b"fThreadInfo" if all => Query::ThreadInfoFirst,
             b"sThreadInfo" if all => Query::ThreadInfoSubsequent,
@@ -641,6 +1132,25 @@ impl GdbServer<'_> {
         let command = leading_alpha(&packet);
         return Ok(match command {
             b"MustReplyEmpty" => Request::MustReplyEmpty,
+            b"FlashErase" => {
+                let (address, length) = parse_offset_length(packet[command.len()..].strip_prefix(b":").ok_or(())?)?;
+                Request::FlashErase { address, length }
+            }
+            b"FlashWrite" => {
+                // vFlashWrite:addr:XX... with the data binary escaped
+                let mut fields = packet[command.len()..].splitn(3, |&c| c == b':').skip(1);
+                match (fields.next(), fields.next()) {
+                    (Some(address), Some(data)) => Request::FlashWrite { address: hex_to_word(address)?, bytes: unescape_binary(data)? },
+                    _ => return Err(()),
+                }
+            }
+            b"FlashDone" => Request::FlashDone,
+            b"Run" => {
+                // vRun;filename[;argument]..., hex encoded. The program has no arguments to take.
+                let mut fields = packet[command.len()..].split(|&c| c == b';').skip(1);
+                let path = parse_hex_bytes(fields.next().unwrap_or(&[]))?;
+                Request::Run { path: if path.is_empty() { None } else { Some(PathBuf::from(String::from_utf8_lossy(&path).into_owned())) } }
+            }
             _ => {
                 if self.debug {
                     println!("unrecognised vPack: {:?}", command);
@@ -650,7 +1160,7 @@ impl GdbServer<'_> {
         });
     }
 
-    // Receives a packet from the TCP stream
+    // Receives a packet from the debugger
     fn receive_packet(&mut self) -> Result<(Vec<u8>, bool), ()> {
         loop {
             if let Some(p) = self.packets.pop_front() {
@@ -658,32 +1168,25 @@ impl GdbServer<'_> {
             }
 
             // No packets means we don't have enough data for a full one.
-            // The only way to get more is reading the stream
-            if let Err(e) = self.process_tcp_packet() {
-                println!("failed to read packet: {:?}", e);
-                return Err(());
-            };
+            // The only way to get more is waiting for the reader thread
+            match self.events.recv() {
+                Ok(SessionEvent::Received(data)) => self.process_received(&data)?,
+                Ok(SessionEvent::Stopped(stop)) => println!("unexpected stop while halted: {:?}", stop),
+                Ok(SessionEvent::Output(text)) => print!("{}", text),
+                Ok(SessionEvent::Disconnected) | Err(_) => {
+                    println!("debugger disconnected");
+                    return Err(());
+                }
+            }
         }
     }
 
-    fn process_tcp_packet(&mut self) -> Result<(), ()> {
-        let size = match self.stream.read(&mut self.tcp_buffer) {
-            Ok(s) => s,
-            Err(e) => {
-                println!("failed to read from tcp stream: {}", e);
-                return Err(());
-            }
-        };
-
+    fn process_received(&mut self, data: &[u8]) -> Result<(), ()> {
         if self.debug {
-            println!("TCP: {:?}", std::str::from_utf8(self.tcp_buffer[..size].as_ref()));
-        }
-
-        if size == 0 {
-            return Ok(());
+            println!("received: {:?}", std::str::from_utf8(data));
         }
 
-        for &c in &self.tcp_buffer[0..size] {
+        for &c in data {
             match self.packet_builder_state {
                 PacketState::Start => {
                     match c {
@@ -744,15 +1247,55 @@ impl GdbServer<'_> {
     }
 }
 
-fn get_tcp_port_from_argv() -> Option<String> {
-    let args: Vec<OsString> = env::args_os().collect();
-    for arg in args {
-        if arg.to_str().expect("").starts_with("tcp::") {
-            let port = &arg.to_str().expect("cannot read OS arg as string")[5..];
-            return Some(String::from(port));
+/**
+ * Creates the board and sets it up from the command line arguments
+ */
+fn load_board() -> Board {
+    let mut board = Board::new();
+    let log_level = get_log_level_from_argv().unwrap_or(LogLevel::Debug);
+    board.set_event_sink(Box::new(ConsoleSink::new(log_level)));
+
+    if let Some(path) = get_elf_file_path_from_argv() {
+        board.load_elf_from_path(&path).expect("failed to load from ELF file");
+    } else {
+        println!("ELF file path not provided");
+    }
+
+    if let Some(cycles) = get_cycles_per_instruction_from_argv() {
+        board.set_cycles_per_instruction(cycles);
+    }
+
+    if let Some(backend) = get_serial_backend_from_argv() {
+        board.connect_serial(&backend).expect("failed to connect serial port");
+    }
+
+    if let Some(path) = get_input_timeline_path_from_argv() {
+        board.load_input_timeline_from_path(&path).expect("failed to load input timeline");
+    }
+    return board;
+}
+
+/**
+ * Reads from GDB on its own thread, so an interrupt can arrive while the target is running
+ */
+fn spawn_reader(mut stream: Box<dyn Read + Send>, buffer_size: usize, events: Sender<SessionEvent>) {
+    thread::spawn(move || {
+        let mut buffer = vec![0; buffer_size];
+        loop {
+            let event = match stream.read(&mut buffer) {
+                Ok(0) => SessionEvent::Disconnected,
+                Ok(size) => SessionEvent::Received(buffer[..size].to_vec()),
+                Err(e) => {
+                    println!("failed to read from the debugger: {}", e);
+                    SessionEvent::Disconnected
+                }
+            };
+            let disconnected = matches!(event, SessionEvent::Disconnected);
+            if events.send(event).is_err() || disconnected {
+                return;
+            }
         }
-    };
-    return None;
+    });
 }
 
 pub fn get_elf_file_path_from_argv() -> Option<PathBuf> {
@@ -766,6 +1309,20 @@ pub fn get_elf_file_path_from_argv() -> Option<PathBuf> {
     return None;
 }
 
+/**
+ * With `--multi`, the server waits for another debugger when a session ends
+ */
+fn get_multi_from_argv() -> bool {
+    return env::args().any(|arg| arg == "--multi");
+}
+
+/**
+ * With `--keep-running`, the board runs while no debugger is attached
+ */
+fn get_keep_running_from_argv() -> bool {
+    return env::args().any(|arg| arg == "--keep-running");
+}
+
 pub fn get_debug_from_argv() -> bool {
     let mut args = env::args();
     while let Some(arg) = args.next() {
@@ -790,6 +1347,64 @@ fn parse_read_memory(mut data: &[u8]) -> Result<(u32, u32), ()> {
     return Ok((hex_to_word(addr)?, hex_to_word(length)?));
 }
 
+/**
+ * Parses "offset,length" in hex, like the end of a qXfer read or vFlashErase
+ */
+fn parse_offset_length(data: &[u8]) -> Result<(u32, u32), ()> {
+    let mut parts = data.split(|&c| c == b',');
+    return match (parts.next(), parts.next(), parts.next()) {
+        (Some(offset), Some(length), None) => Ok((hex_to_word(offset)?, hex_to_word(length)?)),
+        _ => Err(()),
+    };
+}
+
+/**
+ * Splits `addr,length:data` of an M or X packet
+ */
+fn parse_write_header(data: &[u8]) -> Result<(u32, u32, &[u8]), ()> {
+    let colon = match data.iter().position(|&c| c == b':') {
+        Some(i) => i,
+        None => return Err(()),
+    };
+    let mut parts = data[..colon].split(|&c| c == b',');
+    return match (parts.next(), parts.next(), parts.next()) {
+        (Some(a), Some(l), None) => Ok((hex_to_word(a)?, hex_to_word(l)?, &data[colon + 1..])),
+        _ => Err(()),
+    };
+}
+
+/**
+ * Binary data has '#', '$', '}' and '*' escaped as '}' followed by the byte XOR 0x20
+ */
+fn unescape_binary(data: &[u8]) -> Result<Vec<u8>, ()> {
+    let mut out = Vec::new();
+    let mut iter = data.iter();
+    while let Some(&c) = iter.next() {
+        if c == b'}' {
+            match iter.next() {
+                Some(&e) => out.push(e ^ 0x20),
+                None => return Err(()),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    return Ok(out);
+}
+
+fn escape_binary(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for &c in data {
+        if matches!(c, b'#' | b'$' | b'}' | b'*') {
+            out.push(b'}');
+            out.push(c ^ 0x20);
+        } else {
+            out.push(c);
+        }
+    }
+    return out;
+}
+
 fn parse_hex_bytes(data: &[u8]) -> Result<Vec<u8>, ()> {
     if data.len() % 2 != 0 {
         return Err(());
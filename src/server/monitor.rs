@@ -0,0 +1,148 @@
+use std::path::PathBuf;
+
+use disco_emulator::RegFormat;
+
+use super::target::Target;
+use super::get_elf_file_path_from_argv;
+
+const HELP: &str = "\
+Monitor commands:
+  help                      show this list
+  reset [halt]              reset the board, stopping at the reset handler
+  reset reload              load the ELF file again, then reset
+  init                      accepted for compatibility, does nothing
+  tick                      show the instructions run and the simulated time
+  regformat [<reg|all> <bin|oct|dec|sig|hex>]
+                            show the core registers, or set how one is shown
+  peripherals               list the peripherals on the memory bus
+  trace on|off              print every instruction run to the GDB console
+  audio stats               show the audio samples played
+  snapshot save|load <path> save the board state to a file, or restore it
+";
+
+/**
+ * A command run with `monitor` in GDB, sent in a qRcmd packet
+ */
+#[derive(Debug)]
+pub enum MonitorCommand {
+    Help,
+    Reset { reload: bool },
+    Init,
+    Tick,
+    Registers,
+    RegFormat { register: Option<u32>, format: RegFormat }, // None for all registers
+    Peripherals,
+    Trace { enabled: bool },
+    AudioStats,
+    Snapshot { save: bool, path: PathBuf },
+}
+
+impl MonitorCommand {
+    /**
+     * Parses the text after `monitor`, returning how to use the command if it is wrong
+     */
+    pub fn parse(text: &str) -> Result<MonitorCommand, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        return match words.as_slice() {
+            ["help"] => Ok(MonitorCommand::Help),
+            // GDB keeps control, so a reset always stops at the reset handler
+            ["reset"] | ["reset", "halt"] => Ok(MonitorCommand::Reset { reload: false }),
+            ["reset", "reload"] => Ok(MonitorCommand::Reset { reload: true }),
+            ["init"] => Ok(MonitorCommand::Init),
+            ["tick"] => Ok(MonitorCommand::Tick),
+            ["regformat"] => Ok(MonitorCommand::Registers),
+            ["regformat", register, format] => {
+                let register = match *register {
+                    "all" => None,
+                    r => Some(parse_register(r).ok_or(format!("Unknown register \"{}\", expected r0 - r15, sp, lr, pc or all\n", r))?),
+                };
+                let format = RegFormat::parse(format).map_err(|e| e + "\n")?;
+                Ok(MonitorCommand::RegFormat { register, format })
+            }
+            ["peripherals"] => Ok(MonitorCommand::Peripherals),
+            ["trace", "on"] => Ok(MonitorCommand::Trace { enabled: true }),
+            ["trace", "off"] => Ok(MonitorCommand::Trace { enabled: false }),
+            ["audio", "stats"] => Ok(MonitorCommand::AudioStats),
+            ["snapshot", action @ ("save" | "load"), ..] => {
+                // The path is the rest of the line, so it may contain spaces
+                let path = text.trim_start()["snapshot".len()..].trim_start()[action.len()..].trim();
+                if path.is_empty() {
+                    return Err(String::from("usage: monitor snapshot save|load <path>\n"));
+                }
+                Ok(MonitorCommand::Snapshot { save: *action == "save", path: PathBuf::from(path) })
+            }
+            [] => Ok(MonitorCommand::Help),
+            _ => Err(format!("Unknown monitor command \"{}\"\n{}", text.trim(), HELP)),
+        };
+    }
+
+    /**
+     * Runs the command on the target thread, returning what to print in GDB
+     */
+    pub fn run(self, target: &mut Target) -> Result<String, String> {
+        return match self {
+            MonitorCommand::Help => Ok(String::from(HELP)),
+            MonitorCommand::Reset { reload: false } => {
+                target.reset();
+                Ok(format!("Reset, pc is 0x{:08X}\n", target.board.cpu.read_instruction_pc()))
+            }
+            MonitorCommand::Reset { reload: true } => {
+                let path = get_elf_file_path_from_argv().ok_or("No ELF file was given with -kernel\n")?;
+                target.reload(&path).map_err(|e| format!("{}\n", e))?;
+                Ok(format!("Loaded {}, pc is 0x{:08X}\n", path.display(), target.board.cpu.read_instruction_pc()))
+            }
+            MonitorCommand::Init => Ok(String::new()),
+            MonitorCommand::Tick => {
+                let board = &target.board;
+                Ok(format!("{} instructions, {} ns simulated\n", board.instruction_count(), board.simulated_time() / 1000))
+            }
+            MonitorCommand::Registers => Ok(format!("{}\n", target.board)),
+            MonitorCommand::RegFormat { register, format } => {
+                match register {
+                    Some(r) => target.board.set_register_format(r, format),
+                    None => (0..=15).for_each(|r| target.board.set_register_format(r, format)),
+                }
+                Ok(format!("{}\n", target.board))
+            }
+            MonitorCommand::Peripherals => {
+                let mut out = String::new();
+                for p in target.board.peripherals() {
+                    let clock = if p.clocked { "" } else { " (clock disabled)" };
+                    out += &format!("0x{:08X} - 0x{:08X}  {}{}\n", p.base, p.base + (p.size - 1), p.name, clock);
+                }
+                Ok(out)
+            }
+            MonitorCommand::Trace { enabled } => {
+                target.trace = enabled;
+                Ok(format!("Instruction trace {}\n", if enabled { "on" } else { "off" }))
+            }
+            MonitorCommand::AudioStats => {
+                let stats = target.board.audio_stats();
+                let output = if stats.connected { "connected" } else { "not connected" };
+                Ok(format!("{} samples played, audio output {}\n", stats.samples, output))
+            }
+            MonitorCommand::Snapshot { save: true, path } => {
+                target.board.save_snapshot_to_path(&path).map_err(|e| format!("{}\n", e))?;
+                Ok(format!("Saved snapshot to {}\n", path.display()))
+            }
+            MonitorCommand::Snapshot { save: false, path } => {
+                target.board.load_snapshot_from_path(&path).map_err(|e| format!("{}\n", e))?;
+                // The history leads up to the old state, not the restored one
+                target.history.clear();
+                Ok(format!("Loaded snapshot from {}\n", path.display()))
+            }
+        };
+    }
+}
+
+/**
+ * Parses r0 - r15, sp, lr or pc
+ */
+fn parse_register(name: &str) -> Option<u32> {
+    return match name {
+        "sp" => Some(13),
+        "lr" => Some(14),
+        "pc" => Some(15),
+        _ => name.strip_prefix('r')?.parse().ok().filter(|&r| r <= 15),
+    };
+}
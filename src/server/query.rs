@@ -24,6 +24,7 @@ pub enum Query {
     TracepointBuffer { offset: u32, length: u32 },
     ThreadExtraInfo { id: i32 },
     AttachedToProcess { process: Option<u32> },
+    TransferRead { object: String, annex: String, offset: u32, length: u32 }, // qXfer:object:read
 }
 
 #[derive(Debug)]
@@ -0,0 +1,148 @@
+use disco_emulator::Board;
+
+/**
+ * The target description GDB reads with qXfer:features:read. GDB numbers
+ * registers by their order here, and the 'g' and 'G' packets hold every one
+ * of them in that order. The core registers use the numbers GDB's own
+ * m-profile descriptions have, so xPSR is 25.
+ */
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>armv7e-m</architecture>
+  <feature name="org.gnu.gdb.arm.m-profile">
+    <reg name="r0" bitsize="32" regnum="0"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="xpsr" bitsize="32" regnum="25"/>
+  </feature>
+  <feature name="org.gnu.gdb.arm.vfp">
+    <reg name="d0" bitsize="64" type="ieee_double" regnum="26"/>
+    <reg name="d1" bitsize="64" type="ieee_double"/>
+    <reg name="d2" bitsize="64" type="ieee_double"/>
+    <reg name="d3" bitsize="64" type="ieee_double"/>
+    <reg name="d4" bitsize="64" type="ieee_double"/>
+    <reg name="d5" bitsize="64" type="ieee_double"/>
+    <reg name="d6" bitsize="64" type="ieee_double"/>
+    <reg name="d7" bitsize="64" type="ieee_double"/>
+    <reg name="d8" bitsize="64" type="ieee_double"/>
+    <reg name="d9" bitsize="64" type="ieee_double"/>
+    <reg name="d10" bitsize="64" type="ieee_double"/>
+    <reg name="d11" bitsize="64" type="ieee_double"/>
+    <reg name="d12" bitsize="64" type="ieee_double"/>
+    <reg name="d13" bitsize="64" type="ieee_double"/>
+    <reg name="d14" bitsize="64" type="ieee_double"/>
+    <reg name="d15" bitsize="64" type="ieee_double"/>
+    <reg name="fpscr" bitsize="32" type="int" group="float"/>
+  </feature>
+  <feature name="org.gnu.gdb.arm.m-system">
+    <reg name="msp" bitsize="32" type="data_ptr" group="system"/>
+    <reg name="psp" bitsize="32" type="data_ptr" group="system"/>
+    <reg name="primask" bitsize="32" type="int" group="system"/>
+    <reg name="basepri" bitsize="32" type="int" group="system"/>
+    <reg name="faultmask" bitsize="32" type="int" group="system"/>
+    <reg name="control" bitsize="32" type="int" group="system"/>
+  </feature>
+</target>
+"#;
+
+const XPSR: u32 = 25;
+const D0: u32 = 26;
+const D15: u32 = 41;
+const FPSCR: u32 = 42;
+const MSP: u32 = 43;
+const PSP: u32 = 44;
+const PRIMASK: u32 = 45;
+const BASEPRI: u32 = 46;
+const FAULTMASK: u32 = 47;
+const CONTROL: u32 = 48;
+
+/**
+ * The registers in the order of the 'g' and 'G' packets
+ */
+pub fn register_numbers() -> impl Iterator<Item = u32> {
+    return (0..=15).chain(XPSR..=CONTROL);
+}
+
+/**
+ * The size in bytes of a register, or None if there is no such register
+ */
+pub fn register_size(number: u32) -> Option<usize> {
+    return match number {
+        0..=15 | XPSR | FPSCR..=CONTROL => Some(4),
+        D0..=D15 => Some(8),
+        _ => None,
+    };
+}
+
+/**
+ * Reads a register by its GDB number, in target byte order
+ */
+pub fn read_register(board: &Board, number: u32) -> Option<Vec<u8>> {
+    let cpu = &board.cpu;
+    let value = match number {
+        0..=14 => cpu.read_reg(number),
+        15 => cpu.read_instruction_pc(),
+        XPSR => cpu.read_xpsr(),
+        D0..=D15 => {
+            let s = 2 * (number - D0);
+            let mut bytes = cpu.read_fp_reg(s).to_le_bytes().to_vec();
+            bytes.extend_from_slice(&cpu.read_fp_reg(s + 1).to_le_bytes());
+            return Some(bytes);
+        }
+        FPSCR => cpu.read_fpscr(),
+        MSP => cpu.read_msp(),
+        PSP => cpu.read_psp(),
+        PRIMASK => u32::from(cpu.read_primask()),
+        BASEPRI => u32::from(cpu.read_basepri()),
+        FAULTMASK => u32::from(cpu.read_faultmask()),
+        CONTROL => cpu.read_control(),
+        _ => return None,
+    };
+    return Some(value.to_le_bytes().to_vec());
+}
+
+/**
+ * Writes a register by its GDB number from bytes in target byte order,
+ * returning false if there is no such register or the size is wrong
+ */
+pub fn write_register(board: &mut Board, number: u32, bytes: &[u8]) -> bool {
+    if register_size(number) != Some(bytes.len()) {
+        return false;
+    }
+    let cpu = &mut board.cpu;
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    match number {
+        0..=12 | 14 => cpu.write_reg(number, value),
+        13 => cpu.write_sp(value),
+        15 => cpu.write_instruction_pc(value & !0b1),
+        XPSR => cpu.write_xpsr(value),
+        D0..=D15 => {
+            let s = 2 * (number - D0);
+            cpu.write_fp_reg(s, value);
+            cpu.write_fp_reg(s + 1, u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]));
+        }
+        FPSCR => cpu.write_fpscr(value),
+        MSP => cpu.write_msp(value),
+        PSP => cpu.write_psp(value),
+        PRIMASK => cpu.write_primask(value & 1 != 0),
+        BASEPRI => cpu.write_basepri(value as u8),
+        FAULTMASK => cpu.write_faultmask(value & 1 != 0),
+        CONTROL => cpu.write_control(value),
+        _ => return false,
+    }
+    return true;
+}
@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use std::path::Path;
+
+use disco_emulator::{Board, EmulatorError, History, Watchpoint};
+
+// Instructions run between checks for commands while continuing
+const COMMAND_POLL_INTERVAL: u32 = 1024;
+
+/**
+ * The board and the debugger state that goes with it, owned by the target thread
+ */
+pub struct Target {
+    pub board: Board,
+    pub history: History,
+
+    // Every breakpoint, and which of them GDB set as software breakpoints
+    pub breakpoints: HashSet<u32>,
+    pub software_breakpoints: HashSet<u32>,
+
+    // Whether to print each instruction before it runs, and what is still to be sent
+    pub trace: bool,
+    trace_output: String,
+}
+
+/**
+ * Why a step or continue stopped
+ */
+#[derive(Debug, Clone)]
+pub enum Stop {
+    // A single step completed
+    Stepped,
+
+    // The instruction at a breakpoint is next to execute
+    Breakpoint { software: bool },
+
+    // The last instruction was a BKPT that is not a semihosting call
+    BreakpointInstruction,
+
+    // The last instruction accessed the memory of a watchpoint
+    Watchpoint(Watchpoint),
+
+    // The last instruction faulted, or the emulator cannot carry on
+    Fault(EmulatorError),
+
+    // The program exited through semihosting, see `Board::exit_reason`
+    Exited { status: u32, reason: Option<u32> },
+
+    // The debugger asked to stop
+    Interrupted,
+}
+
+pub enum Command {
+    Step,
+    Continue,
+    Interrupt,
+    EditBreakpoint { address: u32, set: bool, software: bool },
+
+    // Reports to a new session from now on. The target stops if it was
+    // running, and always replies with a stop, so the session knows it is halted.
+    Attach(Sender<SessionEvent>),
+
+    // Runs on the target thread while it is stopped, see `GdbServer::with_target`
+    Inspect(Box<dyn FnOnce(&mut Target) + Send>),
+}
+
+/**
+ * What the GDB session thread waits on: data from the debugger, or the target stopping
+ */
+pub enum SessionEvent {
+    Received(Vec<u8>),
+    Disconnected,
+    Stopped(Stop),
+
+    // Text for the GDB console, like the instruction trace
+    Output(String),
+}
+
+impl Target {
+    /**
+     * Runs the target on its own thread. Steps and continues report how they
+     * stopped to the session that last attached, and the returned sender
+     * controls the target until it is dropped. The target outlives sessions,
+     * so a debugger can connect again and find the board as it left it.
+     */
+    pub fn spawn(board: Board) -> Sender<Command> {
+        let (commands, receiver) = channel();
+        thread::spawn(move || {
+            let mut target = Target {
+                board,
+                history: History::new(),
+                breakpoints: HashSet::new(),
+                software_breakpoints: HashSet::new(),
+                trace: false,
+                trace_output: String::new(),
+            };
+            target.run(receiver);
+        });
+        return commands;
+    }
+
+    fn run(&mut self, commands: Receiver<Command>) {
+        // Until a session attaches, nobody hears about stops
+        let (mut events, _) = channel();
+        while let Ok(command) = commands.recv() {
+            let stop = match command {
+                Command::Step => self.single_step(),
+                Command::Continue => self.continue_until_stop(&commands, &mut events),
+                Command::Attach(session) => {
+                    events = session;
+                    Stop::Stepped
+                }
+                command => {
+                    self.apply(command);
+                    continue;
+                }
+            };
+            self.send_trace(&events);
+            // The session may have ended while the target was running
+            let _ = events.send(SessionEvent::Stopped(stop));
+        }
+    }
+
+    /**
+     * Handles the commands that do not run the board
+     */
+    fn apply(&mut self, command: Command) {
+        match command {
+            Command::EditBreakpoint { address, set: true, software } => {
+                self.breakpoints.insert(address);
+                if software {
+                    self.software_breakpoints.insert(address);
+                }
+            }
+            Command::EditBreakpoint { address, set: false, .. } => {
+                self.breakpoints.remove(&address);
+                self.software_breakpoints.remove(&address);
+            }
+            Command::Inspect(f) => f(self),
+            Command::Step | Command::Continue | Command::Interrupt | Command::Attach(_) => {}
+        }
+    }
+
+    /**
+     * Resets the board, keeping the program in flash. There is no history
+     * from before a reset to go back to.
+     */
+    pub fn reset(&mut self) {
+        self.board.reset();
+        self.history.clear();
+    }
+
+    /**
+     * Loads the ELF file at `path` again, like after rebuilding it, and resets the board
+     */
+    pub fn reload(&mut self, path: &Path) -> Result<(), EmulatorError> {
+        self.board.reload_elf_from_path(path)?;
+        self.history.clear();
+        return Ok(());
+    }
+
+    /**
+     * Forgets the breakpoints and watchpoints of a session that has ended
+     */
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+        self.software_breakpoints.clear();
+        self.board.clear_watchpoints();
+    }
+
+    fn single_step(&mut self) -> Stop {
+        if let Some(stop) = self.exited() {
+            return stop;
+        }
+        return self.step().unwrap_or(Stop::Stepped);
+    }
+
+    fn continue_until_stop(&mut self, commands: &Receiver<Command>, events: &mut Sender<SessionEvent>) -> Stop {
+        loop {
+            for _ in 0..COMMAND_POLL_INTERVAL {
+                if let Some(stop) = self.exited() {
+                    return stop;
+                }
+                let pc = self.board.cpu.read_instruction_pc();
+                if self.breakpoints.contains(&pc) {
+                    return Stop::Breakpoint { software: self.software_breakpoints.contains(&pc) };
+                }
+                if let Some(stop) = self.step() {
+                    return stop;
+                }
+            }
+
+            self.send_trace(events);
+            loop {
+                match commands.try_recv() {
+                    Ok(Command::Interrupt) => return Stop::Interrupted,
+                    Ok(Command::Attach(session)) => {
+                        *events = session;
+                        return Stop::Interrupted;
+                    }
+                    Ok(command) => self.apply(command),
+                    Err(TryRecvError::Empty) => break,
+                    // The server has ended, so nobody is waiting for the stop
+                    Err(TryRecvError::Disconnected) => return Stop::Interrupted,
+                }
+            }
+        }
+    }
+
+    /**
+     * Steps once, returning why execution should stop there if it should
+     */
+    fn step(&mut self) -> Option<Stop> {
+        if self.trace {
+            self.trace_instruction();
+        }
+
+        // Stop on faults, so the debugger can look at what caused them
+        if let Err(e) = self.history.step(&mut self.board) {
+            return Some(Stop::Fault(e));
+        }
+        if let Some(w) = self.board.watchpoint_hit() {
+            return Some(Stop::Watchpoint(w));
+        }
+        if self.board.bkpt_hit().is_some() {
+            return Some(Stop::BreakpointInstruction);
+        }
+        return self.exited();
+    }
+
+    /**
+     * Adds the address and encoding of the next instruction to the trace
+     */
+    fn trace_instruction(&mut self) {
+        let pc = self.board.cpu.read_instruction_pc();
+        let first = match self.board.memory.peek_mem(pc, 2) {
+            Ok(h) => h,
+            Err(_) => {
+                self.trace_output += &format!("0x{:08X}: ????\n", pc);
+                return;
+            }
+        };
+        // A3.1: 0b11101, 0b11110 and 0b11111 in bits 15-11 start a 32-bit instruction
+        if first >> 11 >= 0b11101 {
+            let second = self.board.memory.peek_mem(pc + 2, 2).unwrap_or(0);
+            self.trace_output += &format!("0x{:08X}: {:04x} {:04x}\n", pc, first, second);
+        } else {
+            self.trace_output += &format!("0x{:08X}: {:04x}\n", pc, first);
+        }
+    }
+
+    fn send_trace(&mut self, events: &Sender<SessionEvent>) {
+        if !self.trace_output.is_empty() {
+            let _ = events.send(SessionEvent::Output(std::mem::take(&mut self.trace_output)));
+        }
+    }
+
+    fn exited(&self) -> Option<Stop> {
+        let status = self.board.exit_status()?;
+        return Some(Stop::Exited { status, reason: self.board.exit_reason() });
+    }
+}
@@ -0,0 +1,204 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, Shutdown};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+/**
+ * How GDB connects to the server, from the command line
+ */
+pub enum Transport {
+    // tcp::<port> listens on localhost, and tcp:<address>:<port> on the given address
+    Tcp(String),
+
+    // unix:<path>
+    #[cfg(unix)]
+    Unix(PathBuf),
+
+    // --stdio, for `target remote | discoserver --stdio ...`. Holds the
+    // original stdout, as stdout itself now goes to stderr.
+    Stdio(File),
+}
+
+impl Transport {
+    /**
+     * Reads the transport from the command line, or None if there is none.
+     * With `--stdio`, stdout is taken for GDB straight away, so that anything
+     * printed from then on goes to stderr instead.
+     */
+    pub fn from_argv() -> io::Result<Option<Transport>> {
+        for arg in env::args() {
+            if arg == "--stdio" {
+                return Ok(Some(Transport::Stdio(take_stdout()?)));
+            }
+            if let Some(port) = arg.strip_prefix("tcp::") {
+                return Ok(Some(Transport::Tcp(format!("127.0.0.1:{}", port))));
+            }
+            if let Some(address) = arg.strip_prefix("tcp:") {
+                return Ok(Some(Transport::Tcp(String::from(address))));
+            }
+            #[cfg(unix)]
+            if let Some(path) = arg.strip_prefix("unix:") {
+                return Ok(Some(Transport::Unix(PathBuf::from(path))));
+            }
+        }
+        return Ok(None);
+    }
+}
+
+/**
+ * Makes stdout a copy of stderr, returning the original stdout
+ */
+#[cfg(unix)]
+fn take_stdout() -> io::Result<File> {
+    use std::os::unix::io::FromRawFd;
+
+    io::stdout().flush()?;
+    unsafe {
+        let stdout = libc::dup(libc::STDOUT_FILENO);
+        if stdout < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            let error = io::Error::last_os_error();
+            libc::close(stdout);
+            return Err(error);
+        }
+        return Ok(File::from_raw_fd(stdout));
+    }
+}
+
+#[cfg(not(unix))]
+fn take_stdout() -> io::Result<File> {
+    return Err(io::Error::new(io::ErrorKind::Other, "--stdio is only supported on Unix"));
+}
+
+/**
+ * Waits for GDB to connect over a transport
+ */
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+
+    // There is only one connection over stdio, so it is taken by the first accept
+    Stdio(Option<File>),
+}
+
+impl Listener {
+    pub fn bind(transport: Transport) -> io::Result<Listener> {
+        return match transport {
+            Transport::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address)?)),
+            #[cfg(unix)]
+            Transport::Unix(path) => {
+                remove_stale_socket(&path);
+                Ok(Listener::Unix(UnixListener::bind(&path)?, path))
+            }
+            Transport::Stdio(stdout) => Ok(Listener::Stdio(Some(stdout))),
+        };
+    }
+
+    /**
+     * Where GDB can connect, including the port picked if port 0 was asked for
+     */
+    pub fn address(&self) -> String {
+        return match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => address.to_string(),
+                Err(e) => format!("unknown address ({})", e),
+            },
+            #[cfg(unix)]
+            Listener::Unix(_, path) => path.display().to_string(),
+            Listener::Stdio(_) => String::from("stdio"),
+        };
+    }
+
+    /**
+     * Waits for the next connection, or returns None if there can be no more
+     */
+    pub fn accept(&mut self) -> io::Result<Option<Connection>> {
+        return match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                if let Err(e) = stream.set_nodelay(true) {
+                    println!("cannot set no delay on TCP stream: {}", e);
+                }
+                Ok(Some(Connection::Tcp(stream)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Ok(Some(Connection::Unix(listener.accept()?.0))),
+            Listener::Stdio(stdout) => Ok(stdout.take().map(Connection::Stdio)),
+        };
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // Otherwise binding to the path fails next time
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/**
+ * Removes the socket of a server that was killed before it could remove it
+ * itself. A socket that is still being listened on is left alone.
+ */
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) {
+    use std::os::unix::fs::FileTypeExt;
+
+    let socket = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if socket && UnixStream::connect(path).is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/**
+ * A connection to GDB. The session reads from it on one thread and writes on another.
+ */
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Stdio(File),
+}
+
+impl Connection {
+    pub fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        return match self {
+            Connection::Tcp(stream) => Ok(Box::new(stream.try_clone()?)),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Ok(Box::new(stream.try_clone()?)),
+            Connection::Stdio(_) => Ok(Box::new(io::stdin())),
+        };
+    }
+
+    pub fn writer(&self) -> io::Result<Box<dyn Write>> {
+        return match self {
+            Connection::Tcp(stream) => Ok(Box::new(stream.try_clone()?)),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Ok(Box::new(stream.try_clone()?)),
+            Connection::Stdio(stdout) => Ok(Box::new(stdout.try_clone()?)),
+        };
+    }
+
+    /**
+     * Closes the connection, which also ends the session's reader thread
+     */
+    pub fn shutdown(&self) {
+        // The debugger may have closed the connection already
+        let _ = match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.shutdown(Shutdown::Both),
+            Connection::Stdio(_) => Ok(()),
+        };
+    }
+}
@@ -3,9 +3,11 @@
 use std::process::ExitStatus;
 use disco_emulator::Board;
 
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Child};
+use std::process::{Command, Child, Stdio};
+use std::thread;
 
 pub mod online;
 
@@ -72,6 +74,17 @@ pub fn get_default_linker() -> Result<PathBuf, String> {
 }
 
 pub fn load_program(name: &str) -> Result<Board, String> {
+    let elf_path = compile_fixture(name)?;
+    let mut board = Board::new();
+    board.load_elf_from_path(&elf_path).map_err(|e| e.to_string())?;
+
+    return Ok(board);
+}
+
+/**
+ * Builds the offline fixture `name`, returning the path of its ELF file
+ */
+pub fn compile_fixture(name: &str) -> Result<PathBuf, String> {
     let mut path = get_tests_path()?;
     path.push("fixtures");
 
@@ -85,11 +98,70 @@ pub fn load_program(name: &str) -> Result<Board, String> {
     src_path.push(name);
     println!("src: {:?}", src_path);
 
-    let elf_path = compile_program(&src_path, &linker_path)?;
-    let mut board = Board::new();
-    board.load_elf_from_path(&elf_path)?;
+    return compile_program(&src_path, &linker_path);
+}
 
-    return Ok(board);
+/**
+ * A discoserver started by a test, killed when dropped so that a failed
+ * assertion does not leave it running
+ */
+pub struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/**
+ * Starts discoserver on a free TCP port, returning it and the address it listens on
+ */
+pub fn spawn_server(elf_path: &Path, args: &[&str]) -> (Server, String) {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_discoserver"))
+                        .arg("tcp::0")
+                        .arg("-kernel")
+                        .arg(elf_path)
+                        .args(&["--log-level", "error"])
+                        .args(args)
+                        .stdout(Stdio::piped())
+                        .spawn()
+                        .unwrap();
+
+    let mut lines = BufReader::new(server.stdout.take().unwrap()).lines();
+    let address = lines.find_map(|l| l.unwrap().strip_prefix("listening on ").map(String::from)).unwrap();
+
+    // The server stops if it cannot print
+    thread::spawn(move || lines.for_each(drop));
+    return (Server(server), address);
+}
+
+pub fn gdb_packet(contents: &str) -> String {
+    let checksum = contents.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    return format!("${}#{:02x}", contents, checksum);
+}
+
+pub fn gdb_send(stream: &mut impl Write, contents: &str) {
+    stream.write_all(gdb_packet(contents).as_bytes()).unwrap();
+}
+
+/**
+ * Reads the next packet from the server, returning it without its acknowledgement and framing
+ */
+pub fn gdb_reply(stream: &mut impl Read) -> String {
+    let mut reply = Vec::new();
+    let mut byte = [0];
+    while reply.len() < 3 || reply[reply.len() - 3] != b'#' {
+        stream.read_exact(&mut byte).unwrap();
+        reply.push(byte[0]);
+    }
+    let reply = String::from_utf8(reply).unwrap();
+    return String::from(&reply[reply.find('$').unwrap() + 1..reply.len() - 3]);
+}
+
+pub fn gdb_exchange(stream: &mut TcpStream, contents: &str) -> String {
+    gdb_send(stream, contents);
+    return gdb_reply(stream);
 }
 
 pub fn load_and_step(name: &str, steps: usize) -> Result<Board, String> {
@@ -99,9 +171,9 @@ pub fn load_and_step(name: &str, steps: usize) -> Result<Board, String> {
         match board.step() {
             Ok(_) => {},
             Err(e) => {
-                println!("Failed to step board");
+                println!("Failed to step board: {}", e);
                 assert!(false);
-                return Err(e);
+                return Err(e.to_string());
             }
         }
     }
@@ -117,9 +189,9 @@ pub fn load_and_wait(name: &str, wait_reg: u32, wait_signal: u32) -> Result<Boar
         match board.step() {
             Ok(_) => {},
             Err(e) => {
-                println!("Failed to step board");
+                println!("Failed to step board: {}", e);
                 assert!(false);
-                return Err(e);
+                return Err(e.to_string());
             }
         }
         i += 1;
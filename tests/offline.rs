@@ -1,7 +1,15 @@
 #[allow(dead_code)]
 
 mod common;
-use common::{load_program, load_and_step, load_and_wait};
+use common::{load_program, load_and_step, load_and_wait, compile_fixture, spawn_server, gdb_packet, gdb_send, gdb_reply, gdb_exchange};
+
+use disco_emulator::{Board, Peripheral, Interrupts, MemError, EmulatorError, Event, LogLevel, History, ReverseStop, Watchpoint, WatchKind, MemoryKind, PeripheralInfo, ADP_STOPPED_DATA_ABORT, PinChange, InputTimeline, InputEvent, InputTime, PinLevel};
+use std::collections::HashSet;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::Duration;
 
 #[test]
 fn adc() {
@@ -651,3 +659,825 @@ fn exclusive() {
     assert_eq!(board.read_reg(3u32), 1);
     assert_ne!(board.memory.read_mem_u(0x2000_0000, 4).unwrap(), 0xDEAD_BEE2);
 }
+
+#[test]
+fn exception() {
+    let mut board = load_program("exception").unwrap();
+    let sp = board.read_sp();
+
+    // SVC entry stacks the frame and enters handler mode
+    board.step_n(3).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 11);
+    assert_eq!(board.read_lr(), 0xFFFF_FFF9);
+    assert_eq!(board.read_sp(), sp - 32);
+    assert_eq!(board.memory.read_mem_u(sp - 32, 4).unwrap(), 0xA);
+
+    // bx lr returns to thread mode and unstacks the frame
+    board.step_n(3).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+    assert_eq!(board.read_sp(), sp);
+    assert_eq!(board.read_reg(0u32), 0xA);
+    assert_eq!(board.read_reg(5u32), 0x5C);
+
+    board.step().unwrap();
+    assert_eq!(board.read_reg(4u32), 1);
+
+    // Bus fault escalates to HardFault, and pop {pc} returns past the faulting load
+    board.step().unwrap();
+    let fault = EmulatorError::MemoryFault { address: 0x4000_0000, kind: MemError::OutOfBounds };
+    assert_eq!(board.step(), Err(fault));
+    assert_eq!(board.cpu.read_ipsr(), 3);
+    assert_eq!(board.read_lr(), 0xFFFF_FFF9);
+
+    board.step_n(9).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+    assert_eq!(board.read_sp(), sp);
+    assert_eq!(board.read_reg(6u32), 1);
+
+    board.step().unwrap();
+    assert_eq!(board.read_reg(4u32), 2);
+
+    // Branching with the T bit clear faults on the next fetch
+    board.step_n(2).unwrap();
+    let target = board.cpu.read_instruction_pc();
+    assert_eq!(board.step(), Err(EmulatorError::InvalidState { address: target }));
+    assert!(!EmulatorError::InvalidState { address: target }.is_fatal());
+    assert_eq!(board.cpu.read_ipsr(), 3);
+
+    board.step_n(10).unwrap();
+    assert_eq!(board.read_reg(4u32), 3);
+    assert_eq!(board.read_reg(6u32), 2);
+}
+
+#[test]
+fn undefined() {
+    let mut board = load_program("undefined").unwrap();
+    let main = board.cpu.read_instruction_pc();
+
+    // UDF is reported with its encoding, once the fault handler has been entered
+    let undefined = EmulatorError::UndefinedInstruction { address: main, encoding: 0xDE12, wide: false };
+    assert_eq!(board.step(), Err(undefined));
+    assert_eq!(board.cpu.read_ipsr(), 3);
+    assert_eq!(board.read_reg(0u32), 0);
+
+    board.step().unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 3);
+}
+
+#[test]
+fn nvic() {
+    let mut board = load_program("nvic").unwrap();
+    let sp = board.read_sp();
+
+    // Pending with PRIMASK set waits for cpsie
+    board.step_n(14).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+    assert_eq!(board.memory.read_mem_u(0xE000_E200, 4).unwrap(), 0x40);
+    assert_eq!(board.memory.read_mem_u(0xE000_ED04, 4).unwrap() >> 12 & 0x1FF, 22);
+
+    board.step().unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 22);
+    assert_eq!(board.read_lr(), 0xFFFF_FFF9);
+    assert_eq!(board.read_sp(), sp - 32);
+    assert_eq!(board.memory.read_mem_u(0xE000_E200, 4).unwrap(), 0);
+    assert_eq!(board.memory.read_mem_u(0xE000_E300, 4).unwrap(), 0x40);
+
+    // A higher priority interrupt preempts the running handler
+    board.step_n(6).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 23);
+    assert_eq!(board.read_lr(), 0xFFFF_FFF1);
+    assert_eq!(board.read_sp(), sp - 64);
+
+    board.step_n(2).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 22);
+    assert_eq!(board.read_sp(), sp - 32);
+    assert_eq!(board.read_reg(6u32), 1);
+
+    // Returning with the same interrupt pending tail-chains into it without unstacking
+    board.step_n(4).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 22);
+    assert_eq!(board.read_lr(), 0xFFFF_FFF9);
+    assert_eq!(board.read_sp(), sp - 32);
+    assert_eq!(board.read_reg(5u32), 1);
+
+    board.step_n(4).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+    assert_eq!(board.read_sp(), sp);
+    assert_eq!(board.read_reg(0u32), 0xE000_E200);
+    assert_eq!(board.read_reg(5u32), 2);
+
+    // BASEPRI masks interrupts of the same or lower priority
+    board.step_n(4).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+    assert_eq!(board.read_reg(4u32), 3);
+
+    board.step_n(2).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 22);
+
+    board.step_n(5).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+    assert_eq!(board.read_reg(4u32), 4);
+    assert_eq!(board.read_reg(5u32), 3);
+}
+
+#[test]
+fn systick() {
+    let mut board = load_program("systick").unwrap();
+
+    // Enabling loads the reload value, then it counts down once per instruction
+    board.step_n(14).unwrap();
+    assert_eq!(board.memory.read_mem_u(0xE000_E018, 4).unwrap(), 1);
+    assert_eq!(board.cpu.read_ipsr(), 0);
+
+    board.step().unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 15);
+
+    // Reading CSR returns and clears COUNTFLAG
+    board.step_n(2).unwrap();
+    assert_eq!(board.read_reg(4u32), 1);
+    assert_eq!(board.read_reg(1u32), 0x0001_0007);
+    assert_eq!(board.memory.read_mem_u(0xE000_E010, 4).unwrap(), 0b111);
+
+    board.step().unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+
+    board.step_n(7).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 15);
+
+    // More cycles per instruction make it fire sooner
+    board.step_n(3).unwrap();
+    board.set_cycles_per_instruction(5);
+    board.step().unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+    board.step().unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 15);
+    assert_eq!(board.read_reg(4u32), 2);
+}
+
+struct TestDevice {
+    ticks: u32,
+    data: u32,
+    irq: bool,
+}
+
+impl Peripheral for TestDevice {
+    fn read(&mut self, offset: u32, _size: usize) -> Result<u32, MemError> {
+        return match offset {
+            0 => Ok(self.ticks),
+            4 => Ok(self.data),
+            8 => Ok(self.irq as u32),
+            _ => Err(MemError::OutOfBounds),
+        };
+    }
+
+    fn write(&mut self, offset: u32, _size: usize, value: u32) -> Result<(), MemError> {
+        match offset {
+            4 => self.data = value,
+            8 => self.irq = value != 0,
+            _ => return Err(MemError::OutOfBounds),
+        }
+        return Ok(());
+    }
+
+    fn tick(&mut self, cycles: u32, interrupts: &mut Interrupts) {
+        self.ticks += cycles;
+        if self.irq {
+            interrupts.request(0);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ticks = 0;
+        self.data = 0;
+        self.irq = false;
+    }
+}
+
+#[test]
+fn peripheral() {
+    let mut board = load_program("peripheral").unwrap();
+    let device = TestDevice { ticks: 0, data: 0, irq: false };
+    board.register_peripheral(0x4000_0000, 0x400, Box::new(device)).unwrap();
+
+    // Only free ranges of the peripheral regions can be mapped
+    let device = TestDevice { ticks: 0, data: 0, irq: false };
+    assert!(board.register_peripheral(0x4000_0200, 0x400, Box::new(device)).is_err());
+    let device = TestDevice { ticks: 0, data: 0, irq: false };
+    assert!(board.register_peripheral(0x2000_0000, 0x400, Box::new(device)).is_err());
+    let device = TestDevice { ticks: 0, data: 0, irq: false };
+    assert!(board.register_peripheral(0xE000_E000, 0x10, Box::new(device)).is_err());
+
+    // Accesses are forwarded to the device, which is ticked after each instruction
+    board.step_n(5).unwrap();
+    assert_eq!(board.read_reg(2u32), 0x2A);
+    assert_eq!(board.read_reg(3u32), 4);
+    assert_eq!(board.memory.peripheral_mut::<TestDevice>(0x4000_0000).unwrap().data, 0x2A);
+
+    // A requested interrupt is taken, and not again once the device stops requesting it
+    board.step_n(4).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 16);
+
+    board.step_n(4).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+    board.step_n(10).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+    assert_eq!(board.read_reg(5u32), 1);
+
+    // Peripherals without a name are still listed, with the board's own
+    let peripherals = board.peripherals();
+    assert!(peripherals.windows(2).all(|p| p[0].base < p[1].base));
+    assert_eq!(peripherals[0], PeripheralInfo { name: String::from("peripheral"), base: 0x4000_0000, size: 0x400, clocked: true });
+    let gpioa = peripherals.iter().find(|p| p.name == "GPIOA").unwrap();
+    assert_eq!((gpioa.base, gpioa.clocked), (0x4800_0000, false));
+    assert_eq!(board.instruction_count(), 23);
+}
+
+#[test]
+fn gpio() {
+    let mut board = load_program("gpio").unwrap();
+    let (tx, rx) = channel();
+    board.set_gpio_observer(tx);
+
+    // Registers start at their reset values, but read as 0 until the port is clocked
+    assert_eq!(board.gpio('A').unwrap().read(0x00, 4).unwrap(), 0xABFF_FFFF);
+    assert_eq!(board.gpio('B').unwrap().read(0x0C, 4).unwrap(), 0x0000_0100);
+    assert_eq!(board.gpio('H').unwrap().read(0x00, 4).unwrap(), 0x0000_000F);
+    assert_eq!(board.memory.read_mem_u(0x4800_0000, 4).unwrap(), 0);
+
+    board.step_n(3).unwrap();
+    assert_eq!(board.memory.read_mem_u(0x4800_0000, 4).unwrap(), 0xABFF_FFFF);
+
+    board.step_n(8).unwrap();
+    assert_eq!(board.read_reg(2u32), 1 << 2);
+    assert_eq!(rx.try_recv(), Ok(PinChange { port: 'B', pin: 2, high: true, cycle: 9 }));
+
+    board.step_n(2).unwrap();
+    assert_eq!(board.read_reg(3u32) & (1 << 2), 0);
+    assert_eq!(rx.try_recv(), Ok(PinChange { port: 'B', pin: 2, high: false, cycle: 11 }));
+    assert!(rx.try_recv().is_err());
+
+    // Inputs follow the externally driven level
+    board.gpio('A').unwrap().set_input(0, true);
+    board.step_n(6).unwrap();
+    assert_eq!(board.read_reg(4u32), 1);
+    assert!(board.gpio('A').unwrap().read_pin(0));
+    assert_eq!(rx.try_recv().map(|c| (c.port, c.pin, c.high)), Ok(('A', 0, true)));
+}
+
+#[test]
+fn rcc() {
+    let mut board = load_program("rcc").unwrap();
+    assert_eq!(board.memory.read_mem_u(0x4002_1000, 4).unwrap(), 0x0000_0063);
+
+    // Oscillators are ready as soon as they are on, so each wait loop runs once
+    board.step_n(7).unwrap();
+    assert_eq!(board.memory.read_mem_u(0x4002_1000, 4).unwrap(), 0x0000_0563);
+
+    board.step_n(7).unwrap();
+    assert_eq!(board.memory.read_mem_u(0x4002_1008, 4).unwrap(), 0b0101);
+
+    // Clock gated peripherals read as 0 and ignore writes
+    board.step_n(8).unwrap();
+    assert_eq!(board.read_reg(3u32), 0);
+    assert_eq!(board.read_reg(4u32), 0xFFFF_FFFF);
+}
+
+#[test]
+fn exti() {
+    let mut board = load_program("exti").unwrap();
+    board.step_n(20).unwrap();
+    assert_eq!(board.memory.read_mem_u(0x4001_0400, 4).unwrap(), 0xFF82_0001);
+    assert_eq!(board.cpu.read_ipsr(), 0);
+
+    // Pressing the button pends EXTI0 and runs its handler
+    board.gpio('A').unwrap().set_input(0, true);
+    board.step().unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 22);
+    assert_eq!(board.memory.read_mem_u(0x4001_0414, 4).unwrap(), 1);
+
+    board.step_n(5).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+    assert_eq!(board.read_reg(5u32), 1);
+    assert_eq!(board.memory.read_mem_u(0x4001_0414, 4).unwrap(), 0);
+
+    // Releasing it is a falling edge, which is not selected
+    board.gpio('A').unwrap().set_input(0, false);
+    board.step_n(3).unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 0);
+
+    board.gpio('A').unwrap().set_input(0, true);
+    board.step_n(6).unwrap();
+    assert_eq!(board.read_reg(5u32), 2);
+}
+
+#[test]
+fn input_timeline() {
+    let mut expected = InputTimeline::new();
+    expected.push(InputEvent { at: InputTime::Picoseconds(1_500_000_000), port: 'C', pin: 13, level: PinLevel::Low });
+    assert_eq!(InputTimeline::parse("1.5ms PC13 low # comment"), Ok(expected));
+    assert!(InputTimeline::parse("1s centre sideways").is_err());
+    assert!(InputTimeline::parse("1s PI0 high").is_err());
+
+    // At the 4 MHz reset clock each instruction takes 0.25us
+    let mut board = load_program("exti").unwrap();
+    board.set_input_timeline(InputTimeline::parse("7us centre press\n40 centre release\n").unwrap());
+    board.step_n(28).unwrap();
+    assert_eq!(board.simulated_time(), 7_000_000);
+    assert_eq!(board.cpu.read_ipsr(), 0);
+
+    board.step().unwrap();
+    assert_eq!(board.cpu.read_ipsr(), 22);
+
+    board.step_n(6).unwrap();
+    assert_eq!(board.read_reg(5u32), 1);
+    assert!(board.gpio('A').unwrap().read_pin(0));
+
+    board.step_n(6).unwrap();
+    assert!(!board.gpio('A').unwrap().read_pin(0));
+
+    // 48 MHz MSI does not divide a picosecond evenly, but a millisecond of cycles is still a millisecond
+    let mut board = load_program("exti").unwrap();
+    board.write_memory_region(0x4002_1000, &0xBBu32.to_le_bytes()).unwrap();
+    board.step_n(48_000).unwrap();
+    assert_eq!(board.simulated_time(), 1_000_000_000);
+}
+
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+#[test]
+fn usart() {
+    let mut board = load_program("usart").unwrap();
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let (tx, rx) = channel();
+    board.usart2().set_output(Box::new(SharedOutput(Arc::clone(&output))));
+    board.usart2().set_input(rx);
+
+    // Transmitting is instant, so TXE and TC stay set
+    board.step_n(100).unwrap();
+    assert_eq!(output.lock().unwrap().as_slice(), b"Hi");
+    assert_eq!(board.memory.read_mem_u(0x4000_441C, 4).unwrap(), 0x0060_00C0);
+    assert_eq!(board.read_reg(4u32), 0);
+
+    tx.send(b'a').unwrap();
+    board.step().unwrap();
+    assert_ne!(board.memory.read_mem_u(0x4000_441C, 4).unwrap() & (1 << 5), 0);
+
+    // The debugger can look at RDR without taking the byte from the program
+    assert_eq!(board.read_memory_region(0x4000_4424, 4).unwrap(), vec![b'a', 0, 0, 0]);
+    assert_ne!(board.memory.peek_mem(0x4000_441C, 4).unwrap() & (1 << 5), 0);
+    board.step_n(19).unwrap();
+    assert_eq!(board.read_reg(4u32), u32::from(b'a'));
+    assert_eq!(output.lock().unwrap().as_slice(), b"Hib");
+    assert_eq!(board.memory.read_mem_u(0x4000_441C, 4).unwrap() & (1 << 5), 0);
+}
+
+#[test]
+fn semihosting() {
+    let mut board = load_program("semihosting").unwrap();
+    let output = Arc::new(Mutex::new(Vec::new()));
+    board.set_semihosting_console(Box::new(SharedOutput(Arc::clone(&output))));
+
+    board.step_n(40).unwrap();
+    assert_eq!(output.lock().unwrap().as_slice(), b"Hello\n!abc");
+    assert_eq!(board.read_reg(4u32), 1);
+    assert_eq!(board.read_reg(5u32), 0);
+    assert_eq!(board.read_reg(6u32), 0);
+
+    // Nothing executes after the exit
+    assert_eq!(board.exit_status(), Some(3));
+    assert_eq!(board.exit_reason(), None);
+    assert_eq!(board.bkpt_hit(), None);
+    assert_eq!(board.read_reg(7u32), 0);
+}
+
+#[test]
+fn reset() {
+    let mut board = load_program("semihosting").unwrap();
+    let output = Arc::new(Mutex::new(Vec::new()));
+    board.set_semihosting_console(Box::new(SharedOutput(Arc::clone(&output))));
+    let (entry, sp) = (board.cpu.read_instruction_pc(), board.read_sp());
+
+    board.step_n(40).unwrap();
+    assert_eq!(board.exit_status(), Some(3));
+    board.write_memory_region(0x2000_0000, &[1, 2, 3, 4]).unwrap();
+
+    board.reset();
+    assert_eq!(board.exit_status(), None);
+    assert_eq!(board.instruction_count(), 0);
+    assert_eq!((board.cpu.read_instruction_pc(), board.read_sp()), (entry, sp));
+    assert_eq!(board.memory.read_mem_u(0x2000_0000, 4).unwrap(), 0xFFFF_FFFF);
+
+    // The program runs the same way again, still connected to the console
+    board.step_n(40).unwrap();
+    assert_eq!(output.lock().unwrap().as_slice(), b"Hello\n!abcHello\n!abc");
+    assert_eq!(board.exit_status(), Some(3));
+
+    // Reloading replaces the program, unless the new one cannot be loaded
+    let nvic = load_program("nvic").unwrap();
+    board.reload_elf_from_path(&compile_fixture("nvic").unwrap()).unwrap();
+    assert_eq!(board.exit_status(), None);
+    assert_eq!(board.cpu.read_instruction_pc(), nvic.cpu.read_instruction_pc());
+    assert_eq!(board.read_memory_region(0x0800_0000, 0x200).unwrap(), nvic.read_memory_region(0x0800_0000, 0x200).unwrap());
+    assert!(board.reload_elf_from_path(std::path::Path::new("missing.elf")).is_err());
+    assert_eq!(board.read_memory_region(0x0800_0000, 0x200).unwrap(), nvic.read_memory_region(0x0800_0000, 0x200).unwrap());
+}
+
+#[test]
+fn bkpt() {
+    let mut board = load_program("bkpt").unwrap();
+    board.step().unwrap();
+    assert_eq!(board.bkpt_hit(), None);
+
+    // Execution carries on after the breakpoint
+    board.step().unwrap();
+    assert_eq!(board.bkpt_hit(), Some(7));
+    board.step().unwrap();
+    assert_eq!(board.bkpt_hit(), None);
+    assert_eq!(board.read_reg(4u32), 2);
+
+    board.step_n(3).unwrap();
+    assert_eq!(board.exit_status(), Some(1));
+    assert_eq!(board.exit_reason(), Some(ADP_STOPPED_DATA_ABORT));
+}
+
+#[test]
+fn events() {
+    let mut board = load_program("events").unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&events);
+    board.set_event_sink(Box::new(move |e: &Event| sink.lock().unwrap().push(e.clone())));
+
+    // Writes to flash are ignored, and reported instead of printed
+    board.step_n(4).unwrap();
+    assert_eq!(board.read_reg(2u32), 2);
+    let events = events.lock().unwrap();
+    assert_eq!(events.as_slice(), &[Event::ReadOnlyWrite { address: 0x0800_0000 }]);
+    assert_eq!(events[0].level(), LogLevel::Warn);
+    assert!(LogLevel::Error < LogLevel::Warn);
+}
+
+#[test]
+fn snapshot() {
+    let mut board = load_program("nvic").unwrap();
+    board.step_n(20).unwrap();
+    board.cpu.write_fp_reg(31, 0x3F80_0000);
+    board.cpu.write_fpscr(0x0300_0000);
+    let snapshot = board.snapshot();
+
+    let state = |board: &Board| {
+        let mut values: Vec<u32> = (0..16u32).map(|r| board.read_reg(r)).collect();
+        values.push(board.cpu.read_xpsr());
+        values.push(board.cpu.read_fp_reg(31));
+        values.push(board.cpu.read_fpscr());
+        values.push(board.memory.read_mem_u(0xE000_E200, 4).unwrap());
+        values.push(board.memory.read_mem_u(0xE000_E300, 4).unwrap());
+        values
+    };
+    board.step_n(10).unwrap();
+    let expected = state(&board);
+
+    // A new board carries on exactly as the original did, without the ELF file
+    let mut restored = Board::new();
+    restored.restore(&snapshot).unwrap();
+    assert_eq!(restored.cpu.read_ipsr(), 22);
+    restored.step_n(10).unwrap();
+    assert_eq!(state(&restored), expected);
+
+    // Restoring rewinds the original
+    board.restore(&snapshot).unwrap();
+    board.step_n(10).unwrap();
+    assert_eq!(state(&board), expected);
+
+    let mut old = snapshot.clone();
+    old[8] = 0;
+    assert!(matches!(board.restore(&old), Err(EmulatorError::Snapshot(_))));
+    assert!(matches!(board.restore(&snapshot[..100]), Err(EmulatorError::Snapshot(_))));
+}
+
+#[test]
+fn debugger_writes() {
+    let mut board = load_program("nvic").unwrap();
+
+    board.write_memory_region(0x2000_0001, &[0x12, 0x34, 0x56]).unwrap();
+    assert_eq!(board.memory.read_mem_u(0x2000_0001, 4).unwrap() & 0xFF_FFFF, 0x56_3412);
+    board.write_memory_region(0x2000_0004, &[0x78, 0x56, 0x34, 0x12]).unwrap();
+    assert_eq!(board.memory.read_mem_u(0x2000_0004, 4).unwrap(), 0x1234_5678);
+
+    // Flash and unmapped memory fail, and a write running into them changes nothing
+    let flash = board.memory.read_mem_u(0x0800_0000, 4).unwrap();
+    assert_eq!(board.write_memory_region(0x0800_0000, &[0; 4]), Err(EmulatorError::MemoryFault { address: 0x0800_0000, kind: MemError::ReadOnly }));
+    assert_eq!(board.memory.read_mem_u(0x0800_0000, 4).unwrap(), flash);
+    let ram = board.memory.read_mem_u(0x2001_EFFE, 2).unwrap();
+    assert_eq!(board.write_memory_region(0x2001_EFFE, &[1; 4]), Err(EmulatorError::MemoryFault { address: 0x2001_F000, kind: MemError::OutOfBounds }));
+    assert_eq!(board.memory.read_mem_u(0x2001_EFFE, 2).unwrap(), ram);
+}
+
+#[test]
+fn flash_programming() {
+    let mut board = load_program("nvic").unwrap();
+
+    let regions = board.memory.regions();
+    let flash: Vec<u32> = regions.iter().filter(|r| r.kind == MemoryKind::Flash).map(|r| r.start).collect();
+    assert_eq!(flash, vec![0x0000_0000, 0x0800_0000]);
+    assert!(regions.iter().any(|r| r.kind == MemoryKind::Ram && r.start == 0x2000_0000 && r.length == 96 * 1024));
+
+    assert!(matches!(board.erase_flash(0x0808_0000, 0x800), Err(EmulatorError::MemoryFault { kind: MemError::OutOfBounds, .. })));
+    assert!(matches!(board.erase_flash(0x0800_0400, 0x800), Err(EmulatorError::MemoryFault { kind: MemError::Unaligned, .. })));
+    board.erase_flash(0x0807_F800, 0x800).unwrap();
+    assert_eq!(board.read_memory_region(0x0807_FFFC, 4).unwrap(), vec![0xFF; 4]);
+    board.program_flash(0x0807_FFFE, &[0x12, 0x34]).unwrap();
+    assert_eq!(board.read_memory_region(0x0007_FFFC, 4).unwrap(), vec![0xFF, 0xFF, 0x12, 0x34]);
+    assert!(board.program_flash(0x0807_FFFE, &[0; 4]).is_err());
+
+    // Instructions already decoded are replaced by what is programmed over them
+    let pc = board.cpu.read_instruction_pc();
+    board.step().unwrap();
+    board.cpu.write_instruction_pc(pc);
+    board.program_flash(pc, &[0x2A, 0x20]).unwrap(); // movs r0, 42
+    board.step().unwrap();
+    assert_eq!(board.read_reg(0u32), 42);
+}
+
+#[test]
+fn watchpoints() {
+    let mut board = load_program("watchpoint").unwrap();
+    let write = Watchpoint { address: 0x2000_0104, length: 4, kind: WatchKind::Write };
+    let read = Watchpoint { address: 0x2000_0100, length: 4, kind: WatchKind::Read };
+    let access = Watchpoint { address: 0x2000_0108, length: 1, kind: WatchKind::Access };
+    board.insert_watchpoint(write);
+    board.insert_watchpoint(read);
+    board.insert_watchpoint(access);
+
+    // Debugger reads do not count
+    board.read_memory_region(0x2000_0100, 16).unwrap();
+    board.step_n(2).unwrap();
+    assert_eq!(board.watchpoint_hit(), None);
+
+    // The store is not a read, but the byte load overlaps the read watchpoint
+    board.step().unwrap();
+    assert_eq!(board.watchpoint_hit(), None);
+    board.step().unwrap();
+    assert_eq!(board.watchpoint_hit(), Some(read));
+    board.step().unwrap();
+    assert_eq!(board.watchpoint_hit(), Some(write));
+
+    assert!(board.remove_watchpoint(access));
+    assert!(!board.remove_watchpoint(access));
+    board.step().unwrap();
+    assert_eq!(board.watchpoint_hit(), None);
+}
+
+#[test]
+fn history() {
+    let mut board = load_program("nvic").unwrap();
+    let mut history = History::new();
+    let mut pcs = Vec::new();
+    for _ in 0..30 {
+        pcs.push(board.cpu.read_instruction_pc());
+        history.step(&mut board).unwrap();
+    }
+    let xpsr = board.cpu.read_xpsr();
+    let r4 = board.read_reg(4u32);
+
+    // Stepping back lands on the instruction before, with the registers it had then
+    assert_eq!(history.step_back(&mut board).unwrap(), ReverseStop::Stepped);
+    assert_eq!(history.position(), 29);
+    assert_eq!(board.cpu.read_instruction_pc(), pcs[29]);
+    history.step(&mut board).unwrap();
+    assert_eq!(board.cpu.read_xpsr(), xpsr);
+    assert_eq!(board.read_reg(4u32), r4);
+
+    // Continuing back stops at the last time a breakpoint was reached
+    let breakpoints: HashSet<u32> = [pcs[12]].iter().cloned().collect();
+    assert_eq!(history.continue_back(&mut board, &breakpoints).unwrap(), ReverseStop::Breakpoint);
+    assert_eq!(history.position(), pcs.iter().rposition(|&pc| pc == pcs[12]).unwrap() as u64);
+    assert_eq!(board.cpu.read_instruction_pc(), pcs[12]);
+
+    // Without breakpoints, it goes back to the start
+    assert_eq!(history.continue_back(&mut board, &HashSet::new()).unwrap(), ReverseStop::Beginning);
+    assert_eq!(history.position(), 0);
+    assert_eq!(board.cpu.read_instruction_pc(), pcs[0]);
+    assert_eq!(history.step_back(&mut board).unwrap(), ReverseStop::Beginning);
+
+    // Going back past a checkpoint replays the input events since it
+    let timeline = InputTimeline::parse("50010 centre press").unwrap();
+    let mut expected = load_program("exti").unwrap();
+    expected.set_input_timeline(timeline.clone());
+    expected.step_n(50_020).unwrap();
+    let mut board = load_program("exti").unwrap();
+    board.set_input_timeline(timeline);
+    let mut history = History::new();
+    for _ in 0..50_030 {
+        history.step(&mut board).unwrap();
+    }
+    for _ in 0..10 {
+        history.step_back(&mut board).unwrap();
+    }
+    assert_eq!(board.instruction_count(), expected.instruction_count());
+    assert_eq!(board.read_reg(5u32), 1);
+    assert_eq!(board.read_reg(5u32), expected.read_reg(5u32));
+    assert_eq!(board.cpu.read_instruction_pc(), expected.cpu.read_instruction_pc());
+    assert!(board.gpio('A').unwrap().read_pin(0));
+
+    // Semihosting calls reach the host once, however often they are replayed
+    let mut board = load_program("semihosting").unwrap();
+    let output = Arc::new(Mutex::new(Vec::new()));
+    board.set_semihosting_console(Box::new(SharedOutput(Arc::clone(&output))));
+    let mut history = History::new();
+    for _ in 0..40 {
+        history.step(&mut board).unwrap();
+    }
+    assert_eq!(history.continue_back(&mut board, &HashSet::new()).unwrap(), ReverseStop::Beginning);
+    for _ in 0..40 {
+        history.step(&mut board).unwrap();
+    }
+    assert_eq!(output.lock().unwrap().as_slice(), b"Hello\n!abc");
+    assert_eq!(board.read_reg(4u32), 1);
+    assert_eq!(board.exit_status(), Some(3));
+}
+
+#[test]
+fn run_mode() {
+    // Compiles the fixture to firmware.elf
+    let mut board = load_program("run").unwrap();
+    board.step_n(30).unwrap();
+    assert_eq!(board.exit_status(), Some(7));
+
+    let elf_path = format!("{}/tests/fixtures/offline/run/firmware.elf", env!("CARGO_MANIFEST_DIR"));
+    let run = |extra: &[&str]| {
+        return std::process::Command::new(env!("CARGO_BIN_EXE_discoserver"))
+            .args(&["--run", "-kernel", &elf_path])
+            .args(extra)
+            .output()
+            .unwrap()
+            .status
+            .code();
+    };
+    assert_eq!(run(&[]), Some(7));
+    assert_eq!(run(&["--max-instructions", "5"]), Some(125));
+    assert_eq!(run(&["--max-instructions", "five"]), Some(123));
+    assert_eq!(run(&["--timeout", "-1"]), Some(123));
+    assert_eq!(run(&["--timeout", "NaN"]), Some(123));
+}
+
+#[test]
+fn gdb_transports() {
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+
+    // Over stdio, stdout only has packets for GDB
+    let elf_path = compile_fixture("run").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_discoserver"))
+        .args(&["--stdio", "-kernel"])
+        .arg(&elf_path)
+        .args(&["--log-level", "error"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let input = format!("{}+{}", gdb_packet("?"), gdb_packet("D"));
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let mut output = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    assert!(output.starts_with("+$T05"), "{}", output);
+    assert!(output.ends_with("+$OK#9A"), "{}", output);
+    assert!(child.wait().unwrap().success());
+
+    // The program reads the end of the file from the console, leaving stdin to GDB
+    let elf_path = compile_fixture("semihosting_stdin").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_discoserver"))
+        .args(&["--stdio", "-kernel"])
+        .arg(&elf_path)
+        .args(&["--log-level", "error"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let (mut stdin, mut stdout) = (child.stdin.take().unwrap(), child.stdout.take().unwrap());
+    let mut exchange = |contents: &str| {
+        gdb_send(&mut stdin, contents);
+        return gdb_reply(&mut stdout);
+    };
+    assert!(exchange("?").starts_with("T05"));
+    assert!(exchange("c").starts_with("T05"));
+    assert_eq!(exchange("p04"), "04000000");
+    assert_eq!(exchange("D"), "OK");
+    assert!(child.wait().unwrap().success());
+
+    // Only the server takes stdout for GDB, and it needs to be told how to listen
+    let output = Command::new(env!("CARGO_BIN_EXE_discoserver")).args(&["--version", "--stdio"]).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("disco-server v"));
+    let output = Command::new(env!("CARGO_BIN_EXE_discoserver")).arg("-kernel").arg(&elf_path).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+
+    // With port 0 the server says which port it got. With --multi, the next
+    // session finds the board where the last one detached.
+    let (_server, address) = spawn_server(&elf_path, &["--multi"]);
+    let mut stream = TcpStream::connect(&address).unwrap();
+    assert!(gdb_exchange(&mut stream, "?").starts_with("T05"));
+    gdb_exchange(&mut stream, "s");
+    let pc = gdb_exchange(&mut stream, "p0f");
+    assert_eq!(gdb_exchange(&mut stream, "D"), "OK");
+
+    let mut stream = TcpStream::connect(&address).unwrap();
+    assert!(gdb_exchange(&mut stream, "?").starts_with("T05"));
+    assert_eq!(gdb_exchange(&mut stream, "p0f"), pc);
+}
+
+#[test]
+fn gdb_sessions() {
+    let elf_path = compile_fixture("exti").unwrap();
+    let instructions = |stream: &mut TcpStream| {
+        let command: String = "tick".bytes().map(|b| format!("{:02x}", b)).collect();
+        let output = gdb_exchange(stream, &format!("qRcmd,{}", command));
+        assert_eq!(gdb_reply(stream), "OK");
+        let text: Vec<u8> = (1..output.len()).step_by(2).map(|i| u8::from_str_radix(&output[i..i + 2], 16).unwrap()).collect();
+        return String::from_utf8(text).unwrap().split(' ').next().unwrap().parse::<u64>().unwrap();
+    };
+
+    // Killing the program resets it for the next session
+    let (server, address) = spawn_server(&elf_path, &["--multi"]);
+    let mut stream = TcpStream::connect(&address).unwrap();
+    gdb_exchange(&mut stream, "?");
+    let entry = gdb_exchange(&mut stream, "p0f");
+    gdb_exchange(&mut stream, "s");
+    assert_ne!(gdb_exchange(&mut stream, "p0f"), entry);
+
+    // A snapshot that cannot be loaded leaves the history to go back through
+    let command: String = "snapshot load /nonexistent/snapshot".bytes().map(|b| format!("{:02x}", b)).collect();
+    gdb_exchange(&mut stream, &format!("qRcmd,{}", command));
+    assert_eq!(gdb_reply(&mut stream), "E01");
+    gdb_exchange(&mut stream, "bs");
+    assert_eq!(gdb_exchange(&mut stream, "p0f"), entry);
+    gdb_exchange(&mut stream, "s");
+    gdb_send(&mut stream, "k");
+
+    let mut stream = TcpStream::connect(&address).unwrap();
+    gdb_exchange(&mut stream, "?");
+    assert_eq!(gdb_exchange(&mut stream, "p0f"), entry);
+    assert_eq!(instructions(&mut stream), 0);
+    drop(server);
+
+    // With --keep-running, the program carries on between sessions
+    let (_server, address) = spawn_server(&elf_path, &["--multi", "--keep-running"]);
+    let mut stream = TcpStream::connect(&address).unwrap();
+    gdb_exchange(&mut stream, "?");
+    let before = instructions(&mut stream);
+    assert_eq!(gdb_exchange(&mut stream, "D"), "OK");
+    std::thread::sleep(Duration::from_millis(50));
+
+    let mut stream = TcpStream::connect(&address).unwrap();
+    gdb_exchange(&mut stream, "?");
+    assert!(instructions(&mut stream) > before);
+}
+
+#[test]
+fn gdb_continue() {
+    let elf_path = compile_fixture("exti").unwrap();
+    let (_server, address) = spawn_server(&elf_path, &[]);
+    let mut stream = TcpStream::connect(&address).unwrap();
+    gdb_exchange(&mut stream, "qSupported:swbreak+;hwbreak+");
+
+    // Interrupting stops with SIGINT where the program had got to
+    gdb_send(&mut stream, "c");
+    std::thread::sleep(Duration::from_millis(50));
+    stream.write_all(&[0x03]).unwrap();
+    let stop = gdb_reply(&mut stream);
+    let pc = gdb_exchange(&mut stream, "p0f");
+    assert!(stop.starts_with("T02"), "{}", stop);
+    assert!(stop.contains(&format!("0f:{};", pc)), "{}", stop);
+
+    // The program is in its idle loop, so continuing comes back round to a breakpoint on it
+    let loop_address = u32::from_str_radix(&pc, 16).unwrap().swap_bytes();
+    assert_eq!(gdb_exchange(&mut stream, &format!("Z0,{:x},2", loop_address)), "OK");
+    let stop = gdb_exchange(&mut stream, "c");
+    assert!(stop.starts_with("T05") && stop.contains("swbreak:;"), "{}", stop);
+    assert!(stop.contains(&format!("0f:{};", pc)), "{}", stop);
+
+    assert_eq!(gdb_exchange(&mut stream, &format!("z0,{:x},2", loop_address)), "OK");
+    assert_eq!(gdb_exchange(&mut stream, &format!("Z1,{:x},2", loop_address)), "OK");
+    let stop = gdb_exchange(&mut stream, "c");
+    assert!(stop.starts_with("T05") && stop.contains("hwbreak:;"), "{}", stop);
+    assert!(stop.contains(&format!("0f:{};", pc)), "{}", stop);
+}
+
+#[test]
+fn semihosting_bounds() {
+    // Lengths that do not fit in memory fail without allocating buffers for them
+    let mut board = load_program("semihosting_bounds").unwrap();
+    board.step_n(30).unwrap();
+    assert_eq!(board.read_reg(6u32), 1);
+    assert_eq!(board.read_reg(4u32), 0xFFFF_FFFF);
+    assert_eq!(board.read_reg(5u32), 0xFFFF_FFFF);
+}